@@ -0,0 +1,130 @@
+#ifndef COLOR_THIEF_H
+#define COLOR_THIEF_H
+
+/* Generated with cbindgen from src/ffi/mod.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Bumped on every incompatible change of the C API declared in
+// `include/color_thief.h`. Callers allocate the structs, so any change to
+// their layout, appended fields included, bumps it. Status codes are only
+// ever added otherwise. Regenerate the header with
+// `cbindgen --config cbindgen.toml --output include/color_thief.h src/ffi/mod.rs`.
+#define CT_ABI_VERSION 1
+
+// Outcome of a call, `CtError::status` tells the failure apart
+typedef enum CtStatus {
+  CT_STATUS_OK = 0,
+  // A null pointer or an option out of range
+  CT_STATUS_INVALID_ARGUMENT = 1,
+  CT_STATUS_IO = 2,
+  // The data is not an image in a supported format or is corrupt
+  CT_STATUS_DECODE = 3,
+  // The image exceeds the decode limits or the pixel count
+  CT_STATUS_LIMIT = 4,
+  // A raw buffer does not match its dimensions and stride
+  CT_STATUS_INVALID_BUFFER = 5,
+  CT_STATUS_UNSUPPORTED = 6,
+  // A bug in the library, the call had no effect
+  CT_STATUS_INTERNAL = 7,
+} CtStatus;
+
+// Quantization settings, start from `ct_options_default`
+typedef struct CtOptions {
+  // Number of palette colors, 2 to 256
+  uint32_t color_count;
+  // Use every n-th pixel, 1 is the highest quality
+  uint32_t quality;
+  // 0 rgb, 1 lab, 2 oklab
+  uint32_t color_space;
+  // Nonzero to average rgb colors in linear light
+  uint8_t linear_average;
+} CtOptions;
+
+typedef struct CtSwatch {
+  uint8_t r;
+  uint8_t g;
+  uint8_t b;
+  uint32_t population;
+} CtSwatch;
+
+// Swatches sorted by descending population, free with `ct_palette_free`
+typedef struct CtPalette {
+  struct CtSwatch *swatches;
+  size_t swatch_count;
+  // Pixels that survived filtering, the sum of every population
+  uint32_t pixel_count;
+} CtPalette;
+
+// Free with `ct_error_free`
+typedef struct CtError {
+  enum CtStatus status;
+  // NUL-terminated UTF-8 description
+  char *message;
+} CtError;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Version of the API the library implements, compare it with
+// `CT_ABI_VERSION` of the header
+uint32_t ct_abi_version(void);
+
+// 10 colors, quality 10, rgb, averaged in sRGB, like the CLI
+struct CtOptions ct_options_default(void);
+
+// Palette of an encoded image in any supported format. `options` may be
+// null for the defaults. On success `*out_palette` is set, otherwise
+// `*out_error` is set when `out_error` is not null.
+//
+// # Safety
+//
+// `data` points to `len` readable bytes, `options` is null or valid and
+// `out_palette` and `out_error` are null or writable.
+enum CtStatus ct_palette_from_bytes(const uint8_t *data,
+                                    size_t len,
+                                    const struct CtOptions *options,
+                                    struct CtPalette **out_palette,
+                                    struct CtError **out_error);
+
+// Palette of `height` rows of `width` RGBA pixels, `stride` bytes apart,
+// read from a buffer of `len` bytes. Otherwise like
+// `ct_palette_from_bytes`.
+//
+// # Safety
+//
+// `data` points to `len` readable bytes, `options` is null or valid and
+// `out_palette` and `out_error` are null or writable.
+enum CtStatus ct_palette_from_rgba(const uint8_t *data,
+                                   size_t len,
+                                   uint32_t width,
+                                   uint32_t height,
+                                   size_t stride,
+                                   const struct CtOptions *options,
+                                   struct CtPalette **out_palette,
+                                   struct CtError **out_error);
+
+// Accepts null
+//
+// # Safety
+//
+// `palette` is null or was returned by this library and not freed yet.
+void ct_palette_free(struct CtPalette *palette);
+
+// Accepts null
+//
+// # Safety
+//
+// `error` is null or was returned by this library and not freed yet.
+void ct_error_free(struct CtError *error);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* COLOR_THIEF_H */
@@ -0,0 +1,90 @@
+use std::borrow::Cow;
+use std::path::PathBuf;
+use image::{ DynamicImage, RgbaImage };
+use pyo3::exceptions::{ PyException, PyOSError, PyValueError };
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use color_thief_rs::error::Error;
+use color_thief_rs::img_io::{ self, DecodeLimits };
+use color_thief_rs::upstream::{ self, Upstream };
+
+
+/// Colors quantized by `get_color`, like color-thief-py
+const DOMINANT_COLOR_COUNT: i64 = 5;
+
+/// Drop-in for `colorthief.ColorThief`: same constructor, methods, defaults,
+/// return shapes and palettes
+#[pyclass(module = "color_thief_rs", frozen)]
+struct ColorThief {
+    image: RgbaImage,
+}
+
+#[pymethods]
+impl ColorThief {
+    /// `file` is a filename, a path-like object or a binary file object
+    #[new]
+    fn new(py: Python<'_>, file: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let bytes = match file.hasattr("read")? {
+            true => file.call_method0("read")?.downcast_into::<PyBytes>()?.as_bytes().to_vec(),
+            false => std::fs::read(file.extract::<PathBuf>()?)?,
+        };
+        let image = py.allow_threads(|| {
+            let limits = DecodeLimits::default();
+            // The 8-bit copy counts towards the allocation limit
+            match img_io::load_img_with_profile(&bytes, &limits)?.0 {
+                DynamicImage::ImageRgba8(rgba) => Ok(rgba),
+                img => img_io::to_rgba8_within(&img, &limits).map(Cow::into_owned),
+            }
+        });
+        Ok(ColorThief { image: image.map_err(to_py_err)? })
+    }
+
+    /// Dominant color as an `(r, g, b)` tuple
+    #[pyo3(signature = (quality = 10))]
+    fn get_color(&self, py: Python<'_>, quality: i64) -> PyResult<(i32, i32, i32)> {
+        Ok(self.get_palette(py, DOMINANT_COLOR_COUNT, quality)?[0])
+    }
+
+    /// List of `(r, g, b)` tuples, `quality` uses every n-th pixel. Like
+    /// color-thief-py an empty box past the last bin has channels of 256.
+    #[pyo3(signature = (color_count = 10, quality = 10))]
+    fn get_palette(&self, py: Python<'_>, color_count: i64, quality: i64) -> PyResult<Vec<(i32, i32, i32)>> {
+        // Same exceptions as color-thief-py
+        if !(2..=256).contains(&color_count) {
+            return Err(PyException::new_err("Wrong number of max colors when quantize."));
+        }
+        if quality < 1 {
+            return Err(PyValueError::new_err("quality should be 1 or higher"));
+        }
+        let palette = py.allow_threads(|| {
+            let pixels = upstream::sample_pixels(self.image.pixels(), quality as usize);
+            match pixels.is_empty() {
+                true => Ok(None),
+                false => upstream::quantize(&pixels, color_count as usize, Upstream::ColorThiefPy).map(Some),
+            }
+        });
+        let Some(palette) = palette.map_err(to_py_err)? else {
+            return Err(PyException::new_err("Empty pixels when quantize."));
+        };
+        Ok(palette.swatches.iter()
+            .map(|swatch| (swatch.color[0], swatch.color[1], swatch.color[2]))
+            .collect())
+    }
+}
+
+/// I/O errors keep their `OSError` subclass, images that cannot be decoded
+/// raise `OSError` like Pillow
+fn to_py_err(err: Error) -> PyErr {
+    match err {
+        Error::Io(err) => err.into(),
+        err => PyOSError::new_err(err.to_string()),
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "color_thief_rs")]
+fn color_thief_py(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<ColorThief>()?;
+    module.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
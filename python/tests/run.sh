@@ -0,0 +1,15 @@
+#!/bin/sh
+# Builds the extension into a virtual environment with maturin, then runs
+# the pytest suite against it. Run from anywhere, extra arguments go to
+# pytest. Set VENV to reuse an environment, colorthief is installed for the
+# comparisons with color-thief-py.
+set -eu
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+cd "$root"
+venv=${VENV:-"$root/target/venv"}
+[ -d "$venv" ] || python3 -m venv "$venv"
+. "$venv/bin/activate"
+pip install --quiet maturin pytest colorthief
+maturin develop
+pytest tests "$@"
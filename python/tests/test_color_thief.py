@@ -0,0 +1,101 @@
+"""Checks the ColorThief class against the color-thief-py interface.
+
+Run from python/ after `maturin develop`: `pytest tests`. The comparisons
+with color-thief-py are skipped when the colorthief package is missing.
+"""
+import io
+import json
+import pathlib
+
+import pytest
+
+from color_thief_rs import ColorThief
+
+TESTS = pathlib.Path(__file__).resolve().parents[2] / "tests"
+DATA = TESTS / "data"
+COLORFUL = ["12colors.png", "12colors.jpg"]
+
+
+# Empty boxes past the last bin average to 256 like color-thief-py
+def is_rgb(color):
+    return (
+        isinstance(color, tuple)
+        and len(color) == 3
+        and all(isinstance(channel, int) and 0 <= channel <= 256 for channel in color)
+    )
+
+
+@pytest.mark.parametrize("name", COLORFUL)
+def test_get_color(name):
+    color = ColorThief(str(DATA / name)).get_color(quality=1)
+    assert is_rgb(color)
+    # The dominant color leads the palette it is taken from
+    assert color == ColorThief(str(DATA / name)).get_palette(5, 1)[0]
+
+
+@pytest.mark.parametrize("name", COLORFUL)
+def test_get_palette(name):
+    thief = ColorThief(str(DATA / name))
+    palette = thief.get_palette(color_count=6, quality=1)
+    assert isinstance(palette, list)
+    assert 1 <= len(palette) <= 6
+    assert all(is_rgb(color) for color in palette)
+    # Positional arguments and defaults like color-thief-py
+    assert thief.get_palette(6, 1) == palette
+    assert len(thief.get_palette()) <= 10
+
+
+def test_inputs():
+    path = DATA / "12colors.png"
+    expected = ColorThief(str(path)).get_palette(quality=1)
+    assert ColorThief(path).get_palette(quality=1) == expected
+    with open(path, "rb") as file:
+        assert ColorThief(file).get_palette(quality=1) == expected
+    assert ColorThief(io.BytesIO(path.read_bytes())).get_palette(quality=1) == expected
+
+
+def test_filtered_pixels():
+    # Black is kept as the center of its bin, padded with empty boxes like
+    # color-thief-py, white and transparent pixels are dropped
+    thief = ColorThief(str(DATA / "000000FF.png"))
+    assert thief.get_color() == (4, 4, 4)
+    assert thief.get_palette(3) == [(4, 4, 4), (8, 4, 4), (8, 4, 4), (8, 4, 4)]
+    for name in ["FFFFFFFF.png", "00000000.png"]:
+        with pytest.raises(Exception, match="Empty pixels"):
+            ColorThief(str(DATA / name)).get_color()
+
+
+def test_errors():
+    with pytest.raises(FileNotFoundError):
+        ColorThief(str(DATA / "missing.png"))
+    with pytest.raises(OSError):
+        ColorThief(io.BytesIO(b"not an image"))
+    thief = ColorThief(str(DATA / "12colors.png"))
+    for color_count in [1, 257]:
+        with pytest.raises(Exception, match="Wrong number of max colors"):
+            thief.get_palette(color_count)
+    with pytest.raises(ValueError):
+        thief.get_palette(quality=0)
+
+
+def test_matches_golden_palettes():
+    path = TESTS / "golden" / "color_thief_py.json"
+    if not path.exists():
+        pytest.skip("color_thief_py.json is written by generate_color_thief_py.py")
+    golden = json.loads(path.read_text())
+    for name in ["12colors.png", "000000FF.png"]:
+        thief = ColorThief(str(DATA / name))
+        for color_count, colors in golden["palettes"][name].items():
+            expected = [tuple(color) for color in colors]
+            assert thief.get_palette(int(color_count), 1) == expected
+
+
+# Pillow decodes JPEG slightly differently, only lossless images match
+@pytest.mark.parametrize("name", ["12colors.png", "000000FF.png"])
+def test_matches_color_thief_py(name):
+    colorthief = pytest.importorskip("colorthief")
+    expected = colorthief.ColorThief(str(DATA / name))
+    found = ColorThief(str(DATA / name))
+    for color_count in [2, 5, 10]:
+        assert found.get_palette(color_count, 1) == expected.get_palette(color_count, 1)
+    assert found.get_color(1) == expected.get_color(1)
@@ -0,0 +1,213 @@
+use image::{ Delay, Frame, Rgba };
+use crate::data_models::{ ColorPalette, FrequencyMap, MinMaxBox, QuantizeOptions };
+use crate::error::Error;
+use crate::icc::Transform;
+use crate::mmcq::{ self, MMCQ };
+use crate::stats;
+
+
+/// Browsers show frames without a delay for this long
+pub const DEFAULT_DELAY_MS: u32 = 100;
+
+/// Palette of a single frame and when it is shown
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct FramePalette {
+    pub start_ms: u32,
+    pub duration_ms: u32,
+    pub palette: ColorPalette,
+}
+
+/// Per-frame timeline of an animation and the palette of the whole
+/// animation. The global palette weighs every frame by how long it is
+/// shown, so its populations count pixels times the frame duration in units
+/// of the greatest common divisor of all durations.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct AnimationPalette {
+    pub frames: Vec<FramePalette>,
+    pub global: ColorPalette,
+}
+
+/// Frame delay rounded to whole milliseconds, zero delays are replaced with
+/// `DEFAULT_DELAY_MS`
+pub fn frame_duration_ms(delay: Delay) -> u32 {
+    let (numer, denom) = delay.numer_denom_ms();
+    match (numer + denom / 2) / denom {
+        0 => DEFAULT_DELAY_MS,
+        duration_ms => duration_ms,
+    }
+}
+
+/// Palettes of every frame plus the duration-weighted global palette.
+/// `quality` uses every n-th pixel like the CLI option, 0 is taken as 1.
+/// A transform converts the pixels to the working space first, at 16 bits
+/// like still images. Only the frequency maps are kept between frames,
+/// never the pixels.
+pub fn calc_palettes(frames: impl Iterator<Item = Result<Frame, image::ImageError>>, quality: usize, transform: Option<&Transform>, options: &QuantizeOptions) -> Result<AnimationPalette, Error> {
+    let mut timeline = Vec::new();
+    let mut frame_stats: Vec<(FrequencyMap, MinMaxBox, u32)> = Vec::new();
+    let mut start_ms: u32 = 0;
+    for frame in frames {
+        let frame = frame?;
+        let duration_ms = frame_duration_ms(frame.delay());
+        let pixels: Vec<Rgba<u8>> = frame.buffer().pixels()
+            // Sample every n-th pixel
+            .step_by(quality.max(1))
+            // Filter out half-transparent pixels
+            .filter(|pixel| pixel[3] > 125)
+            // Bin using MMCQ bit shift or perceptual coordinates
+            .map(|&pixel| match transform {
+                Some(transform) => {
+                    let pixel = transform.apply_rgba16(Rgba(pixel.0.map(|val| val as u16 * 257)));
+                    MMCQ::bin_pixel16_as(pixel, options.color_space)
+                },
+                None => MMCQ::bin_pixel_as(pixel, options.color_space),
+            })
+            .collect();
+        let palette = mmcq::quantize_pixels(&pixels, options)?;
+        if !pixels.is_empty() {
+            frame_stats.push((
+                stats::calc_frequency_map(&pixels, &MMCQ::hash_pixel),
+                stats::calc_minmax_box(&pixels),
+                duration_ms,
+            ));
+        }
+        timeline.push(FramePalette { start_ms, duration_ms, palette });
+        start_ms = start_ms.saturating_add(duration_ms);
+    }
+
+    let global = match merge_weighted(frame_stats) {
+        Some((frequency_map, minmax_box)) => mmcq::quantize(&frequency_map, minmax_box, options),
+        None => ColorPalette { swatches: Vec::new(), pixel_count: 0 },
+    };
+    Ok(AnimationPalette { frames: timeline, global })
+}
+
+/// Sums the frequency maps with every count multiplied by its duration
+/// divided by the common divisor of all durations, and spans every box.
+/// Long or many frames whose weighted counts would pass `u32::MAX` are
+/// scaled to their share of the duration instead. `None` when there is
+/// nothing to merge.
+fn merge_weighted(frame_stats: Vec<(FrequencyMap, MinMaxBox, u32)>) -> Option<(FrequencyMap, MinMaxBox)> {
+    let divisor = frame_stats.iter().fold(0, |divisor, (_, _, duration_ms)| gcd(divisor, *duration_ms));
+    let weighted_total: u128 = frame_stats.iter()
+        .map(|(frequency_map, _, duration_ms)| frequency_map.total() as u128 * (duration_ms / divisor) as u128)
+        .sum();
+    let mut merged: Option<(FrequencyMap, MinMaxBox)> = None;
+    for (mut frequency_map, minmax_box, duration_ms) in frame_stats {
+        let weight = duration_ms / divisor;
+        match weighted_total > u32::MAX as u128 {
+            true => {
+                let share = frequency_map.total() as u128 * weight as u128 * u32::MAX as u128 / weighted_total;
+                frequency_map.normalize(share as u32);
+            },
+            false => frequency_map.weight(weight),
+        }
+        merged = Some(match merged {
+            None => (frequency_map, minmax_box),
+            Some((mut merged_map, merged_box)) => {
+                // Rounding in normalize can overshoot a little, add scales
+                // that back down
+                merged_map.add(&frequency_map);
+                (merged_map, merged_box.union(&minmax_box))
+            },
+        });
+    }
+    merged
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+
+#[cfg(test)]
+mod test_animation {
+    use super::*;
+    use image::RgbaImage;
+    use crate::icc::RgbProfile;
+
+    fn solid_frame(color: [u8; 4], delay_ms: u32) -> Frame {
+        let buffer = RgbaImage::from_pixel(4, 4, Rgba(color));
+        Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+    }
+
+    #[test]
+    fn test_frame_duration_ms() {
+        assert_eq!(40, frame_duration_ms(Delay::from_numer_denom_ms(40, 1)), "Logic Error: whole");
+        assert_eq!(33, frame_duration_ms(Delay::from_numer_denom_ms(100, 3)), "Logic Error: rounding");
+        assert_eq!(DEFAULT_DELAY_MS, frame_duration_ms(Delay::from_numer_denom_ms(0, 1)), "Logic Error: zero");
+    }
+
+    #[test]
+    fn test_calc_palettes() {
+        // Red for 100 ms, then blue for 300 ms, then a transparent frame
+        let frames = vec![
+            Ok(solid_frame([255, 0, 0, 255], 100)),
+            Ok(solid_frame([0, 0, 255, 255], 300)),
+            Ok(solid_frame([0, 255, 0, 0], 100)),
+        ];
+        let options = QuantizeOptions { color_count: 2, ..Default::default() };
+        let found = calc_palettes(frames.into_iter(), 1, None, &options).unwrap();
+
+        let starts: Vec<u32> = found.frames.iter().map(|frame| frame.start_ms).collect();
+        assert_eq!(vec![0, 100, 400], starts, "Logic Error: timeline");
+        assert_eq!(16, found.frames[0].palette.pixel_count, "Logic Error: red frame");
+        assert_eq!(0, found.frames[2].palette.pixel_count, "Logic Error: transparent frame");
+
+        // Blue is shown three times as long, durations share a divisor of 100
+        let populations: Vec<u32> = found.global.swatches.iter().map(|swatch| swatch.population).collect();
+        assert_eq!(vec![48, 16], populations, "Logic Error: weights");
+        assert!(found.global.swatches[0].color[2] > 240, "Logic Error: blue first");
+
+        // sRGB red lies inside Display P3, at a red of about 234
+        let to_p3 = Transform::new(&RgbProfile::srgb(), &RgbProfile::display_p3());
+        let frames = vec![Ok(solid_frame([255, 0, 0, 255], 100))];
+        let found = calc_palettes(frames.into_iter(), 1, Some(&to_p3), &options).unwrap();
+        assert_eq!(236, found.global.swatches[0].color[0], "Logic Error: converted");
+    }
+
+    #[test]
+    fn test_merge_weighted() {
+        // A frame shown for 2^30 ms outweighs its neighbour past u32::MAX
+        let frame_stats = [([255, 0, 0, 255], 1), ([0, 0, 255, 255], 1 << 30)].map(|(color, duration_ms)| {
+            let pixels = vec![MMCQ::bin_pixel(Rgba(color)); 16];
+            (stats::calc_frequency_map(&pixels, &MMCQ::hash_pixel), stats::calc_minmax_box(&pixels), duration_ms)
+        });
+        let (frequency_map, _) = merge_weighted(frame_stats.to_vec()).unwrap();
+        assert!(frequency_map.total() <= u32::MAX as u64, "Logic Error: total");
+        let blue = frequency_map.0[&MMCQ::hash_rgb(0, 0, 31)] as u64;
+        let red = frequency_map.0[&MMCQ::hash_rgb(31, 0, 0)] as u64;
+        assert!(blue > u32::MAX as u64 - 8, "Logic Error: blue share");
+        assert!(red.abs_diff(4) <= 1, "Logic Error: red share");
+    }
+
+    #[test]
+    fn test_read_frames() {
+        use image::codecs::gif::GifEncoder;
+
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            encoder.encode_frame(solid_frame([255, 0, 0, 255], 100)).unwrap();
+            encoder.encode_frame(solid_frame([0, 0, 255, 255], 300)).unwrap();
+        }
+        let frames = crate::img_io::read_frames(std::io::Cursor::new(&bytes), &Default::default()).unwrap();
+        let options = QuantizeOptions { color_count: 2, ..Default::default() };
+        let found = calc_palettes(frames, 1, None, &options).unwrap();
+        let durations: Vec<u32> = found.frames.iter().map(|frame| frame.duration_ms).collect();
+        assert_eq!(vec![100, 300], durations, "Logic Error: gif delays");
+        assert!(found.global.swatches[0].color[2] > 240, "Logic Error: blue first");
+
+        // Still images are a single frame
+        let file = std::fs::File::open("tests/data/12colors.png").unwrap();
+        let frames = crate::img_io::read_frames(file, &Default::default()).unwrap();
+        assert_eq!(1, frames.count(), "Logic Error: still image");
+    }
+}
@@ -0,0 +1,210 @@
+use std::path::{ Path, PathBuf };
+use std::sync::{ mpsc, Mutex };
+use crate::error::Error;
+
+
+/// Include and exclude glob patterns for the files found in a directory.
+/// Patterns holding a `/` match the path relative to the walked directory,
+/// other patterns match the file name alone, like in `.gitignore`.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Default)]
+#[derive(Debug)]
+pub struct GlobFilter {
+    /// Every file is included when empty
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl GlobFilter {
+    /// `relative` uses `/` as separator
+    pub fn is_match(&self, relative: &str) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let is_match = |pattern: &String| match pattern.contains('/') {
+            true => glob_match(pattern, relative),
+            false => glob_match(pattern, name),
+        };
+        (self.include.is_empty() || self.include.iter().any(is_match))
+            && !self.exclude.iter().any(is_match)
+    }
+}
+
+/// Matches a path against a glob. `*` and `?` stand for any characters and
+/// any single character within a path segment, `**` also crosses `/`, so
+/// `**/` matches any number of directories.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[char], path: &[char]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', '/', rest @ ..] => {
+                matches(rest, path) || (0..path.len())
+                    .filter(|&i| path[i] == '/')
+                    .any(|i| matches(rest, &path[i + 1..]))
+            },
+            ['*', '*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+            ['*', rest @ ..] => {
+                // Stop at the end of the segment
+                let segment_len = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+                (0..=segment_len).any(|i| matches(rest, &path[i..]))
+            },
+            ['?', rest @ ..] => {
+                path.first().is_some_and(|&c| c != '/') && matches(rest, &path[1..])
+            },
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches(&pattern, &path)
+}
+
+/// Every file below `root` passing the filter, sorted by path. Symbolic
+/// links to directories are not followed. Entries that cannot be read are
+/// returned with their error instead of ending the walk.
+pub fn walk_dir(root: &Path, filter: &GlobFilter) -> (Vec<PathBuf>, Vec<(PathBuf, Error)>) {
+    let mut files = Vec::new();
+    let mut failures = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                failures.push((dir, err.into()));
+                continue;
+            },
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    failures.push((dir.clone(), err.into()));
+                    continue;
+                },
+            };
+            let path = entry.path();
+            // Symbolic links are followed to files only
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if is_dir {
+                dirs.push(path);
+            } else if path.is_file() {
+                let relative: Vec<String> = path.strip_prefix(root).unwrap_or(&path)
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                if filter.is_match(&relative.join("/")) {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    files.sort();
+    (files, failures)
+}
+
+/// Runs `work` over the items on `jobs` threads and hands every result to
+/// `on_result` on the calling thread as soon as it is ready, so results
+/// stream in completion order. A single job keeps the order of the items.
+pub fn run_parallel<T: Send, R: Send>(
+    items: Vec<T>,
+    jobs: usize,
+    work: impl Fn(T) -> R + Sync,
+    mut on_result: impl FnMut(R)) {
+    let queue = Mutex::new(items.into_iter());
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            let sender = sender.clone();
+            let (queue, work) = (&queue, &work);
+            scope.spawn(move || loop {
+                // Release the queue before working on the item
+                let item = queue.lock().unwrap().next();
+                let Some(item) = item else {
+                    break;
+                };
+                if sender.send(work(item)).is_err() {
+                    break;
+                }
+            });
+        }
+        // Only the workers hold senders now, receiving ends with them
+        drop(sender);
+        for result in receiver {
+            on_result(result);
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod test_batch {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases = [
+            ("*.png", "logo.png", true),
+            ("*.png", "logo.png.bak", false),
+            ("*.png", "icons/logo.png", false),
+            ("logo.???", "logo.jpg", true),
+            ("logo.???", "logo.jpeg", false),
+            ("icons/*", "icons/logo.png", true),
+            ("icons/*", "icons/small/logo.png", false),
+            ("icons/**", "icons/small/logo.png", true),
+            ("**/*.png", "logo.png", true),
+            ("**/*.png", "icons/small/logo.png", true),
+            ("**/small/*", "icons/small/logo.png", true),
+            ("**/small/*", "icons/large/logo.png", false),
+            ("*", "", true),
+            ("", "a", false),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(expected, glob_match(pattern, path), "Logic Error: {} on {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn test_glob_filter() {
+        let filter = GlobFilter {
+            include: vec!["*.png".into(), "*.jpg".into()],
+            exclude: vec!["drafts/**".into(), "*_thumb.*".into()],
+        };
+        assert!(filter.is_match("icons/logo.png"), "Logic Error: name pattern in a directory");
+        assert!(!filter.is_match("icons/logo.svg"), "Logic Error: not included");
+        assert!(!filter.is_match("drafts/logo.png"), "Logic Error: excluded directory");
+        assert!(!filter.is_match("logo_thumb.jpg"), "Logic Error: excluded name");
+        assert!(GlobFilter::default().is_match("anything"), "Logic Error: empty filter");
+    }
+
+    #[test]
+    fn test_walk_dir() {
+        let root = std::env::temp_dir().join("color-thief-rs-test-walk-dir");
+        let _ = std::fs::remove_dir_all(&root);
+        for path in ["b.png", "a.png", "notes.txt", "icons/c.png", "icons/deep/d.png"] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let filter = GlobFilter { include: vec!["*.png".into()], exclude: vec!["icons/deep/**".into()] };
+        let (files, failures) = walk_dir(&root, &filter);
+        let missing = walk_dir(&root.join("missing"), &filter);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let expected: Vec<PathBuf> = ["a.png", "b.png", "icons/c.png"].iter().map(|path| root.join(path)).collect();
+        assert_eq!(expected, files, "Logic Error: files");
+        assert!(failures.is_empty(), "Logic Error: failures");
+        assert!(missing.0.is_empty(), "Logic Error: missing files");
+        assert!(matches!(missing.1[..], [(_, Error::Io(_))]), "Logic Error: missing failure");
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        let mut found = Vec::new();
+        run_parallel((0..100).collect(), 4, |i: u32| i * 2, |result| found.push(result));
+        found.sort();
+        assert_eq!((0..100).map(|i| i * 2).collect::<Vec<u32>>(), found, "Logic Error: results");
+
+        let mut found = Vec::new();
+        run_parallel(vec!["a", "b", "c"], 1, |item| item, |result| found.push(result));
+        assert_eq!(vec!["a", "b", "c"], found, "Logic Error: single job order");
+    }
+}
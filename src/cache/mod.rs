@@ -0,0 +1,195 @@
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use image::Rgb;
+use crate::data_models::{ ColorPalette, Swatch };
+use crate::error::Error;
+use crate::export::PaletteRecord;
+use crate::histogram_io;
+
+
+/// First line of every cache entry, bumped on every incompatible change of
+/// the layout
+const HEADER: &str = "color-thief-rs palette cache 1";
+
+/// Tells temporary files of concurrent writers in the same process apart
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a result by the encoded source and every option that changes
+/// it, so editing either one misses the cache instead of reading a stale
+/// entry
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct CacheKey {
+    /// `histogram_io::hash_source` of the encoded source
+    pub content_hash: u64,
+    pub content_len: u64,
+    /// Canonical single-line description of the pipeline options, stored in
+    /// the entry and compared on load to rule out hash collisions
+    pub options: String,
+}
+
+impl CacheKey {
+    fn file_name(&self) -> String {
+        format!(
+            "{:016x}-{:x}-{:016x}.palette",
+            self.content_hash, self.content_len, histogram_io::hash_source(self.options.as_bytes()),
+        )
+    }
+}
+
+/// Directory of palette records keyed by `CacheKey`, one file per entry.
+///
+/// An entry is text: the header line, the options line, then per record
+/// the suffix it adds to the source name (`#3` for frame 3, usually
+/// empty), a tab, the pixel count, a tab and the swatches as space
+/// separated `rrggbb:population` pairs.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct PaletteCache {
+    dir: PathBuf,
+}
+
+impl PaletteCache {
+    /// Creates the directory when missing
+    pub fn open(dir: impl Into<PathBuf>) -> Result<PaletteCache, Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(PaletteCache { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Records of the entry renamed after `source`. Missing, unreadable and
+    /// corrupt entries are all a miss.
+    pub fn load(&self, key: &CacheKey, source: &str) -> Option<Vec<PaletteRecord>> {
+        let text = std::fs::read_to_string(self.dir.join(key.file_name())).ok()?;
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) || lines.next() != Some(key.options.as_str()) {
+            return None;
+        }
+        lines.map(|line| parse_record(line, source)).collect()
+    }
+
+    /// Writes the records under the key, replacing the entry at once so
+    /// concurrent readers never see it half written. Every record source
+    /// has to start with `source`.
+    pub fn store(&self, key: &CacheKey, source: &str, records: &[PaletteRecord]) -> Result<(), Error> {
+        if key.options.contains(['\n', '\r']) {
+            return Err(Error::Unsupported("cache options have to fit on one line".into()));
+        }
+        let mut text = format!("{}\n{}\n", HEADER, key.options);
+        for record in records {
+            let suffix = record.source.strip_prefix(source)
+                .filter(|suffix| !suffix.contains(['\t', '\n', '\r']))
+                .ok_or_else(|| Error::Unsupported(format!("cannot cache record {}", record.source)))?;
+            let swatches: Vec<String> = record.palette.swatches.iter()
+                .map(|swatch| format!("{}:{}", &swatch.hex()[1..], swatch.population))
+                .collect();
+            text += &format!("{}\t{}\t{}\n", suffix, record.palette.pixel_count, swatches.join(" "));
+        }
+
+        let path = self.dir.join(key.file_name());
+        let temp_path = self.dir.join(format!(
+            "{}.{}-{}.tmp",
+            key.file_name(), std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        std::fs::write(&temp_path, text)?;
+        std::fs::rename(&temp_path, &path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp_path);
+        })?;
+        Ok(())
+    }
+}
+
+/// `None` when the line is malformed or the populations do not add up
+fn parse_record(line: &str, source: &str) -> Option<PaletteRecord> {
+    let mut fields = line.split('\t');
+    let (suffix, pixel_count, swatches) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() {
+        return None;
+    }
+    let pixel_count: u32 = pixel_count.parse().ok()?;
+    let swatches: Vec<Swatch> = swatches.split(' ')
+        .filter(|swatch| !swatch.is_empty())
+        .map(parse_swatch)
+        .collect::<Option<_>>()?;
+    let total: u64 = swatches.iter().map(|swatch| swatch.population as u64).sum();
+    if total != pixel_count as u64 {
+        return None;
+    }
+    Some(PaletteRecord {
+        source: format!("{}{}", source, suffix),
+        palette: ColorPalette { swatches, pixel_count },
+    })
+}
+
+fn parse_swatch(swatch: &str) -> Option<Swatch> {
+    let (hex, population) = swatch.split_once(':')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Swatch {
+        color: Rgb([channel(0)?, channel(2)?, channel(4)?]),
+        population: population.parse().ok()?,
+    })
+}
+
+
+#[cfg(test)]
+mod test_cache {
+    use super::*;
+    use crate::test_util::palette;
+
+    fn record(source: &str, colors: &[([u8; 3], u32)]) -> PaletteRecord {
+        PaletteRecord { source: source.into(), palette: palette(colors) }
+    }
+
+    fn key(options: &str) -> CacheKey {
+        CacheKey { content_hash: 0xfeed, content_len: 42, options: options.into() }
+    }
+
+    #[test]
+    fn test_palette_cache() {
+        let dir = std::env::temp_dir().join("color-thief-rs-test-palette-cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = PaletteCache::open(&dir).unwrap();
+        let records = vec![
+            record("old.gif#0", &[([255, 0, 0], 12), ([0, 0, 255], 4)]),
+            record("old.gif#1", &[]),
+            record("old.gif", &[([10, 20, 30], 7)]),
+        ];
+        let key = key("count=2");
+        cache.store(&key, "old.gif", &records).unwrap();
+
+        // The same content under another name
+        let found = cache.load(&key, "new.gif").unwrap();
+        let sources: Vec<&str> = found.iter().map(|record| record.source.as_str()).collect();
+        assert_eq!(vec!["new.gif#0", "new.gif#1", "new.gif"], sources, "Logic Error: sources");
+        let palettes: Vec<&ColorPalette> = found.iter().map(|record| &record.palette).collect();
+        let expected: Vec<&ColorPalette> = records.iter().map(|record| &record.palette).collect();
+        assert_eq!(expected, palettes, "Logic Error: round trip");
+
+        // Other options and other content miss
+        assert_eq!(None, cache.load(&self::key("count=3"), "old.gif"), "Logic Error: options");
+        let other = CacheKey { content_len: 43, ..key.clone() };
+        assert_eq!(None, cache.load(&other, "old.gif"), "Logic Error: content");
+
+        // Corrupt entries miss
+        let path = dir.join(key.file_name());
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, text.replace("ff0000:12", "ff0000:13")).unwrap();
+        assert_eq!(None, cache.load(&key, "old.gif"), "Logic Error: population");
+        std::fs::write(&path, text.replace("ff0000", "fg0000")).unwrap();
+        assert_eq!(None, cache.load(&key, "old.gif"), "Logic Error: hex");
+
+        let found = cache.store(&self::key("a\nb"), "old.gif", &records);
+        assert!(matches!(found, Err(Error::Unsupported(_))), "Logic Error: multiline options");
+        let found = cache.store(&key, "other.gif", &records);
+        assert!(matches!(found, Err(Error::Unsupported(_))), "Logic Error: foreign source");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
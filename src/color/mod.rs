@@ -0,0 +1,498 @@
+use std::str::FromStr;
+use image::Rgb;
+
+
+/// D65 reference white in CIE XYZ, Y normalized to 1
+const D65_WHITE: [f64; 3] = [0.95047, 1.0, 1.08883];
+
+/// Hue in degrees from 0 to 360, saturation and lightness from 0 to 1
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Hsl {
+    pub h: f64,
+    pub s: f64,
+    pub l: f64,
+}
+
+/// Hue in degrees from 0 to 360, saturation and value from 0 to 1
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Hsv {
+    pub h: f64,
+    pub s: f64,
+    pub v: f64,
+}
+
+/// CIE XYZ relative to D65, Y from 0 to 1
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Xyz {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// CIE L*a*b* relative to D65, L from 0 to 100
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+/// Cylindrical CIE L*a*b*, hue in degrees from 0 to 360
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Lch {
+    pub l: f64,
+    pub c: f64,
+    pub h: f64,
+}
+
+/// Oklab, L from 0 to 1
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Oklab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+/// Cylindrical Oklab, hue in degrees from 0 to 360
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Oklch {
+    pub l: f64,
+    pub c: f64,
+    pub h: f64,
+}
+
+/// Naive CMYK without an ink profile, every channel from 0 to 1
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Cmyk {
+    pub c: f64,
+    pub m: f64,
+    pub y: f64,
+    pub k: f64,
+}
+
+/// sRGB transfer function, from an encoded channel between 0 and 1 to
+/// linear light
+pub fn decode_srgb(val: f64) -> f64 {
+    if val <= 0.04045 {
+        val / 12.92
+    } else {
+        ((val + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Inverse of `decode_srgb`
+pub fn encode_srgb(val: f64) -> f64 {
+    if val <= 0.0031308 {
+        val * 12.92
+    } else {
+        1.055 * val.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes an 8-bit sRGB channel to linear light between 0 and 1
+pub fn srgb_to_linear(val: u8) -> f64 {
+    decode_srgb(val as f64 / 255.0)
+}
+
+/// Encodes linear light to an 8-bit sRGB channel, clipping out of gamut
+/// values
+pub fn linear_to_srgb(val: f64) -> u8 {
+    (encode_srgb(val.clamp(0.0, 1.0)) * 255.0).round() as u8
+}
+
+/// Ways of bringing linear light above 1, as found in HDR images, into the
+/// displayable range
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum ToneMap {
+    /// Clips every channel at 1, highlights lose their hue
+    Clip,
+    /// `x / (1 + x)`, compresses the whole range
+    Reinhard,
+    /// Narkowicz fit of the ACES filmic curve
+    Aces,
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clip" => Ok(ToneMap::Clip),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::Aces),
+            _ => Err(format!("Unknown tone map: {}", s)),
+        }
+    }
+}
+
+/// Maps a linear light channel to the range 0 to 1
+pub fn tone_map(val: f64, tone_map: ToneMap) -> f64 {
+    let val = val.max(0.0);
+    let mapped = match tone_map {
+        ToneMap::Clip => val,
+        ToneMap::Reinhard => val / (1.0 + val),
+        ToneMap::Aces => (val * (2.51 * val + 0.03)) / (val * (2.43 * val + 0.59) + 0.14),
+    };
+    mapped.clamp(0.0, 1.0)
+}
+
+/// Scales a channel between 0 and 1 to 8 bits, clipping out of range values
+fn to_u8(val: f64) -> u8 {
+    (val * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Hue in degrees shared by HSL and HSV, 0 for grays
+fn calc_hue(r: f64, g: f64, b: f64, max: f64, delta: f64) -> f64 {
+    if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    }
+}
+
+/// Red, green and blue between 0 and 1 from hue, chroma and the offset
+/// added to every channel
+fn hue_chroma_to_rgb(h: f64, chroma: f64, offset: f64) -> Rgb<u8> {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    Rgb([to_u8(r + offset), to_u8(g + offset), to_u8(b + offset)])
+}
+
+pub fn rgb_to_hsl(color: Rgb<u8>) -> Hsl {
+    let [r, g, b] = color.0.map(|val| val as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let l = (max + min) / 2.0;
+    let s = if delta == 0.0 { 0.0 } else { delta / (1.0 - (2.0 * l - 1.0).abs()) };
+    Hsl { h: calc_hue(r, g, b, max, delta), s, l }
+}
+
+pub fn hsl_to_rgb(color: Hsl) -> Rgb<u8> {
+    let l = color.l.clamp(0.0, 1.0);
+    let s = color.s.clamp(0.0, 1.0);
+    let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    hue_chroma_to_rgb(color.h, chroma, l - chroma / 2.0)
+}
+
+pub fn rgb_to_hsv(color: Rgb<u8>) -> Hsv {
+    let [r, g, b] = color.0.map(|val| val as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    Hsv { h: calc_hue(r, g, b, max, delta), s, v: max }
+}
+
+pub fn hsv_to_rgb(color: Hsv) -> Rgb<u8> {
+    let v = color.v.clamp(0.0, 1.0);
+    let chroma = v * color.s.clamp(0.0, 1.0);
+    hue_chroma_to_rgb(color.h, chroma, v - chroma)
+}
+
+pub fn rgb_to_xyz(color: Rgb<u8>) -> Xyz {
+    linear_to_xyz(color.0.map(srgb_to_linear))
+}
+
+/// CIE XYZ of linear light sRGB channels, keeping the full precision of
+/// 16-bit and floating-point sources
+pub fn linear_to_xyz(linear: [f64; 3]) -> Xyz {
+    let [r, g, b] = linear;
+    Xyz {
+        x: 0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        y: 0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        z: 0.0193339 * r + 0.1191920 * g + 0.9503041 * b,
+    }
+}
+
+pub fn xyz_to_rgb(color: Xyz) -> Rgb<u8> {
+    let Xyz { x, y, z } = color;
+    let r = 3.2404542 * x - 1.5371385 * y - 0.4985314 * z;
+    let g = -0.9692660 * x + 1.8760108 * y + 0.0415560 * z;
+    let b = 0.0556434 * x - 0.2040259 * y + 1.0572252 * z;
+    Rgb([linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b)])
+}
+
+pub fn xyz_to_lab(color: Xyz) -> Lab {
+    const EPSILON: f64 = 216.0 / 24389.0;
+    const KAPPA: f64 = 24389.0 / 27.0;
+    let xyz = [color.x, color.y, color.z];
+    let [fx, fy, fz] = [0, 1, 2].map(|i| {
+        let t = xyz[i] / D65_WHITE[i];
+        if t > EPSILON { t.cbrt() } else { (KAPPA * t + 16.0) / 116.0 }
+    });
+    Lab {
+        l: 116.0 * fy - 16.0,
+        a: 500.0 * (fx - fy),
+        b: 200.0 * (fy - fz),
+    }
+}
+
+pub fn lab_to_xyz(color: Lab) -> Xyz {
+    const EPSILON: f64 = 216.0 / 24389.0;
+    const KAPPA: f64 = 24389.0 / 27.0;
+    let fy = (color.l + 16.0) / 116.0;
+    let fx = fy + color.a / 500.0;
+    let fz = fy - color.b / 200.0;
+    let inverse = |f: f64| {
+        let t = f.powi(3);
+        if t > EPSILON { t } else { (116.0 * f - 16.0) / KAPPA }
+    };
+    let y = if color.l > KAPPA * EPSILON { fy.powi(3) } else { color.l / KAPPA };
+    Xyz {
+        x: inverse(fx) * D65_WHITE[0],
+        y: y * D65_WHITE[1],
+        z: inverse(fz) * D65_WHITE[2],
+    }
+}
+
+pub fn rgb_to_lab(color: Rgb<u8>) -> Lab {
+    xyz_to_lab(rgb_to_xyz(color))
+}
+
+pub fn lab_to_rgb(color: Lab) -> Rgb<u8> {
+    xyz_to_rgb(lab_to_xyz(color))
+}
+
+/// Polar form of a pair of opponent axes, hue in degrees from 0 to 360
+fn to_polar(a: f64, b: f64) -> (f64, f64) {
+    (a.hypot(b), b.atan2(a).to_degrees().rem_euclid(360.0))
+}
+
+fn from_polar(c: f64, h: f64) -> (f64, f64) {
+    let h = h.to_radians();
+    (c * h.cos(), c * h.sin())
+}
+
+pub fn lab_to_lch(color: Lab) -> Lch {
+    let (c, h) = to_polar(color.a, color.b);
+    Lch { l: color.l, c, h }
+}
+
+pub fn lch_to_lab(color: Lch) -> Lab {
+    let (a, b) = from_polar(color.c, color.h);
+    Lab { l: color.l, a, b }
+}
+
+pub fn rgb_to_lch(color: Rgb<u8>) -> Lch {
+    lab_to_lch(rgb_to_lab(color))
+}
+
+pub fn lch_to_rgb(color: Lch) -> Rgb<u8> {
+    lab_to_rgb(lch_to_lab(color))
+}
+
+pub fn rgb_to_oklab(color: Rgb<u8>) -> Oklab {
+    linear_to_oklab(color.0.map(srgb_to_linear))
+}
+
+/// Oklab of linear light sRGB channels
+pub fn linear_to_oklab(linear: [f64; 3]) -> Oklab {
+    let [r, g, b] = linear;
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    Oklab {
+        l: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    }
+}
+
+pub fn oklab_to_rgb(color: Oklab) -> Rgb<u8> {
+    let l = (color.l + 0.3963377774 * color.a + 0.2158037573 * color.b).powi(3);
+    let m = (color.l - 0.1055613458 * color.a - 0.0638541728 * color.b).powi(3);
+    let s = (color.l - 0.0894841775 * color.a - 1.2914855480 * color.b).powi(3);
+    Rgb([
+        linear_to_srgb(4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+        linear_to_srgb(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+        linear_to_srgb(-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s),
+    ])
+}
+
+pub fn oklab_to_oklch(color: Oklab) -> Oklch {
+    let (c, h) = to_polar(color.a, color.b);
+    Oklch { l: color.l, c, h }
+}
+
+pub fn oklch_to_oklab(color: Oklch) -> Oklab {
+    let (a, b) = from_polar(color.c, color.h);
+    Oklab { l: color.l, a, b }
+}
+
+pub fn rgb_to_oklch(color: Rgb<u8>) -> Oklch {
+    oklab_to_oklch(rgb_to_oklab(color))
+}
+
+pub fn oklch_to_rgb(color: Oklch) -> Rgb<u8> {
+    oklab_to_rgb(oklch_to_oklab(color))
+}
+
+pub fn rgb_to_cmyk(color: Rgb<u8>) -> Cmyk {
+    let [r, g, b] = color.0.map(|val| val as f64 / 255.0);
+    let k = 1.0 - r.max(g).max(b);
+    if k == 1.0 {
+        return Cmyk { c: 0.0, m: 0.0, y: 0.0, k };
+    }
+    Cmyk {
+        c: (1.0 - r - k) / (1.0 - k),
+        m: (1.0 - g - k) / (1.0 - k),
+        y: (1.0 - b - k) / (1.0 - k),
+        k,
+    }
+}
+
+pub fn cmyk_to_rgb(color: Cmyk) -> Rgb<u8> {
+    let white = 1.0 - color.k;
+    Rgb([
+        to_u8((1.0 - color.c) * white),
+        to_u8((1.0 - color.m) * white),
+        to_u8((1.0 - color.y) * white),
+    ])
+}
+
+
+#[cfg(test)]
+mod test_color {
+    use super::*;
+    use crate::test_util::assert_close;
+
+    #[test]
+    fn test_rgb_to_lab() {
+        let found = rgb_to_lab(Rgb([255, 0, 0]));
+        assert_close([53.2408, 80.0925, 67.2032], [found.l, found.a, found.b], 0.01);
+        let found = rgb_to_lab(Rgb([255, 255, 255]));
+        assert_close([100.0, 0.0, 0.0], [found.l, found.a, found.b], 0.01);
+        let found = rgb_to_lab(Rgb([0, 0, 255]));
+        assert_close([32.2970, 79.1875, -107.8602], [found.l, found.a, found.b], 0.01);
+    }
+
+    #[test]
+    fn test_rgb_to_oklab() {
+        let found = rgb_to_oklab(Rgb([255, 0, 0]));
+        assert_close([0.627955, 0.224863, 0.125846], [found.l, found.a, found.b], 1e-4);
+        let found = rgb_to_oklab(Rgb([255, 255, 255]));
+        assert_close([1.0, 0.0, 0.0], [found.l, found.a, found.b], 1e-4);
+    }
+
+    #[test]
+    fn test_rgb_to_hsl() {
+        let found = rgb_to_hsl(Rgb([255, 128, 0]));
+        assert_close([30.1176, 1.0, 0.5], [found.h, found.s, found.l], 1e-4);
+        let found = rgb_to_hsl(Rgb([64, 128, 191]));
+        assert_close([209.7638, 0.4980, 0.5000], [found.h, found.s, found.l], 1e-4);
+    }
+
+    #[test]
+    fn test_rgb_to_hsv() {
+        let found = rgb_to_hsv(Rgb([255, 128, 0]));
+        assert_close([30.1176, 1.0, 1.0], [found.h, found.s, found.v], 1e-4);
+        let found = rgb_to_hsv(Rgb([64, 128, 191]));
+        assert_close([209.7638, 0.6649, 0.7490], [found.h, found.s, found.v], 1e-4);
+    }
+
+    #[test]
+    fn test_rgb_to_xyz() {
+        let found = rgb_to_xyz(Rgb([255, 0, 0]));
+        assert_close([0.412456, 0.212673, 0.019334], [found.x, found.y, found.z], 1e-6);
+        let found = rgb_to_xyz(Rgb([255, 255, 255]));
+        assert_close(D65_WHITE, [found.x, found.y, found.z], 1e-4);
+    }
+
+    #[test]
+    fn test_rgb_to_lch() {
+        let found = rgb_to_lch(Rgb([255, 0, 0]));
+        assert_close([53.2408, 104.5518, 39.9990], [found.l, found.c, found.h], 0.01);
+        let found = rgb_to_lch(Rgb([0, 0, 255]));
+        assert_close([32.2970, 133.8076, 306.2849], [found.l, found.c, found.h], 0.01);
+    }
+
+    #[test]
+    fn test_rgb_to_oklch() {
+        let found = rgb_to_oklch(Rgb([255, 0, 0]));
+        assert_close([0.627955, 0.257683, 29.2339], [found.l, found.c, found.h], 1e-3);
+    }
+
+    #[test]
+    fn test_rgb_to_cmyk() {
+        let found = rgb_to_cmyk(Rgb([255, 128, 0]));
+        assert_close([0.0, 0.4980, 1.0], [found.c, found.m, found.y], 1e-4);
+        assert_eq!(0.0, found.k, "Logic Error: key");
+        let found = rgb_to_cmyk(Rgb([0, 0, 0]));
+        assert_eq!(Cmyk { c: 0.0, m: 0.0, y: 0.0, k: 1.0 }, found, "Logic Error: black");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for color in [[0, 0, 0], [255, 255, 255], [12, 200, 99], [250, 3, 140], [1, 1, 2], [128, 128, 128]] {
+            let color = Rgb(color);
+            assert_eq!(color, hsl_to_rgb(rgb_to_hsl(color)), "Logic Error: HSL");
+            assert_eq!(color, hsv_to_rgb(rgb_to_hsv(color)), "Logic Error: HSV");
+            assert_eq!(color, xyz_to_rgb(rgb_to_xyz(color)), "Logic Error: XYZ");
+            assert_eq!(color, lab_to_rgb(rgb_to_lab(color)), "Logic Error: Lab");
+            assert_eq!(color, lch_to_rgb(rgb_to_lch(color)), "Logic Error: LCh");
+            assert_eq!(color, oklab_to_rgb(rgb_to_oklab(color)), "Logic Error: Oklab");
+            assert_eq!(color, oklch_to_rgb(rgb_to_oklch(color)), "Logic Error: Oklch");
+            assert_eq!(color, cmyk_to_rgb(rgb_to_cmyk(color)), "Logic Error: CMYK");
+        }
+    }
+
+    #[test]
+    fn test_encode_srgb() {
+        for val in [0, 1, 10, 128, 254, 255] {
+            let found = encode_srgb(decode_srgb(val as f64 / 255.0)) * 255.0;
+            assert!((val as f64 - found).abs() < 1e-9, "Logic Error: {}", val);
+        }
+    }
+
+    #[test]
+    fn test_tone_map() {
+        assert_eq!(1.0, tone_map(4.0, ToneMap::Clip), "Logic Error: clip");
+        assert_eq!(0.25, tone_map(0.25, ToneMap::Clip), "Logic Error: clip in range");
+        assert_eq!(0.8, tone_map(4.0, ToneMap::Reinhard), "Logic Error: reinhard");
+        assert_eq!(0.0, tone_map(-1.0, ToneMap::Reinhard), "Logic Error: negative");
+        // ACES maps 0 to 0 and saturates before reaching very bright values
+        assert_eq!(0.0, tone_map(0.0, ToneMap::Aces), "Logic Error: aces black");
+        assert_eq!(1.0, tone_map(100.0, ToneMap::Aces), "Logic Error: aces white");
+        let found = tone_map(0.18, ToneMap::Aces);
+        assert!((0.2670 - found).abs() < 1e-3, "Logic Error: aces mid gray {}", found);
+    }
+}
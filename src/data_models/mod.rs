@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 use std::ops::Add;
+use std::str::FromStr;
+use image::Rgb;
+use crate::color::{ self, Hsl, Hsv, Xyz, Lab, Lch, Oklab, Oklch, Cmyk };
 
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Histogram(pub Vec<u32>);
 
 // Encapsulation with effectively-functional programming methods
@@ -16,13 +20,106 @@ pub struct Histogram(pub Vec<u32>);
 //     }
 // }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DimHistograms(pub [Histogram; 3]);
 
+/// Pixel count of every occupied bin. Maps of several images or pixel
+/// shards can be combined and quantized as one, the counts are kept to a
+/// sum of at most `u32::MAX`. Serializes as a map from bin hash to count,
+/// sorted by bin hash.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Default)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "UncheckedFrequencyMap"))]
 pub struct FrequencyMap(pub HashMap<u32,u32>);
 
+/// Deserialized frequency map whose bin hashes are not checked yet
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(rename = "FrequencyMap")]
+struct UncheckedFrequencyMap(HashMap<u32,u32>);
+
+#[cfg(feature = "serde")]
+impl TryFrom<UncheckedFrequencyMap> for FrequencyMap {
+    type Error = String;
+
+    /// Hashes past the last bin would be masked into another one, like in
+    /// `SavedHistogram::from_bytes` they are rejected
+    fn try_from(map: UncheckedFrequencyMap) -> Result<Self, Self::Error> {
+        let bins = 1 << (3 * crate::mmcq::MMCQ::SIGNIFICANT_BITS);
+        match map.0.keys().find(|&&hash| hash >= bins) {
+            Some(hash) => Err(format!("bin hash {} is out of range", hash)),
+            None => Ok(FrequencyMap(map.0)),
+        }
+    }
+}
+
+impl FrequencyMap {
+    /// Sum of every count
+    pub fn total(&self) -> u64 {
+        self.0.values().map(|&count| count as u64).sum()
+    }
+
+    /// Adds the counts of another map, bin by bin. When the sum would
+    /// pass `u32::MAX` both maps are scaled down by the same factor first,
+    /// keeping their proportions, and bins rounding to zero are dropped.
+    pub fn add(&mut self, other: &FrequencyMap) {
+        let total = self.total() as u128 + other.total() as u128;
+        let scale = |count: u32| match total > u32::MAX as u128 {
+            true => (count as u128 * u32::MAX as u128 / total) as u32,
+            false => count,
+        };
+        self.0.retain(|_, count| {
+            *count = scale(*count);
+            *count > 0
+        });
+        for (&hash, &count) in &other.0 {
+            let count = scale(count);
+            if count > 0 {
+                *self.0.entry(hash).or_insert(0) += count;
+            }
+        }
+    }
+
+    /// Multiplies every count, e.g. by how long an animation frame is shown.
+    /// Counts whose sum would pass `u32::MAX` are scaled to fit instead,
+    /// keeping their proportions.
+    pub fn weight(&mut self, factor: u32) {
+        let total = self.total() as u128 * factor as u128;
+        if total > u32::MAX as u128 {
+            let current = self.total() as u128;
+            self.0.retain(|_, count| {
+                *count = (*count as u128 * u32::MAX as u128 / current) as u32;
+                *count > 0
+            });
+            return;
+        }
+        for count in self.0.values_mut() {
+            *count *= factor;
+        }
+    }
+
+    /// Scales the counts to sum to about `total`, so every image weighs the
+    /// same in a merged map whatever its size. Bins rounding to zero are
+    /// dropped.
+    pub fn normalize(&mut self, total: u32) {
+        let current = self.total() as u128;
+        if current == 0 {
+            return;
+        }
+        self.0.retain(|_, count| {
+            *count = ((*count as u128 * total as u128 + current / 2) / current) as u32;
+            *count > 0
+        });
+    }
+}
+
 #[derive(PartialEq)]
 #[derive(Clone)]
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MinMaxBox {
     pub rmin: u8,
     pub rmax: u8,
@@ -32,6 +129,29 @@ pub struct MinMaxBox {
     pub bmax: u8,
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for FrequencyMap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Sorted so the same map always gives the same bytes
+        let sorted: std::collections::BTreeMap<&u32, &u32> = self.0.iter().collect();
+        serializer.serialize_newtype_struct("FrequencyMap", &sorted)
+    }
+}
+
+impl MinMaxBox {
+    /// Smallest box holding both boxes
+    pub fn union(&self, other: &MinMaxBox) -> MinMaxBox {
+        MinMaxBox {
+            rmin: self.rmin.min(other.rmin),
+            rmax: self.rmax.max(other.rmax),
+            gmin: self.gmin.min(other.gmin),
+            gmax: self.gmax.max(other.gmax),
+            bmin: self.bmin.min(other.bmin),
+            bmax: self.bmax.max(other.bmax),
+        }
+    }
+}
+
 impl std::fmt::Display for MinMaxBox {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -46,6 +166,7 @@ impl std::fmt::Display for MinMaxBox {
 
 #[derive(PartialEq)]
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BoxQueue(pub Vec<MinMaxBox>);
 
 impl std::fmt::Display for BoxQueue {
@@ -58,6 +179,146 @@ impl std::fmt::Display for BoxQueue {
     }
 }
 
+/// A single palette color together with the number of filtered pixels
+/// that fell inside its MinMaxBox. Serializes the color as `#rrggbb`.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Swatch {
+    #[cfg_attr(feature = "serde", serde(with = "hex_color"))]
+    pub color: Rgb<u8>,
+    pub population: u32,
+}
+
+impl Swatch {
+    /// Lowercase `#rrggbb` notation of the swatch color
+    pub fn hex(&self) -> String {
+        let [r, g, b] = self.color.0;
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    pub fn hsl(&self) -> Hsl {
+        color::rgb_to_hsl(self.color)
+    }
+
+    pub fn hsv(&self) -> Hsv {
+        color::rgb_to_hsv(self.color)
+    }
+
+    pub fn xyz(&self) -> Xyz {
+        color::rgb_to_xyz(self.color)
+    }
+
+    pub fn lab(&self) -> Lab {
+        color::rgb_to_lab(self.color)
+    }
+
+    pub fn lch(&self) -> Lch {
+        color::rgb_to_lch(self.color)
+    }
+
+    pub fn oklab(&self) -> Oklab {
+        color::rgb_to_oklab(self.color)
+    }
+
+    pub fn oklch(&self) -> Oklch {
+        color::rgb_to_oklch(self.color)
+    }
+
+    /// Naive conversion, see `color::Cmyk`
+    pub fn cmyk(&self) -> Cmyk {
+        color::rgb_to_cmyk(self.color)
+    }
+}
+
+/// Serde representation of a color as lowercase `#rrggbb`
+#[cfg(feature = "serde")]
+mod hex_color {
+    use image::Rgb;
+    use serde::{ Deserialize, Deserializer, Serializer };
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(color: &Rgb<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+        let [r, g, b] = color.0;
+        serializer.serialize_str(&format!("#{:02x}{:02x}{:02x}", r, g, b))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rgb<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let digits = hex.strip_prefix('#')
+            .filter(|digits| digits.len() == 6 && digits.is_ascii())
+            .ok_or_else(|| D::Error::custom(format!("expected #rrggbb, found {}", hex)))?;
+        let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16)
+            .map_err(|_| D::Error::custom(format!("expected #rrggbb, found {}", hex)));
+        Ok(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+    }
+}
+
+/// Result of the MMCQ algorithm, swatches are sorted by descending
+/// population. `pixel_count` is the number of pixels that survived
+/// filtering, i.e. the sum of every swatch population.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColorPalette {
+    pub swatches: Vec<Swatch>,
+    pub pixel_count: u32,
+}
+
+/// Coordinates the pixels are binned in before quantization. Perceptual
+/// spaces spread the bins evenly over perceived differences so dark colors
+/// are not merged while bright ones are over-split.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ColorSpace {
+    Rgb,
+    Lab,
+    Oklab,
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgb" | "srgb" => Ok(ColorSpace::Rgb),
+            "lab" | "cielab" => Ok(ColorSpace::Lab),
+            "oklab" => Ok(ColorSpace::Oklab),
+            _ => Err(format!("Unknown color space: {}", s)),
+        }
+    }
+}
+
+/// Settings shared by every step of the MMCQ algorithm
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct QuantizeOptions {
+    pub color_count: usize,
+    pub color_space: ColorSpace,
+    /// Average box colors in linear light instead of averaging the sRGB
+    /// values, only used with `ColorSpace::Rgb`
+    pub linear_average: bool,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            color_count: 10,
+            color_space: ColorSpace::Rgb,
+            linear_average: false,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
 pub enum ColorChannel {
@@ -80,3 +341,77 @@ impl Add<i32> for ColorChannel {
         }
     }
 }
+
+
+#[cfg(all(test, feature = "serde"))]
+mod test_data_models {
+    use super::*;
+
+    fn palette() -> ColorPalette {
+        ColorPalette {
+            swatches: vec![
+                Swatch { color: Rgb([20, 52, 28]), population: 2 },
+                Swatch { color: Rgb([255, 0, 171]), population: 1 },
+            ],
+            pixel_count: 3,
+        }
+    }
+
+    #[test]
+    fn test_serialize_json() {
+        // Field names and hex colors are part of the stable format
+        let expected = r##"{"swatches":[{"color":"#14341c","population":2},{"color":"#ff00ab","population":1}],"pixel_count":3}"##;
+        let found = serde_json::to_string(&palette()).unwrap();
+        assert_eq!(expected, found, "Logic Error: palette");
+        assert_eq!(palette(), serde_json::from_str(&found).unwrap(), "Logic Error: palette round trip");
+
+        let minmax_box = MinMaxBox { rmin: 0, rmax: 31, gmin: 1, gmax: 2, bmin: 3, bmax: 4 };
+        let expected = r#"[{"rmin":0,"rmax":31,"gmin":1,"gmax":2,"bmin":3,"bmax":4}]"#;
+        let found = serde_json::to_string(&BoxQueue(vec![minmax_box])).unwrap();
+        assert_eq!(expected, found, "Logic Error: box queue");
+
+        let frequency_map = FrequencyMap(HashMap::from([(1057, 2), (3, 5), (31744, 1)]));
+        let found = serde_json::to_string(&frequency_map).unwrap();
+        assert_eq!(r#"{"3":5,"1057":2,"31744":1}"#, found, "Logic Error: sorted frequency map");
+        assert_eq!(frequency_map, serde_json::from_str(&found).unwrap(), "Logic Error: frequency map round trip");
+        let found = serde_json::from_str::<FrequencyMap>(r#"{"40000":5,"1":3}"#);
+        assert!(found.is_err(), "Logic Error: hash out of range");
+        let found: FrequencyMap = serde_json::from_str(r#"{"32767":5}"#).unwrap();
+        assert_eq!(5, found.0[&32767], "Logic Error: last bin");
+
+        let histograms = DimHistograms([Histogram(vec![1, 2]), Histogram(vec![]), Histogram(vec![3])]);
+        let found = serde_json::to_string(&histograms).unwrap();
+        assert_eq!("[[1,2],[],[3]]", found, "Logic Error: histograms");
+
+        let found = serde_json::to_string(&QuantizeOptions::default()).unwrap();
+        let expected = r#"{"color_count":10,"color_space":"rgb","linear_average":false}"#;
+        assert_eq!(expected, found, "Logic Error: options");
+        let found: QuantizeOptions = serde_json::from_str(r#"{"color_space":"oklab"}"#).unwrap();
+        assert_eq!(ColorSpace::Oklab, found.color_space, "Logic Error: partial options");
+    }
+
+    #[test]
+    fn test_serialize_msgpack() {
+        let bytes = rmp_serde::to_vec_named(&palette()).unwrap();
+        let found: ColorPalette = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(palette(), found, "Logic Error: palette");
+
+        let frequency_map = FrequencyMap(HashMap::from([(1057, 2), (3, 5)]));
+        let bytes = rmp_serde::to_vec_named(&frequency_map).unwrap();
+        let found: FrequencyMap = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(frequency_map, found, "Logic Error: frequency map");
+    }
+
+    #[test]
+    fn test_deserialize_hex_color() {
+        // Missing #, short, not hex, six bytes but not six digits
+        let cases = ["14341c", "#14341", "#14341g", "#1434é"];
+        for hex in cases {
+            let json = format!(r#"{{"color":"{}","population":1}}"#, hex);
+            let found = serde_json::from_str::<Swatch>(&json);
+            assert!(found.is_err(), "Logic Error: {}", json);
+        }
+        let found: Swatch = serde_json::from_str(r##"{"color":"#FF00AB","population":1}"##).unwrap();
+        assert_eq!(Rgb([255, 0, 171]), found.color, "Logic Error: uppercase");
+    }
+}
@@ -0,0 +1,330 @@
+use std::str::FromStr;
+use image::Rgb;
+use crate::color::{ self, Lab, Oklab };
+use crate::data_models::{ ColorPalette, Swatch };
+
+
+/// Ways of measuring how different two colors look, from the cheapest to
+/// the most perceptually uniform
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum DistanceMetric {
+    /// Euclidean distance between 8-bit sRGB values
+    Rgb,
+    /// sRGB distance with the weights of the compuphase "redmean"
+    /// approximation
+    Redmean,
+    Cie76,
+    /// CIE94 with the graphic arts constants
+    Cie94,
+    Ciede2000,
+    /// Euclidean distance in Oklab
+    Oklab,
+}
+
+impl FromStr for DistanceMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgb" => Ok(DistanceMetric::Rgb),
+            "redmean" => Ok(DistanceMetric::Redmean),
+            "cie76" => Ok(DistanceMetric::Cie76),
+            "cie94" => Ok(DistanceMetric::Cie94),
+            "ciede2000" | "de2000" => Ok(DistanceMetric::Ciede2000),
+            "oklab" => Ok(DistanceMetric::Oklab),
+            _ => Err(format!("Unknown distance metric: {}", s)),
+        }
+    }
+}
+
+/// Difference between two sRGB colors, 0 for equal colors
+pub fn color_distance(a: Rgb<u8>, b: Rgb<u8>, metric: DistanceMetric) -> f64 {
+    match metric {
+        DistanceMetric::Rgb => {
+            a.0.iter().zip(b.0)
+                .map(|(&x, y)| (x as f64 - y as f64).powi(2))
+                .sum::<f64>()
+                .sqrt()
+        },
+        DistanceMetric::Redmean => redmean(a, b),
+        DistanceMetric::Cie76 => delta_e_76(color::rgb_to_lab(a), color::rgb_to_lab(b)),
+        DistanceMetric::Cie94 => delta_e_94(color::rgb_to_lab(a), color::rgb_to_lab(b)),
+        DistanceMetric::Ciede2000 => {
+            delta_e_2000(color::rgb_to_lab(a), color::rgb_to_lab(b))
+        },
+        DistanceMetric::Oklab => delta_e_ok(color::rgb_to_oklab(a), color::rgb_to_oklab(b)),
+    }
+}
+
+fn redmean(a: Rgb<u8>, b: Rgb<u8>) -> f64 {
+    let [r1, g1, b1] = a.0.map(|val| val as f64);
+    let [r2, g2, b2] = b.0.map(|val| val as f64);
+    let rmean = (r1 + r2) / 2.0;
+    let (dr, dg, db) = (r1 - r2, g1 - g2, b1 - b2);
+    ((2.0 + rmean / 256.0) * dr * dr
+        + 4.0 * dg * dg
+        + (2.0 + (255.0 - rmean) / 256.0) * db * db)
+        .sqrt()
+}
+
+/// Euclidean distance in CIE L*a*b*
+pub fn delta_e_76(a: Lab, b: Lab) -> f64 {
+    ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+/// CIE94 color difference using the graphic arts weights, not symmetric
+/// since the chroma weights come from the first color
+pub fn delta_e_94(a: Lab, b: Lab) -> f64 {
+    const K1: f64 = 0.045;
+    const K2: f64 = 0.015;
+    let c1 = a.a.hypot(a.b);
+    let c2 = b.a.hypot(b.b);
+    let delta_l = a.l - b.l;
+    let delta_c = c1 - c2;
+    // Hue difference squared, clamped against rounding below zero
+    let delta_h2 = ((a.a - b.a).powi(2) + (a.b - b.b).powi(2) - delta_c * delta_c).max(0.0);
+    let s_c = 1.0 + K1 * c1;
+    let s_h = 1.0 + K2 * c1;
+    (delta_l.powi(2) + (delta_c / s_c).powi(2) + delta_h2 / (s_h * s_h)).sqrt()
+}
+
+/// CIEDE2000 color difference with unit weighting factors, following
+/// Sharma, Wu and Dalal (2005)
+pub fn delta_e_2000(a: Lab, b: Lab) -> f64 {
+    let c_mean = (a.a.hypot(a.b) + b.a.hypot(b.b)) / 2.0;
+    let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt());
+    let a1 = (1.0 + g) * a.a;
+    let a2 = (1.0 + g) * b.a;
+    let c1 = a1.hypot(a.b);
+    let c2 = a2.hypot(b.b);
+    let hue = |b: f64, a: f64| match (b, a) {
+        (0.0, 0.0) => 0.0,
+        _ => b.atan2(a).to_degrees().rem_euclid(360.0),
+    };
+    let h1 = hue(a.b, a1);
+    let h2 = hue(b.b, a2);
+
+    let delta_l = b.l - a.l;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_mean = (a.l + b.l) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    let t = 1.0
+        - 0.17 * (h_mean - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_mean).to_radians().cos()
+        + 0.32 * (3.0 * h_mean + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_mean - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_mean;
+    let s_h = 1.0 + 0.015 * c_mean * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    ((delta_l / s_l).powi(2)
+        + (delta_c / s_c).powi(2)
+        + (delta_h / s_h).powi(2)
+        + r_t * (delta_c / s_c) * (delta_h / s_h))
+        .sqrt()
+}
+
+/// Euclidean distance in Oklab
+pub fn delta_e_ok(a: Oklab, b: Oklab) -> f64 {
+    ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+/// Index of the swatch closest to the color, `None` for an empty palette
+pub fn find_nearest(palette: &ColorPalette, color: Rgb<u8>, metric: DistanceMetric) -> Option<usize> {
+    palette.swatches.iter()
+        .map(|swatch| color_distance(color, swatch.color, metric))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+}
+
+/// Folds every swatch closer than `threshold` to a more populated swatch
+/// into it. The kept swatch keeps its color and gains the population, so
+/// `pixel_count` is unchanged.
+pub fn merge_similar(palette: &ColorPalette, threshold: f64, metric: DistanceMetric) -> ColorPalette {
+    let mut merged: Vec<Swatch> = Vec::new();
+    for swatch in &palette.swatches {
+        let nearest = merged.iter_mut()
+            .map(|kept| (color_distance(kept.color, swatch.color, metric), kept))
+            .filter(|(distance, _)| *distance < threshold)
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        match nearest {
+            Some((_, kept)) => kept.population += swatch.population,
+            None => merged.push(swatch.clone()),
+        }
+    }
+    merged.sort_by_key(|swatch| std::cmp::Reverse(swatch.population));
+    ColorPalette { swatches: merged, pixel_count: palette.pixel_count }
+}
+
+/// Symmetric difference between two palettes: every swatch is matched to
+/// its nearest swatch in the other palette and the distances are averaged,
+/// weighted by population. Identical palettes give 0, comparing against
+/// an empty palette gives infinity.
+pub fn compare_palettes(a: &ColorPalette, b: &ColorPalette, metric: DistanceMetric) -> f64 {
+    if a.swatches.is_empty() && b.swatches.is_empty() {
+        return 0.0;
+    }
+    if a.swatches.is_empty() || b.swatches.is_empty() {
+        return f64::INFINITY;
+    }
+    let one_way = |from: &ColorPalette, to: &ColorPalette| -> f64 {
+        let total: f64 = from.swatches.iter().map(|swatch| swatch.population as f64).sum();
+        let weighted: f64 = from.swatches.iter()
+            .map(|swatch| {
+                let nearest = to.swatches.iter()
+                    .map(|other| color_distance(swatch.color, other.color, metric))
+                    .fold(f64::INFINITY, f64::min);
+                nearest * swatch.population as f64
+            })
+            .sum();
+        match total {
+            0.0 => 0.0,
+            total => weighted / total,
+        }
+    };
+    (one_way(a, b) + one_way(b, a)) / 2.0
+}
+
+
+#[cfg(test)]
+mod test_distance {
+    use super::*;
+    use crate::test_util::palette;
+
+    fn lab(l: f64, a: f64, b: f64) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn test_delta_e_2000() {
+        // Sharma, Wu and Dalal (2005), table 1
+        let dataset = [
+            ([50.0000, 2.6772, -79.7751], [50.0000, 0.0000, -82.7485], 2.0425),
+            ([50.0000, 3.1571, -77.2803], [50.0000, 0.0000, -82.7485], 2.8615),
+            ([50.0000, 2.8361, -74.0200], [50.0000, 0.0000, -82.7485], 3.4412),
+            ([50.0000, -1.3802, -84.2814], [50.0000, 0.0000, -82.7485], 1.0000),
+            ([50.0000, -1.1848, -84.8006], [50.0000, 0.0000, -82.7485], 1.0000),
+            ([50.0000, -0.9009, -85.5211], [50.0000, 0.0000, -82.7485], 1.0000),
+            ([50.0000, 0.0000, 0.0000], [50.0000, -1.0000, 2.0000], 2.3669),
+            ([50.0000, -1.0000, 2.0000], [50.0000, 0.0000, 0.0000], 2.3669),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0009], 7.1792),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0010], 7.1792),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0011], 7.2195),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0012], 7.2195),
+            ([50.0000, -0.0010, 2.4900], [50.0000, 0.0009, -2.4900], 4.8045),
+            ([50.0000, -0.0010, 2.4900], [50.0000, 0.0010, -2.4900], 4.8045),
+            ([50.0000, -0.0010, 2.4900], [50.0000, 0.0011, -2.4900], 4.7461),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 0.0000, -2.5000], 4.3065),
+            ([50.0000, 2.5000, 0.0000], [73.0000, 25.0000, -18.0000], 27.1492),
+            ([50.0000, 2.5000, 0.0000], [61.0000, -5.0000, 29.0000], 22.8977),
+            ([50.0000, 2.5000, 0.0000], [56.0000, -27.0000, -3.0000], 31.9030),
+            ([50.0000, 2.5000, 0.0000], [58.0000, 24.0000, 15.0000], 19.4535),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 3.1736, 0.5854], 1.0000),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 3.2972, 0.0000], 1.0000),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 1.8634, 0.5757], 1.0000),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 3.2592, 0.3350], 1.0000),
+            ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+            ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+            ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+            ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+            ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+            ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+            ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+            ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+            ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+            ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082),
+        ];
+        for (i, (a, b, expected)) in dataset.iter().enumerate() {
+            let found = delta_e_2000(lab(a[0], a[1], a[2]), lab(b[0], b[1], b[2]));
+            assert!(
+                (expected - found).abs() < 1e-4,
+                "Logic Error: pair {}, expected {}, found {}", i + 1, expected, found,
+            );
+            let found = delta_e_2000(lab(b[0], b[1], b[2]), lab(a[0], a[1], a[2]));
+            assert!((expected - found).abs() < 1e-4, "Logic Error: pair {} swapped", i + 1);
+        }
+    }
+
+    #[test]
+    fn test_delta_e_reference() {
+        let a = lab(0.9, 16.3, -2.22);
+        let b = lab(0.7, 14.2, -1.80);
+        assert!((2.151 - delta_e_76(a, b)).abs() < 1e-3, "Logic Error: CIE76");
+        assert!((1.249 - delta_e_94(a, b)).abs() < 1e-3, "Logic Error: CIE94");
+        assert!((1.523 - delta_e_2000(a, b)).abs() < 1e-3, "Logic Error: CIEDE2000");
+    }
+
+    #[test]
+    fn test_color_distance() {
+        let (black, white) = (Rgb([0, 0, 0]), Rgb([255, 255, 255]));
+        let expected = 255.0 * 3f64.sqrt();
+        let found = color_distance(black, white, DistanceMetric::Rgb);
+        assert!((expected - found).abs() < 1e-9, "Logic Error: RGB");
+        let expected = 255.0 * (8.0f64 + 255.0 / 256.0).sqrt();
+        let found = color_distance(black, white, DistanceMetric::Redmean);
+        assert!((expected - found).abs() < 1e-9, "Logic Error: redmean");
+        let found = color_distance(black, white, DistanceMetric::Cie76);
+        assert!((100.0 - found).abs() < 1e-3, "Logic Error: CIE76");
+        let found = color_distance(black, white, DistanceMetric::Oklab);
+        assert!((1.0 - found).abs() < 1e-3, "Logic Error: Oklab");
+        for metric in [DistanceMetric::Cie94, DistanceMetric::Ciede2000] {
+            assert_eq!(0.0, color_distance(white, white, metric), "Logic Error: {:?}", metric);
+        }
+    }
+
+    #[test]
+    fn test_find_nearest() {
+        let found = palette(&[([255, 0, 0], 2), ([0, 0, 255], 1)]);
+        let nearest = find_nearest(&found, Rgb([200, 30, 40]), DistanceMetric::Ciede2000);
+        assert_eq!(Some(0), nearest, "Logic Error:");
+        let nearest = find_nearest(&palette(&[]), Rgb([0, 0, 0]), DistanceMetric::Rgb);
+        assert_eq!(None, nearest, "Logic Error: empty");
+    }
+
+    #[test]
+    fn test_merge_similar() {
+        let found = palette(&[([255, 0, 0], 2), ([0, 0, 255], 2), ([250, 2, 3], 1)]);
+        let found = merge_similar(&found, 2.0, DistanceMetric::Ciede2000);
+        let expected = palette(&[([255, 0, 0], 3), ([0, 0, 255], 2)]);
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_compare_palettes() {
+        let a = palette(&[([255, 0, 0], 2), ([0, 0, 255], 1)]);
+        let b = palette(&[([0, 0, 255], 5), ([255, 0, 0], 1)]);
+        assert_eq!(0.0, compare_palettes(&a, &b, DistanceMetric::Cie76), "Logic Error: same colors");
+        let c = palette(&[([255, 0, 0], 1)]);
+        let found = compare_palettes(&a, &c, DistanceMetric::Cie76);
+        assert!(found > 0.0 && found.is_finite(), "Logic Error: different colors");
+        let found = compare_palettes(&a, &palette(&[]), DistanceMetric::Cie76);
+        assert_eq!(f64::INFINITY, found, "Logic Error: empty");
+    }
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+use std::io;
+use image::ImageError;
+use image::error::LimitErrorKind;
+
+
+/// Everything that can go wrong between reading an image and producing its
+/// palette
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or stream failed
+    Io(io::Error),
+    /// The data is not an image in a supported format or is corrupt
+    Decode(ImageError),
+    /// The image is wider or taller than the decode limits allow, nothing
+    /// beyond the header was decoded
+    DimensionsTooLarge { width: u32, height: u32 },
+    /// Decoding would allocate more memory than the decode limits allow
+    AllocationTooLarge,
+    /// A raw pixel buffer does not match its description
+    InvalidBuffer(String),
+    /// A video stream is malformed or uses an unsupported format
+    InvalidStream(String),
+    /// A saved histogram is corrupt or from an unsupported version
+    InvalidHistogram(String),
+    /// The input cannot be used for the requested operation, such as
+    /// merging a video
+    Unsupported(String),
+    /// More pixels survived filtering than a `u32` population can count
+    TooManyPixels,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Decode(err) => write!(f, "Could not decode image: {}", err),
+            Error::DimensionsTooLarge { width, height } => {
+                write!(f, "Image of {}x{} exceeds the maximum dimensions", width, height)
+            },
+            Error::AllocationTooLarge => write!(f, "Image exceeds the maximum allocation"),
+            Error::InvalidBuffer(reason) => write!(f, "Invalid pixel buffer: {}", reason),
+            Error::InvalidStream(reason) => write!(f, "Invalid video stream: {}", reason),
+            Error::InvalidHistogram(reason) => write!(f, "Invalid histogram: {}", reason),
+            Error::Unsupported(reason) => write!(f, "{}", reason),
+            Error::TooManyPixels => write!(f, "Image was too large!"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::IoError(err) => Error::Io(err),
+            ImageError::Limits(ref limit_err) => match limit_err.kind() {
+                LimitErrorKind::InsufficientMemory => Error::AllocationTooLarge,
+                _ => Error::Decode(err),
+            },
+            err => Error::Decode(err),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::fmt::Write;
+use std::str::FromStr;
+use crate::data_models::{ ColorPalette, Swatch };
+
+
+/// Machine-readable and human-readable palette output formats
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+    Ndjson,
+    Css,
+    Scss,
+    Tailwind,
+    Base16,
+    Alacritty,
+    Xresources,
+    Iterm,
+}
+
+impl OutputFormat {
+    /// Terminal color schemes are built from an extended palette
+    pub fn is_theme(&self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Base16 | OutputFormat::Alacritty
+                | OutputFormat::Xresources | OutputFormat::Iterm
+        )
+    }
+
+    /// Formats declaring the same names for every palette, so one output
+    /// can only hold one
+    pub fn is_single_record(&self) -> bool {
+        self.is_theme() || matches!(self, OutputFormat::Css | OutputFormat::Scss | OutputFormat::Tailwind)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "css" => Ok(OutputFormat::Css),
+            "scss" => Ok(OutputFormat::Scss),
+            "tailwind" => Ok(OutputFormat::Tailwind),
+            "base16" => Ok(OutputFormat::Base16),
+            "alacritty" => Ok(OutputFormat::Alacritty),
+            "xresources" => Ok(OutputFormat::Xresources),
+            "iterm" => Ok(OutputFormat::Iterm),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+/// A palette together with the image it was calculated from
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct PaletteRecord {
+    pub source: String,
+    pub palette: ColorPalette,
+}
+
+pub const CSV_HEADER: &str = "source,pixel_count,rank,hex,r,g,b,population";
+
+/// Default name shared by every web-oriented emitter
+pub const DEFAULT_PREFIX: &str = "palette";
+
+/// Whether a prefix can be used verbatim as a CSS custom property, SCSS
+/// variable and Tailwind color name
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Formats records as a single JSON array, one record per line
+pub fn format_json(records: &[PaletteRecord]) -> String {
+    let records: Vec<String> = records.iter()
+        .map(|record| format!("  {}", format_json_record(record)))
+        .collect();
+    if records.is_empty() {
+        return String::from("[]");
+    }
+    format!("[\n{}\n]", records.join(",\n"))
+}
+
+/// Formats a record as one line of newline-delimited JSON
+pub fn format_ndjson(record: &PaletteRecord) -> String {
+    format_json_record(record)
+}
+
+fn format_json_record(record: &PaletteRecord) -> String {
+    let swatches: Vec<String> = record.palette.swatches.iter().map(format_json_swatch).collect();
+    format!(
+        "{{\"source\":{},\"pixel_count\":{},\"palette\":[{}]}}",
+        escape_json(&record.source),
+        record.palette.pixel_count,
+        swatches.join(","),
+    )
+}
+
+/// Formats a swatch as a JSON object with its hex code, rgb channels and
+/// population
+pub fn format_json_swatch(swatch: &Swatch) -> String {
+    let [r, g, b] = swatch.color.0;
+    format!(
+        "{{\"hex\":\"{}\",\"rgb\":[{},{},{}],\"population\":{}}}",
+        swatch.hex(), r, g, b, swatch.population,
+    )
+}
+
+/// Formats a record as CSV rows, one row per swatch, without the header
+pub fn format_csv(record: &PaletteRecord) -> String {
+    let source = escape_csv(&record.source);
+    let mut rows = String::new();
+    for (rank, swatch) in record.palette.swatches.iter().enumerate() {
+        let [r, g, b] = swatch.color.0;
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            rows,
+            "{},{},{},{},{},{},{},{}",
+            source, record.palette.pixel_count, rank + 1,
+            swatch.hex(), r, g, b, swatch.population,
+        );
+    }
+    rows
+}
+
+/// Share of the filtered pixels held by a swatch, in percent
+pub fn calc_percentage(population: u32, pixel_count: u32) -> f64 {
+    if pixel_count == 0 {
+        return 0.0;
+    }
+    population as f64 * 100.0 / pixel_count as f64
+}
+
+/// Formats a record for reading in a terminal without color
+pub fn format_text(record: &PaletteRecord) -> String {
+    let mut text = format!(
+        "{} ({} pixels)\n", record.source, record.palette.pixel_count
+    );
+    for swatch in &record.palette.swatches {
+        let [r, g, b] = swatch.color.0;
+        let _ = writeln!(
+            text,
+            "  {}  rgb({:>3}, {:>3}, {:>3})  {:>5.1}%  {}",
+            swatch.hex(), r, g, b,
+            calc_percentage(swatch.population, record.palette.pixel_count),
+            swatch.population,
+        );
+    }
+    text
+}
+
+/// Formats a record as CSS custom properties on `:root`, named
+/// `--<prefix>-<rank>` with rank 1 being the most populated color
+pub fn format_css(record: &PaletteRecord, prefix: &str) -> String {
+    let mut css = format!("/* {} */\n:root {{\n", record.source.replace("*/", "* /"));
+    for (rank, swatch) in record.palette.swatches.iter().enumerate() {
+        let _ = writeln!(css, "  --{}-{}: {};", prefix, rank + 1, swatch.hex());
+    }
+    css.push_str("}\n");
+    css
+}
+
+/// Formats a record as SCSS variables named `$<prefix>-<rank>` followed by
+/// a `$<prefix>` map of the same colors keyed by rank
+pub fn format_scss(record: &PaletteRecord, prefix: &str) -> String {
+    let mut scss = format!("// {}\n", record.source.replace('\n', " "));
+    for (rank, swatch) in record.palette.swatches.iter().enumerate() {
+        let _ = writeln!(scss, "${}-{}: {};", prefix, rank + 1, swatch.hex());
+    }
+    let _ = writeln!(scss, "${}: (", prefix);
+    for rank in 1..=record.palette.swatches.len() {
+        let _ = writeln!(scss, "  {}: ${}-{},", rank, prefix, rank);
+    }
+    scss.push_str(");\n");
+    scss
+}
+
+/// Formats a record as a Tailwind config extending the theme colors,
+/// usable as `bg-<prefix>-<rank>`
+pub fn format_tailwind(record: &PaletteRecord, prefix: &str) -> String {
+    let mut config = format!(
+        "// {}\nmodule.exports = {{\n  theme: {{\n    extend: {{\n      colors: {{\n        '{}': {{\n",
+        record.source.replace('\n', " "), prefix,
+    );
+    for (rank, swatch) in record.palette.swatches.iter().enumerate() {
+        let _ = writeln!(config, "          '{}': '{}',", rank + 1, swatch.hex());
+    }
+    config.push_str("        },\n      },\n    },\n  },\n};\n");
+    config
+}
+
+/// Quoted JSON string
+pub fn escape_json(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn escape_csv(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod test_export {
+    use super::*;
+    use crate::data_models::Swatch;
+    use image::Rgb;
+
+    fn sample_record() -> PaletteRecord {
+        PaletteRecord {
+            source: String::from("tests/data/12colors.png"),
+            palette: ColorPalette {
+                swatches: vec![
+                    Swatch { color: Rgb([170, 187, 204]), population: 3 },
+                    Swatch { color: Rgb([1, 2, 3]), population: 1 },
+                ],
+                pixel_count: 4,
+            },
+        }
+    }
+
+    #[test]
+    fn test_format_ndjson() {
+        let found = format_ndjson(&sample_record());
+        let expected = concat!(
+            "{\"source\":\"tests/data/12colors.png\",\"pixel_count\":4,\"palette\":[",
+            "{\"hex\":\"#aabbcc\",\"rgb\":[170,187,204],\"population\":3},",
+            "{\"hex\":\"#010203\",\"rgb\":[1,2,3],\"population\":1}]}",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_format_json() {
+        let record = sample_record();
+        let found = format_json(&[record.clone(), record.clone()]);
+        let line = format_json_record(&record);
+        let expected = format!("[\n  {},\n  {}\n]", line, line);
+        assert_eq!(expected, found, "Logic Error:");
+        assert_eq!("[]", format_json(&[]), "Logic Error: empty array");
+    }
+
+    #[test]
+    fn test_format_csv() {
+        let mut record = sample_record();
+        record.source = String::from("a,\"b\".png");
+        let found = format_csv(&record);
+        let expected = concat!(
+            "\"a,\"\"b\"\".png\",4,1,#aabbcc,170,187,204,3\n",
+            "\"a,\"\"b\"\".png\",4,2,#010203,1,2,3,1\n",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_format_text() {
+        let found = format_text(&sample_record());
+        let expected = concat!(
+            "tests/data/12colors.png (4 pixels)\n",
+            "  #aabbcc  rgb(170, 187, 204)   75.0%  3\n",
+            "  #010203  rgb(  1,   2,   3)   25.0%  1\n",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_format_css() {
+        let found = format_css(&sample_record(), "brand");
+        let expected = concat!(
+            "/* tests/data/12colors.png */\n",
+            ":root {\n",
+            "  --brand-1: #aabbcc;\n",
+            "  --brand-2: #010203;\n",
+            "}\n",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_format_scss() {
+        let found = format_scss(&sample_record(), "brand");
+        let expected = concat!(
+            "// tests/data/12colors.png\n",
+            "$brand-1: #aabbcc;\n",
+            "$brand-2: #010203;\n",
+            "$brand: (\n",
+            "  1: $brand-1,\n",
+            "  2: $brand-2,\n",
+            ");\n",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_format_tailwind() {
+        let found = format_tailwind(&sample_record(), "brand");
+        let expected = concat!(
+            "// tests/data/12colors.png\n",
+            "module.exports = {\n",
+            "  theme: {\n",
+            "    extend: {\n",
+            "      colors: {\n",
+            "        'brand': {\n",
+            "          '1': '#aabbcc',\n",
+            "          '2': '#010203',\n",
+            "        },\n",
+            "      },\n",
+            "    },\n",
+            "  },\n",
+            "};\n",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_is_valid_prefix() {
+        assert!(is_valid_prefix("palette"), "Logic Error: palette");
+        assert!(is_valid_prefix("brand_dark-2"), "Logic Error: brand_dark-2");
+        assert!(!is_valid_prefix(""), "Logic Error: empty");
+        assert!(!is_valid_prefix("2tone"), "Logic Error: leading digit");
+        assert!(!is_valid_prefix("a b"), "Logic Error: whitespace");
+    }
+
+    #[test]
+    fn test_escape_json() {
+        let found = escape_json("C:\\img\t\"1\"\u{1}.png");
+        let expected = "\"C:\\\\img\\t\\\"1\\\"\\u0001.png\"";
+        assert_eq!(expected, found, "Logic Error:");
+    }
+}
@@ -0,0 +1,386 @@
+use std::ffi::{ c_char, CString };
+use std::panic::{ self, AssertUnwindSafe };
+use crate::data_models::{ ColorPalette, ColorSpace, QuantizeOptions };
+use crate::error::Error;
+use crate::img_io::{ self, DecodeLimits };
+use crate::raw::{ self, PixelLayout, RawImage };
+
+
+/// Bumped on every incompatible change of the C API declared in
+/// `include/color_thief.h`. Callers allocate the structs, so any change to
+/// their layout, appended fields included, bumps it. Status codes are only
+/// ever added otherwise. Regenerate the header with
+/// `cbindgen --config cbindgen.toml --output include/color_thief.h src/ffi/mod.rs`.
+pub const CT_ABI_VERSION: u32 = 1;
+
+/// Outcome of a call, `CtError::status` tells the failure apart
+#[repr(C)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum CtStatus {
+    Ok = 0,
+    /// A null pointer or an option out of range
+    InvalidArgument = 1,
+    Io = 2,
+    /// The data is not an image in a supported format or is corrupt
+    Decode = 3,
+    /// The image exceeds the decode limits or the pixel count
+    Limit = 4,
+    /// A raw buffer does not match its dimensions and stride
+    InvalidBuffer = 5,
+    Unsupported = 6,
+    /// A bug in the library, the call had no effect
+    Internal = 7,
+}
+
+/// Quantization settings, start from `ct_options_default`
+#[repr(C)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct CtOptions {
+    /// Number of palette colors, 2 to 256
+    pub color_count: u32,
+    /// Use every n-th pixel, 1 is the highest quality
+    pub quality: u32,
+    /// 0 rgb, 1 lab, 2 oklab
+    pub color_space: u32,
+    /// Nonzero to average rgb colors in linear light
+    pub linear_average: u8,
+}
+
+#[repr(C)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct CtSwatch {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub population: u32,
+}
+
+/// Swatches sorted by descending population, free with `ct_palette_free`
+#[repr(C)]
+#[derive(Debug)]
+pub struct CtPalette {
+    pub swatches: *mut CtSwatch,
+    pub swatch_count: usize,
+    /// Pixels that survived filtering, the sum of every population
+    pub pixel_count: u32,
+}
+
+/// Free with `ct_error_free`
+#[repr(C)]
+#[derive(Debug)]
+pub struct CtError {
+    pub status: CtStatus,
+    /// NUL-terminated UTF-8 description
+    pub message: *mut c_char,
+}
+
+/// Version of the API the library implements, compare it with
+/// `CT_ABI_VERSION` of the header
+#[unsafe(no_mangle)]
+pub extern "C" fn ct_abi_version() -> u32 {
+    CT_ABI_VERSION
+}
+
+/// 10 colors, quality 10, rgb, averaged in sRGB, like the CLI
+#[unsafe(no_mangle)]
+pub extern "C" fn ct_options_default() -> CtOptions {
+    CtOptions { color_count: 10, quality: 10, color_space: 0, linear_average: 0 }
+}
+
+/// Palette of an encoded image in any supported format. `options` may be
+/// null for the defaults. On success `*out_palette` is set, otherwise
+/// `*out_error` is set when `out_error` is not null.
+///
+/// # Safety
+///
+/// `data` points to `len` readable bytes, `options` is null or valid and
+/// `out_palette` and `out_error` are null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ct_palette_from_bytes(
+    data: *const u8,
+    len: usize,
+    options: *const CtOptions,
+    out_palette: *mut *mut CtPalette,
+    out_error: *mut *mut CtError) -> CtStatus {
+    // SAFETY: guaranteed by the caller
+    unsafe {
+        run(out_palette, out_error, || {
+            let (data, (quality, options)) = (bytes_arg(data, len)?, options_arg(options)?);
+            let limits = DecodeLimits::default();
+            let (img, _) = img_io::load_img_with_profile(data, &limits)?;
+            let img = img_io::to_rgba8_within(&img, &limits)?;
+            let (width, height) = img.dimensions();
+            let raw = RawImage::packed(img.as_raw(), width as usize, height as usize, PixelLayout::Rgba)?;
+            Ok(raw::calc_palette(&raw, quality, &options)?)
+        })
+    }
+}
+
+/// Palette of `height` rows of `width` RGBA pixels, `stride` bytes apart,
+/// read from a buffer of `len` bytes. Otherwise like
+/// `ct_palette_from_bytes`.
+///
+/// # Safety
+///
+/// `data` points to `len` readable bytes, `options` is null or valid and
+/// `out_palette` and `out_error` are null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ct_palette_from_rgba(
+    data: *const u8,
+    len: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    options: *const CtOptions,
+    out_palette: *mut *mut CtPalette,
+    out_error: *mut *mut CtError) -> CtStatus {
+    // SAFETY: guaranteed by the caller
+    unsafe {
+        run(out_palette, out_error, || {
+            let (data, (quality, options)) = (bytes_arg(data, len)?, options_arg(options)?);
+            let raw = RawImage::new(data, width as usize, height as usize, stride, PixelLayout::Rgba)?;
+            Ok(raw::calc_palette(&raw, quality, &options)?)
+        })
+    }
+}
+
+/// Accepts null
+///
+/// # Safety
+///
+/// `palette` is null or was returned by this library and not freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ct_palette_free(palette: *mut CtPalette) {
+    if palette.is_null() {
+        return;
+    }
+    // SAFETY: both were leaked from boxes by `into_c_palette`
+    unsafe {
+        let palette = Box::from_raw(palette);
+        let swatches = std::ptr::slice_from_raw_parts_mut(palette.swatches, palette.swatch_count);
+        drop(Box::from_raw(swatches));
+    }
+}
+
+/// Accepts null
+///
+/// # Safety
+///
+/// `error` is null or was returned by this library and not freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ct_error_free(error: *mut CtError) {
+    if error.is_null() {
+        return;
+    }
+    // SAFETY: both were leaked by `into_c_error`
+    unsafe {
+        let error = Box::from_raw(error);
+        drop(CString::from_raw(error.message));
+    }
+}
+
+/// Failure before or during the pipeline
+enum CallError {
+    Argument(&'static str),
+    Pipeline(Error),
+    Panic,
+}
+
+impl From<Error> for CallError {
+    fn from(err: Error) -> Self {
+        CallError::Pipeline(err)
+    }
+}
+
+fn status_of(err: &Error) -> CtStatus {
+    match err {
+        Error::Io(_) => CtStatus::Io,
+        Error::Decode(_) | Error::InvalidStream(_) | Error::InvalidHistogram(_) => CtStatus::Decode,
+        Error::DimensionsTooLarge { .. } | Error::AllocationTooLarge | Error::TooManyPixels => CtStatus::Limit,
+        Error::InvalidBuffer(_) => CtStatus::InvalidBuffer,
+        Error::Unsupported(_) => CtStatus::Unsupported,
+    }
+}
+
+/// Runs the call without letting a panic unwind into C and hands the result
+/// over through the out pointers
+unsafe fn run(
+    out_palette: *mut *mut CtPalette,
+    out_error: *mut *mut CtError,
+    call: impl FnOnce() -> Result<ColorPalette, CallError>) -> CtStatus {
+    let result = match out_palette.is_null() {
+        true => Err(CallError::Argument("out_palette is null")),
+        false => panic::catch_unwind(AssertUnwindSafe(call))
+            .unwrap_or(Err(CallError::Panic)),
+    };
+    let (status, message) = match result {
+        Ok(palette) => {
+            // SAFETY: checked for null above, writable by contract
+            unsafe { *out_palette = into_c_palette(palette) };
+            return CtStatus::Ok;
+        },
+        Err(CallError::Argument(reason)) => (CtStatus::InvalidArgument, String::from(reason)),
+        Err(CallError::Pipeline(err)) => (status_of(&err), err.to_string()),
+        Err(CallError::Panic) => (CtStatus::Internal, String::from("internal error")),
+    };
+    if !out_error.is_null() {
+        // SAFETY: writable by contract
+        unsafe { *out_error = into_c_error(status, &message) };
+    }
+    status
+}
+
+fn into_c_palette(palette: ColorPalette) -> *mut CtPalette {
+    let swatches: Box<[CtSwatch]> = palette.swatches.iter()
+        .map(|swatch| {
+            let [r, g, b] = swatch.color.0;
+            CtSwatch { r, g, b, population: swatch.population }
+        })
+        .collect();
+    let swatch_count = swatches.len();
+    Box::into_raw(Box::new(CtPalette {
+        swatches: Box::into_raw(swatches) as *mut CtSwatch,
+        swatch_count,
+        pixel_count: palette.pixel_count,
+    }))
+}
+
+fn into_c_error(status: CtStatus, message: &str) -> *mut CtError {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    Box::into_raw(Box::new(CtError { status, message: message.into_raw() }))
+}
+
+/// Empty slice for a null pointer without data
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize) -> Result<&'a [u8], CallError> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err(CallError::Argument("data is null")),
+        // SAFETY: `len` readable bytes by contract
+        (false, _) => Ok(unsafe { std::slice::from_raw_parts(data, len) }),
+    }
+}
+
+/// Quality and quantize options, the defaults for null
+unsafe fn options_arg(options: *const CtOptions) -> Result<(usize, QuantizeOptions), CallError> {
+    // SAFETY: null or valid by contract
+    let options = unsafe { options.as_ref() }.copied().unwrap_or_else(|| ct_options_default());
+    if options.quality < 1 {
+        return Err(CallError::Argument("quality should be 1 or higher"));
+    }
+    if !(2..=256).contains(&options.color_count) {
+        return Err(CallError::Argument("color_count should be between 2 and 256"));
+    }
+    let color_space = match options.color_space {
+        0 => ColorSpace::Rgb,
+        1 => ColorSpace::Lab,
+        2 => ColorSpace::Oklab,
+        _ => return Err(CallError::Argument("color_space should be 0, 1 or 2")),
+    };
+    Ok((options.quality as usize, QuantizeOptions {
+        color_count: options.color_count as usize,
+        color_space,
+        linear_average: options.linear_average != 0,
+    }))
+}
+
+
+#[cfg(test)]
+mod test_ffi {
+    use super::*;
+    use std::ffi::CStr;
+    use std::ptr;
+
+    /// Palette or status and message of a call
+    unsafe fn collect(call: impl FnOnce(*mut *mut CtPalette, *mut *mut CtError) -> CtStatus) -> Result<Vec<CtSwatch>, (CtStatus, String)> {
+        let mut palette: *mut CtPalette = ptr::null_mut();
+        let mut error: *mut CtError = ptr::null_mut();
+        let status = call(&mut palette, &mut error);
+        unsafe {
+            if status != CtStatus::Ok {
+                let message = CStr::from_ptr((*error).message).to_string_lossy().into_owned();
+                assert_eq!(status, (*error).status, "Logic Error: error status");
+                ct_error_free(error);
+                return Err((status, message));
+            }
+            let swatches = std::slice::from_raw_parts((*palette).swatches, (*palette).swatch_count).to_vec();
+            let total: u32 = swatches.iter().map(|swatch| swatch.population).sum();
+            assert_eq!(total, (*palette).pixel_count, "Logic Error: pixel count");
+            ct_palette_free(palette);
+            Ok(swatches)
+        }
+    }
+
+    #[test]
+    fn test_palette_from_bytes() {
+        let bytes = std::fs::read("tests/data/12colors.png").unwrap();
+        let options = CtOptions { color_count: 4, quality: 1, ..ct_options_default() };
+        let found = unsafe {
+            collect(|palette, error| ct_palette_from_bytes(bytes.as_ptr(), bytes.len(), &options, palette, error))
+        }.unwrap();
+
+        // Same palette as the library
+        let img = img_io::open_img_rgba("tests/data/12colors.png").unwrap();
+        let (width, height) = img.dimensions();
+        let raw = RawImage::packed(img.as_raw(), width as usize, height as usize, PixelLayout::Rgba).unwrap();
+        let expected: Vec<CtSwatch> = raw::calc_palette(&raw, 1, &QuantizeOptions { color_count: 4, ..Default::default() })
+            .unwrap()
+            .swatches.iter()
+            .map(|swatch| CtSwatch { r: swatch.color[0], g: swatch.color[1], b: swatch.color[2], population: swatch.population })
+            .collect();
+        assert_eq!(expected, found, "Logic Error: palette");
+
+        let found = unsafe {
+            collect(|palette, error| ct_palette_from_bytes(b"not an image".as_ptr(), 12, ptr::null(), palette, error))
+        };
+        assert!(matches!(found, Err((CtStatus::Decode, _))), "Logic Error: decode");
+        let found = unsafe { collect(|palette, error| ct_palette_from_bytes(ptr::null(), 1, ptr::null(), palette, error)) };
+        assert_eq!(Err((CtStatus::InvalidArgument, String::from("data is null"))), found, "Logic Error: null");
+        let status = unsafe { ct_palette_from_bytes(bytes.as_ptr(), bytes.len(), ptr::null(), ptr::null_mut(), ptr::null_mut()) };
+        assert_eq!(CtStatus::InvalidArgument, status, "Logic Error: null out pointer");
+    }
+
+    #[test]
+    fn test_header() {
+        // Regenerate the header when this fails
+        let header = std::fs::read_to_string("include/color_thief.h").unwrap();
+        let version = format!("#define CT_ABI_VERSION {}", CT_ABI_VERSION);
+        assert!(header.contains(&version), "Logic Error: version");
+        for name in ["ct_abi_version(", "ct_options_default(", "ct_palette_from_bytes(", "ct_palette_from_rgba(", "ct_palette_free(", "ct_error_free("] {
+            assert!(header.contains(name), "Logic Error: {}", name);
+        }
+    }
+
+    #[test]
+    fn test_palette_from_rgba() {
+        // Two opaque red pixels and a transparent one per row, padded to 16 bytes
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            data.extend([255, 0, 0, 255, 255, 0, 0, 255, 0, 0, 255, 0, 9, 9, 9, 9]);
+        }
+        let options = CtOptions { color_count: 2, quality: 1, ..ct_options_default() };
+        let found = unsafe {
+            collect(|palette, error| ct_palette_from_rgba(data.as_ptr(), data.len(), 3, 2, 16, &options, palette, error))
+        }.unwrap();
+        assert_eq!(4, found.iter().map(|swatch| swatch.population).sum::<u32>(), "Logic Error: filtered");
+        assert!(found[0].r > 240 && found[0].b < 16, "Logic Error: red");
+
+        let found = unsafe {
+            collect(|palette, error| ct_palette_from_rgba(data.as_ptr(), data.len(), 5, 2, 16, &options, palette, error))
+        };
+        assert!(matches!(found, Err((CtStatus::InvalidBuffer, _))), "Logic Error: short stride");
+        let options = CtOptions { color_count: 1, ..options };
+        let found = unsafe {
+            collect(|palette, error| ct_palette_from_rgba(data.as_ptr(), data.len(), 3, 2, 16, &options, palette, error))
+        };
+        assert!(matches!(found, Err((CtStatus::InvalidArgument, _))), "Logic Error: color count");
+    }
+}
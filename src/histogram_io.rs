@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::io::{ self, Read };
+use image::Rgba;
+use crate::data_models::{ ColorPalette, ColorSpace, FrequencyMap, QuantizeOptions };
+use crate::error::Error;
+use crate::mmcq::{ self, MMCQ };
+use crate::stats;
+
+
+/// Signature starting every histogram file
+pub const MAGIC: &[u8; 4] = b"CTFM";
+/// Bumped on every incompatible change of the layout
+pub const VERSION: u8 = 1;
+/// Magic, version, bit depth, color space, reserved byte, pixel count,
+/// source hash and entry count
+const HEADER_LEN: usize = 4 + 1 + 1 + 1 + 1 + 4 + 8 + 4;
+/// Bin hash and count
+const ENTRY_LEN: usize = 2 + 4;
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Binned frequency map of an image along with everything needed to
+/// quantize it again without the image.
+///
+/// The file layout is little-endian: the `CTFM` magic, the version, the
+/// significant bits per channel, the color space (0 rgb, 1 lab, 2 oklab), a
+/// reserved zero byte, the pixel count as u32, the source hash as u64, the
+/// number of entries as u32, then per entry the bin hash as u16 and its
+/// count as u32, sorted by bin hash.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct SavedHistogram {
+    pub frequency_map: FrequencyMap,
+    pub color_space: ColorSpace,
+    /// Number of pixels binned, the sum of every count
+    pub pixel_count: u32,
+    /// `hash_source` of the encoded image, to tell whether it changed
+    pub source_hash: u64,
+}
+
+impl SavedHistogram {
+    /// Histogram of pixels that are already sampled, filtered and binned in
+    /// `color_space`
+    pub fn new(pixels: &Vec<Rgba<u8>>, color_space: ColorSpace, source_hash: u64) -> Result<SavedHistogram, Error> {
+        if pixels.len() > u32::MAX as usize {
+            return Err(Error::TooManyPixels);
+        }
+        Ok(SavedHistogram {
+            frequency_map: stats::calc_frequency_map(pixels, &MMCQ::hash_pixel),
+            color_space,
+            pixel_count: pixels.len() as u32,
+            source_hash,
+        })
+    }
+
+    /// Palette of any size from the histogram alone. The histogram decides
+    /// the color space, the one in the options is ignored.
+    pub fn quantize(&self, options: &QuantizeOptions) -> ColorPalette {
+        let options = QuantizeOptions { color_space: self.color_space, ..options.clone() };
+        match stats::calc_frequency_minmax_box(&self.frequency_map) {
+            Some(minmax_box) => mmcq::quantize(&self.frequency_map, minmax_box, &options),
+            None => ColorPalette { swatches: Vec::new(), pixel_count: 0 },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entries: Vec<(&u32, &u32)> = self.frequency_map.0.iter().collect();
+        entries.sort();
+        let mut bytes = Vec::with_capacity(HEADER_LEN + entries.len() * ENTRY_LEN);
+        bytes.extend(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(MMCQ::SIGNIFICANT_BITS);
+        bytes.push(color_space_code(self.color_space));
+        bytes.push(0);
+        bytes.extend(self.pixel_count.to_le_bytes());
+        bytes.extend(self.source_hash.to_le_bytes());
+        bytes.extend((entries.len() as u32).to_le_bytes());
+        for (&hash, &count) in entries {
+            bytes.extend((hash as u16).to_le_bytes());
+            bytes.extend(count.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parses a histogram, checking that it is complete and consistent
+    pub fn from_bytes(bytes: &[u8]) -> Result<SavedHistogram, Error> {
+        let invalid = |reason: &str| Error::InvalidHistogram(reason.into());
+        if bytes.len() < HEADER_LEN || !is_histogram(bytes) {
+            return Err(invalid("missing CTFM header"));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if bytes[4] != VERSION {
+            return Err(Error::InvalidHistogram(format!("unsupported version {}", bytes[4])));
+        }
+        if bytes[5] != MMCQ::SIGNIFICANT_BITS {
+            return Err(Error::InvalidHistogram(format!("unsupported bit depth {}", bytes[5])));
+        }
+        let color_space = match bytes[6] {
+            0 => ColorSpace::Rgb,
+            1 => ColorSpace::Lab,
+            2 => ColorSpace::Oklab,
+            code => return Err(Error::InvalidHistogram(format!("unknown color space {}", code))),
+        };
+        let pixel_count = u32_at(8);
+        let source_hash = u64::from_le_bytes(bytes[12..20].try_into().unwrap());
+        let entry_count = u32_at(20) as usize;
+        let entries = &bytes[HEADER_LEN..];
+        if entries.len() as u64 != entry_count as u64 * ENTRY_LEN as u64 {
+            return Err(invalid("entry count does not match the file size"));
+        }
+
+        let mut frequency_map = HashMap::with_capacity(entry_count);
+        let mut total: u64 = 0;
+        let mut last_hash: Option<u32> = None;
+        for entry in entries.chunks_exact(ENTRY_LEN) {
+            let hash = u16::from_le_bytes([entry[0], entry[1]]) as u32;
+            let count = u32::from_le_bytes([entry[2], entry[3], entry[4], entry[5]]);
+            if hash >= 1 << (3 * MMCQ::SIGNIFICANT_BITS) || last_hash.is_some_and(|last| hash <= last) {
+                return Err(invalid("bins are out of range or order"));
+            }
+            last_hash = Some(hash);
+            total += count as u64;
+            frequency_map.insert(hash, count);
+        }
+        if total != pixel_count as u64 {
+            return Err(invalid("counts do not add up to the pixel count"));
+        }
+        Ok(SavedHistogram {
+            frequency_map: FrequencyMap(frequency_map),
+            color_space,
+            pixel_count,
+            source_hash,
+        })
+    }
+}
+
+fn color_space_code(color_space: ColorSpace) -> u8 {
+    match color_space {
+        ColorSpace::Rgb => 0,
+        ColorSpace::Lab => 1,
+        ColorSpace::Oklab => 2,
+    }
+}
+
+/// True when the data starts like a histogram file
+pub fn is_histogram(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+pub fn save_histogram(path: &str, histogram: &SavedHistogram) -> Result<(), Error> {
+    Ok(std::fs::write(path, histogram.to_bytes())?)
+}
+
+pub fn open_histogram(path: &str) -> Result<SavedHistogram, Error> {
+    SavedHistogram::from_bytes(&std::fs::read(path)?)
+}
+
+/// 64-bit FNV-1a hash of the encoded source, fast and stable across
+/// platforms and releases but not collision resistant
+pub fn hash_source(bytes: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, bytes)
+}
+
+/// `hash_source` of everything the reader yields along with its length in
+/// bytes, read in chunks rather than held in memory
+pub fn hash_reader(mut reader: impl Read) -> io::Result<(u64, u64)> {
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut len: u64 = 0;
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok((hash, len)),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hash = fnv1a(hash, &buffer[..read]);
+        len += read as u64;
+    }
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+
+#[cfg(test)]
+mod test_histogram_io {
+    use super::*;
+
+    fn load_binned(color_space: ColorSpace) -> Vec<Rgba<u8>> {
+        let img = crate::img_io::open_img_rgba("tests/data/12colors.png").unwrap();
+        img.pixels().map(|&pixel| MMCQ::bin_pixel_as(pixel, color_space)).collect()
+    }
+
+    #[test]
+    fn test_hash_source() {
+        // Reference values of the FNV-1a specification
+        assert_eq!(0xcbf29ce484222325, hash_source(b""), "Logic Error: empty");
+        assert_eq!(0xaf63dc4c8601ec8c, hash_source(b"a"), "Logic Error: a");
+        assert_eq!(0x85944171f73967e8, hash_source(b"foobar"), "Logic Error: foobar");
+        // Chunked reading gives the same hash
+        let bytes: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let found = hash_reader(bytes.as_slice()).unwrap();
+        assert_eq!((hash_source(&bytes), 200_000), found, "Logic Error: reader");
+    }
+
+    #[test]
+    fn test_saved_histogram() {
+        let pixels = load_binned(ColorSpace::Lab);
+        let expected = SavedHistogram::new(&pixels, ColorSpace::Lab, 42).unwrap();
+        let bytes = expected.to_bytes();
+        assert_eq!(HEADER_LEN + expected.frequency_map.0.len() * ENTRY_LEN, bytes.len(), "Logic Error: size");
+        let found = SavedHistogram::from_bytes(&bytes).unwrap();
+        assert_eq!(expected, found, "Logic Error: round trip");
+        // Sorted entries keep the bytes stable
+        assert_eq!(bytes, found.to_bytes(), "Logic Error: deterministic");
+    }
+
+    #[test]
+    fn test_quantize() {
+        // Every palette size matches quantizing the pixels
+        let pixels = load_binned(ColorSpace::Oklab);
+        let histogram = SavedHistogram::new(&pixels, ColorSpace::Oklab, 0).unwrap();
+        let histogram = SavedHistogram::from_bytes(&histogram.to_bytes()).unwrap();
+        for color_count in [2, 5, 10] {
+            let options = QuantizeOptions { color_count, color_space: ColorSpace::Oklab, ..Default::default() };
+            let expected = mmcq::quantize_pixels(&pixels, &options).unwrap();
+            // The color space comes from the histogram
+            let options = QuantizeOptions { color_space: ColorSpace::Rgb, ..options };
+            assert_eq!(expected, histogram.quantize(&options), "Logic Error: {} colors", color_count);
+        }
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let histogram = SavedHistogram::new(&load_binned(ColorSpace::Rgb), ColorSpace::Rgb, 0).unwrap();
+        let bytes = histogram.to_bytes();
+        let corrupt = |i: usize, val: u8| {
+            let mut bytes = bytes.clone();
+            bytes[i] = val;
+            bytes
+        };
+        let cases: [(&str, Vec<u8>); 7] = [
+            ("magic", corrupt(0, b'X')),
+            ("version", corrupt(4, VERSION + 1)),
+            ("bit depth", corrupt(5, 8)),
+            ("color space", corrupt(6, 3)),
+            ("pixel count", corrupt(8, bytes[8].wrapping_add(1))),
+            ("truncated", bytes[..bytes.len() - 1].to_vec()),
+            ("header", bytes[..HEADER_LEN - 1].to_vec()),
+        ];
+        for (name, bytes) in cases {
+            let found = SavedHistogram::from_bytes(&bytes);
+            assert!(matches!(found, Err(Error::InvalidHistogram(_))), "Logic Error: {}", name);
+        }
+        // Swapping the first two entries breaks the order
+        let mut bytes = bytes.clone();
+        let (first, second) = bytes[HEADER_LEN..HEADER_LEN + 2 * ENTRY_LEN].split_at_mut(ENTRY_LEN);
+        first.swap_with_slice(second);
+        let found = SavedHistogram::from_bytes(&bytes);
+        assert!(matches!(found, Err(Error::InvalidHistogram(_))), "Logic Error: order");
+    }
+}
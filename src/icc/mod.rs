@@ -0,0 +1,478 @@
+use std::str::FromStr;
+use image::{ Rgb, Rgba };
+
+
+/// D50 white of the ICC profile connection space
+const D50_WHITE: [f64; 3] = [0.9642, 1.0, 0.8249];
+/// D65 white in CIE xy chromaticity
+const D65_XY: (f64, f64) = (0.3127, 0.3290);
+/// Bradford cone response matrix used for chromatic adaptation
+const BRADFORD: [[f64; 3]; 3] = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+type Matrix = [[f64; 3]; 3];
+
+/// Transfer function of a single channel, from encoded values to linear
+/// light, both between 0 and 1
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum ToneCurve {
+    /// ICC parametric curve with its seven parameters `g a b c d e f`,
+    /// every simpler parametric type is expressed in this form
+    Parametric([f64; 7]),
+    /// Evenly spaced samples between 0 and 1
+    Table(Vec<f64>),
+}
+
+impl ToneCurve {
+    pub fn gamma(gamma: f64) -> ToneCurve {
+        ToneCurve::Parametric([gamma, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0])
+    }
+
+    pub fn srgb() -> ToneCurve {
+        ToneCurve::Parametric([2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045, 0.0, 0.0])
+    }
+
+    pub fn decode(&self, val: f64) -> f64 {
+        let val = val.clamp(0.0, 1.0);
+        match self {
+            ToneCurve::Parametric([g, a, b, c, d, e, f]) => {
+                if val >= *d {
+                    (a * val + b).max(0.0).powf(*g) + e
+                } else {
+                    c * val + f
+                }
+            },
+            ToneCurve::Table(table) => {
+                let pos = val * (table.len() - 1) as f64;
+                let i = (pos as usize).min(table.len() - 2);
+                let t = pos - i as f64;
+                table[i] + t * (table[i + 1] - table[i])
+            },
+        }
+    }
+
+    /// Inverse of `decode`, linear light outside 0 to 1 is clipped
+    pub fn encode(&self, val: f64) -> f64 {
+        let val = val.clamp(0.0, 1.0);
+        let encoded = match self {
+            ToneCurve::Parametric([g, a, b, c, d, e, f]) => {
+                if val >= self.decode(*d) {
+                    ((val - e).max(0.0).powf(1.0 / g) - b) / a
+                } else if *c != 0.0 {
+                    (val - f) / c
+                } else {
+                    *d
+                }
+            },
+            ToneCurve::Table(table) => {
+                // Tables are monotonic, find the segment holding the value
+                let i = table.partition_point(|&sample| sample < val).clamp(1, table.len() - 1);
+                let (low, high) = (table[i - 1], table[i]);
+                let t = if high > low { (val - low) / (high - low) } else { 0.0 };
+                (i as f64 - 1.0 + t.clamp(0.0, 1.0)) / (table.len() - 1) as f64
+            },
+        };
+        encoded.clamp(0.0, 1.0)
+    }
+}
+
+/// Matrix/TRC RGB profile: one tone curve per channel and the matrix from
+/// linear RGB to CIE XYZ relative to the D50 white of the ICC connection
+/// space
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RgbProfile {
+    pub to_xyz: Matrix,
+    pub curves: [ToneCurve; 3],
+}
+
+impl RgbProfile {
+    /// Builds a D65 profile from the xy chromaticities of its primaries,
+    /// adapted to D50 the way ICC profiles store them
+    pub fn from_primaries(primaries: [(f64, f64); 3], curve: ToneCurve) -> RgbProfile {
+        let to_xyz = mul(&adapt_to_d50(xy_to_xyz(D65_XY)), &calc_rgb_to_xyz(primaries, D65_XY));
+        RgbProfile { to_xyz, curves: [curve.clone(), curve.clone(), curve] }
+    }
+
+    pub fn srgb() -> RgbProfile {
+        RgbProfile::from_primaries([(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)], ToneCurve::srgb())
+    }
+
+    pub fn display_p3() -> RgbProfile {
+        RgbProfile::from_primaries([(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)], ToneCurve::srgb())
+    }
+
+    pub fn adobe_rgb() -> RgbProfile {
+        RgbProfile::from_primaries(
+            [(0.64, 0.33), (0.21, 0.71), (0.15, 0.06)], ToneCurve::gamma(563.0 / 256.0)
+        )
+    }
+}
+
+/// RGB spaces pixels can be converted to before binning
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum WorkingSpace {
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+}
+
+impl WorkingSpace {
+    pub fn profile(&self) -> RgbProfile {
+        match self {
+            WorkingSpace::Srgb => RgbProfile::srgb(),
+            WorkingSpace::DisplayP3 => RgbProfile::display_p3(),
+            WorkingSpace::AdobeRgb => RgbProfile::adobe_rgb(),
+        }
+    }
+}
+
+impl FromStr for WorkingSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "srgb" => Ok(WorkingSpace::Srgb),
+            "display-p3" | "p3" => Ok(WorkingSpace::DisplayP3),
+            "adobe-rgb" | "adobergb" => Ok(WorkingSpace::AdobeRgb),
+            _ => Err(format!("Unknown working space: {}", s)),
+        }
+    }
+}
+
+/// Reads the matrix and tone curves of an embedded ICC profile. Only RGB
+/// matrix/TRC profiles are supported, which covers Display P3, Adobe RGB
+/// and the sRGB profiles cameras and editors embed.
+pub fn parse_icc(data: &[u8]) -> Result<RgbProfile, String> {
+    if data.len() < 132 {
+        return Err("ICC profile is truncated".into());
+    }
+    if &data[16..20] != b"RGB " {
+        return Err("ICC profile is not an RGB profile".into());
+    }
+    let tag_count = read_u32(data, 128)? as usize;
+    let find_tag = |signature: &[u8]| -> Result<&[u8], String> {
+        (0..tag_count)
+            .map(|i| 132 + 12 * i)
+            .find(|&entry| data.get(entry..entry + 4) == Some(signature))
+            .ok_or(format!("ICC profile has no {} tag", String::from_utf8_lossy(signature)))
+            .and_then(|entry| {
+                let offset = read_u32(data, entry + 4)? as usize;
+                let size = read_u32(data, entry + 8)? as usize;
+                data.get(offset..offset + size).ok_or("ICC tag is truncated".into())
+            })
+    };
+
+    let mut to_xyz = [[0.0; 3]; 3];
+    for (column, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().enumerate() {
+        let tag = find_tag(*signature)?;
+        for (row, value) in to_xyz.iter_mut().enumerate() {
+            value[column] = read_s15_fixed16(tag, 8 + 4 * row)?;
+        }
+    }
+    // Transforms invert the matrix
+    if determinant(&to_xyz).abs() < 1e-6 {
+        return Err("ICC profile has a singular colorant matrix".into());
+    }
+    let curves = [
+        parse_curve(find_tag(b"rTRC")?)?,
+        parse_curve(find_tag(b"gTRC")?)?,
+        parse_curve(find_tag(b"bTRC")?)?,
+    ];
+    Ok(RgbProfile { to_xyz, curves })
+}
+
+fn parse_curve(tag: &[u8]) -> Result<ToneCurve, String> {
+    match tag.get(0..4) {
+        Some(b"curv") => {
+            let count = read_u32(tag, 8)? as usize;
+            match count {
+                0 => Ok(ToneCurve::gamma(1.0)),
+                1 => Ok(ToneCurve::gamma(check_gamma(read_u16(tag, 12)? as f64 / 256.0)?)),
+                _ => {
+                    let table = (0..count)
+                        .map(|i| Ok(read_u16(tag, 12 + 2 * i)? as f64 / 65535.0))
+                        .collect::<Result<Vec<f64>, String>>()?;
+                    Ok(ToneCurve::Table(table))
+                },
+            }
+        },
+        Some(b"para") => {
+            let function = read_u16(tag, 8)?;
+            let param_count = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(format!("Unknown ICC parametric curve: {}", function)),
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().enumerate().take(param_count) {
+                *param = read_s15_fixed16(tag, 12 + 4 * i)?;
+            }
+            let [g, a, b, c, d, e, f] = params;
+            check_gamma(g)?;
+            if matches!(function, 1 | 2) && a == 0.0 {
+                return Err("ICC parametric curve divides by a of 0".into());
+            }
+            // Express every type as type 4
+            let params = match function {
+                0 => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                1 => [g, a, b, 0.0, -b / a, 0.0, 0.0],
+                2 => [g, a, b, 0.0, -b / a, c, c],
+                3 => [g, a, b, c, d, 0.0, 0.0],
+                _ => [g, a, b, c, d, e, f],
+            };
+            Ok(ToneCurve::Parametric(params))
+        },
+        _ => Err("Unknown ICC curve type".into()),
+    }
+}
+
+fn check_gamma(gamma: f64) -> Result<f64, String> {
+    match gamma.is_finite() && gamma > 0.0 {
+        true => Ok(gamma),
+        false => Err(format!("ICC curve has a gamma of {}", gamma)),
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or("ICC profile is truncated".into())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or("ICC profile is truncated".into())
+}
+
+fn read_s15_fixed16(data: &[u8], offset: usize) -> Result<f64, String> {
+    Ok(read_u32(data, offset)? as i32 as f64 / 65536.0)
+}
+
+/// Converts colors between two RGB profiles through the D50 connection
+/// space, colors out of the target gamut are clipped
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Transform {
+    source_curves: [ToneCurve; 3],
+    matrix: Matrix,
+    target_curves: [ToneCurve; 3],
+}
+
+impl Transform {
+    pub fn new(source: &RgbProfile, target: &RgbProfile) -> Transform {
+        Transform {
+            source_curves: source.curves.clone(),
+            matrix: mul(&invert(&target.to_xyz), &source.to_xyz),
+            target_curves: target.curves.clone(),
+        }
+    }
+
+    /// Converts encoded channels between 0 and 1
+    pub fn apply(&self, encoded: [f64; 3]) -> [f64; 3] {
+        let linear = [0, 1, 2].map(|i| self.source_curves[i].decode(encoded[i]));
+        let linear = apply_matrix(&self.matrix, linear);
+        [0, 1, 2].map(|i| self.target_curves[i].encode(linear[i]))
+    }
+
+    pub fn apply_rgba16(&self, pixel: Rgba<u16>) -> Rgba<u16> {
+        let encoded = [pixel[0], pixel[1], pixel[2]].map(|val| val as f64 / 65535.0);
+        let [r, g, b] = self.apply(encoded).map(|val| (val * 65535.0).round() as u16);
+        Rgba([r, g, b, pixel[3]])
+    }
+
+    pub fn apply_rgb8(&self, color: Rgb<u8>) -> Rgb<u8> {
+        let encoded = color.0.map(|val| val as f64 / 255.0);
+        Rgb(self.apply(encoded).map(|val| (val * 255.0).round() as u8))
+    }
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// Linear RGB to XYZ matrix scaled so RGB white maps to the white point
+fn calc_rgb_to_xyz(primaries: [(f64, f64); 3], white: (f64, f64)) -> Matrix {
+    let columns = primaries.map(xy_to_xyz);
+    let unscaled = [0, 1, 2].map(|row| [0, 1, 2].map(|column| columns[column][row]));
+    let scale = apply_matrix(&invert(&unscaled), xy_to_xyz(white));
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| unscaled[row][column] * scale[column]))
+}
+
+/// Bradford adaptation from the given white to D50
+fn adapt_to_d50(white: [f64; 3]) -> Matrix {
+    let source = apply_matrix(&BRADFORD, white);
+    let target = apply_matrix(&BRADFORD, D50_WHITE);
+    let scale = [0, 1, 2].map(|i| {
+        let mut row = [0.0; 3];
+        row[i] = target[i] / source[i];
+        row
+    });
+    mul(&invert(&BRADFORD), &mul(&scale, &BRADFORD))
+}
+
+fn apply_matrix(matrix: &Matrix, vector: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0] * vector[0] + row[1] * vector[1] + row[2] * vector[2])
+}
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| (0..3).map(|k| a[row][k] * b[k][column]).sum()))
+}
+
+fn cofactor(m: &Matrix, row: usize, column: usize) -> f64 {
+    let (r1, r2) = ((row + 1) % 3, (row + 2) % 3);
+    let (c1, c2) = ((column + 1) % 3, (column + 2) % 3);
+    m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+}
+
+fn determinant(m: &Matrix) -> f64 {
+    (0..3).map(|column| m[0][column] * cofactor(m, 0, column)).sum()
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let determinant = determinant(m);
+    // The inverse is the transposed cofactor matrix over the determinant
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| cofactor(m, column, row) / determinant))
+}
+
+
+#[cfg(test)]
+mod test_icc {
+    use super::*;
+    use crate::test_util::assert_close;
+
+    /// Serializes a profile with the tags `parse_icc` reads, the curves as
+    /// given ICC types
+    fn write_icc(to_xyz: &Matrix, curve: &[u8]) -> Vec<u8> {
+        let fixed = |val: f64| ((val * 65536.0).round() as i32).to_be_bytes();
+        let mut tags: Vec<(&[u8; 4], Vec<u8>)> = Vec::new();
+        for (column, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].into_iter().enumerate() {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for row in to_xyz {
+                tag.extend(fixed(row[column]));
+            }
+            tags.push((signature, tag));
+        }
+        for signature in [b"rTRC", b"gTRC", b"bTRC"] {
+            tags.push((signature, curve.to_vec()));
+        }
+
+        let mut data = vec![0; 128];
+        data[16..20].copy_from_slice(b"RGB ");
+        data.extend((tags.len() as u32).to_be_bytes());
+        let mut offset = 132 + 12 * tags.len();
+        for (signature, tag) in &tags {
+            data.extend(*signature);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((tag.len() as u32).to_be_bytes());
+            offset += tag.len();
+        }
+        for (_, tag) in &tags {
+            data.extend(tag);
+        }
+        data
+    }
+
+    #[test]
+    fn test_rgb_profile() {
+        // sRGB matrix of the ICC v4 reference profile
+        let expected = [
+            [0.436066, 0.385147, 0.143066],
+            [0.222488, 0.716873, 0.060608],
+            [0.013916, 0.097076, 0.714096],
+        ];
+        let found = RgbProfile::srgb().to_xyz;
+        for (expected, found) in expected.iter().zip(found) {
+            assert_close(*expected, found, 2e-4);
+        }
+    }
+
+    #[test]
+    fn test_parse_icc() {
+        let to_xyz = RgbProfile::display_p3().to_xyz;
+        // Parametric type 3 with the sRGB curve
+        let mut curve = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for param in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+            curve.extend(((param * 65536.0_f64).round() as i32).to_be_bytes());
+        }
+        let found = parse_icc(&write_icc(&to_xyz, &curve)).unwrap();
+        for (expected, found) in to_xyz.iter().zip(found.to_xyz) {
+            assert_close(*expected, found, 1e-4);
+        }
+        for val in [0.0, 0.02, 0.5, 1.0] {
+            let expected = ToneCurve::srgb().decode(val);
+            assert!((expected - found.curves[0].decode(val)).abs() < 1e-4, "Logic Error: curve");
+        }
+
+        // Single gamma of 2.2 stored as u8Fixed8
+        let curve = b"curv\0\0\0\0\0\0\0\x01\x02\x33\0\0";
+        let found = parse_icc(&write_icc(&to_xyz, curve)).unwrap();
+        assert_eq!(ToneCurve::gamma(563.0 / 256.0), found.curves[1], "Logic Error: gamma");
+
+        assert!(parse_icc(&[0; 64]).is_err(), "Logic Error: truncated");
+    }
+
+    #[test]
+    fn test_parse_icc_degenerate() {
+        let para = |function: u8, params: &[f64]| {
+            let mut curve = vec![b'p', b'a', b'r', b'a', 0, 0, 0, 0, 0, function, 0, 0];
+            for param in params {
+                curve.extend(((param * 65536.0).round() as i32).to_be_bytes());
+            }
+            curve
+        };
+        let to_xyz = RgbProfile::srgb().to_xyz;
+        let cases: [(&str, Matrix, Vec<u8>); 5] = [
+            ("para 1 with a of 0", to_xyz, para(1, &[2.2, 0.0, 0.1])),
+            ("para 2 with a of 0", to_xyz, para(2, &[2.2, 0.0, 0.1, 0.0])),
+            ("para gamma of 0", to_xyz, para(0, &[0.0])),
+            ("curv gamma of 0", to_xyz, b"curv\0\0\0\0\0\0\0\x01\0\0\0\0".to_vec()),
+            ("singular matrix", [[0.4, 0.4, 0.1], [0.2, 0.2, 0.1], [0.0, 0.0, 0.7]], para(0, &[2.2])),
+        ];
+        for (name, to_xyz, curve) in cases {
+            assert!(parse_icc(&write_icc(&to_xyz, &curve)).is_err(), "Logic Error: {}", name);
+        }
+        assert!(parse_icc(&write_icc(&to_xyz, &para(0, &[2.2]))).is_ok(), "Logic Error: valid");
+    }
+
+    #[test]
+    fn test_tone_curve() {
+        let table = ToneCurve::Table((0..=16).map(|i| (i as f64 / 16.0).powi(2)).collect());
+        for curve in [ToneCurve::srgb(), ToneCurve::gamma(2.2), table] {
+            for val in [0.0, 0.01, 0.3, 0.75, 1.0] {
+                let found = curve.encode(curve.decode(val));
+                assert!((val - found).abs() < 1e-9, "Logic Error: {:?} {}", curve, val);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform() {
+        // CSS Color 4 gives sRGB red as color(display-p3 0.9175 0.2003 0.1386)
+        let to_p3 = Transform::new(&RgbProfile::srgb(), &RgbProfile::display_p3());
+        assert_close([0.9175, 0.2003, 0.1386], to_p3.apply([1.0, 0.0, 0.0]), 1e-3);
+
+        // Display P3 red lies outside sRGB and is clipped
+        let to_srgb = Transform::new(&RgbProfile::display_p3(), &RgbProfile::srgb());
+        assert_eq!(Rgb([255, 0, 0]), to_srgb.apply_rgb8(Rgb([255, 0, 0])), "Logic Error: clip");
+        for color in [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0], [0.5, 0.5, 0.5], [0.8, 0.45, 0.15]] {
+            assert_close(color, to_srgb.apply(to_p3.apply(color)), 1e-9);
+        }
+        let to_adobe = Transform::new(&RgbProfile::srgb(), &RgbProfile::adobe_rgb());
+        // Grays stay neutral, the gamma 2.2 curve encodes them slightly darker
+        assert_eq!(Rgb([127, 127, 127]), to_adobe.apply_rgb8(Rgb([128, 128, 128])), "Logic Error: gray");
+    }
+}
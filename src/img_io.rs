@@ -1,12 +1,334 @@
-use image::{ImageReader, RgbaImage, ImageError};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{ BufReader, Cursor, Read, Seek };
+use image::{ImageReader, ImageDecoder, ImageFormat, DynamicImage, GenericImageView, RgbaImage, Limits};
+use image::{ AnimationDecoder, Frame, Frames };
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use crate::error::Error;
 
-pub fn open_img_rgba(img_dir: &str) -> Result<RgbaImage, ImageError>{
+
+/// Limits checked while decoding, before the pixel buffer is allocated.
+/// `None` disables a limit.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct DecodeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Bytes the decoder may allocate, the decoded image included
+    pub max_alloc: Option<u64>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: None,
+            max_height: None,
+            max_alloc: Some(512 * 1024 * 1024),
+        }
+    }
+}
+
+pub fn open_img_rgba(img_dir: &str) -> Result<RgbaImage, Error>{
     // Open the image directory
-    let img = ImageReader::open(img_dir)?;
-    // Decode data when successfully read
-    let img = img.decode()?;
+    let img = open_img(img_dir)?;
     // Convert data to RGBA8 when successfully decoded
     let img = img.to_rgba8();
 
     Ok(img)
 }
+
+/// Decodes an image keeping its bit depth, so 16-bit and floating-point
+/// sources can be binned without losing precision
+pub fn open_img(img_dir: &str) -> Result<DynamicImage, Error> {
+    Ok(open_img_with_profile(img_dir, &DecodeLimits::default())?.0)
+}
+
+/// Decodes an image like `open_img` along with its embedded ICC profile,
+/// if the format and the file carry one
+pub fn open_img_with_profile(img_dir: &str, limits: &DecodeLimits) -> Result<(DynamicImage, Option<Vec<u8>>), Error> {
+    read_img_with_profile(File::open(img_dir)?, limits)
+}
+
+/// Decodes an image held in memory, such as an upload
+pub fn load_img(bytes: &[u8]) -> Result<DynamicImage, Error> {
+    Ok(load_img_with_profile(bytes, &DecodeLimits::default())?.0)
+}
+
+pub fn load_img_with_profile(bytes: &[u8], limits: &DecodeLimits) -> Result<(DynamicImage, Option<Vec<u8>>), Error> {
+    read_img_with_profile(Cursor::new(bytes), limits)
+}
+
+/// Decodes an image from any seekable reader
+pub fn read_img(reader: impl Read + Seek) -> Result<DynamicImage, Error> {
+    Ok(read_img_with_profile(reader, &DecodeLimits::default())?.0)
+}
+
+/// Every loader ends up here. The format is sniffed from the content, never
+/// from a file extension, and the limits are checked against the header
+/// before any pixel is decoded.
+pub fn read_img_with_profile(reader: impl Read + Seek, limits: &DecodeLimits) -> Result<(DynamicImage, Option<Vec<u8>>), Error> {
+    let mut reader = ImageReader::new(BufReader::new(reader)).with_guessed_format()?;
+    let mut image_limits = Limits::no_limits();
+    image_limits.max_alloc = limits.max_alloc;
+    // Dimensions are checked below rather than by the decoder, to report them
+    reader.limits(image_limits.clone());
+    let mut decoder = reader.into_decoder()?;
+    apply_limits(&mut decoder, image_limits, limits)?;
+
+    let icc_profile = decoder.icc_profile()?;
+    let img = DynamicImage::from_decoder(decoder)?;
+    Ok((img, icc_profile))
+}
+
+/// Checks the dimensions from the header, then hands the allocation limit to
+/// the decoder
+fn apply_limits(decoder: &mut impl ImageDecoder, mut image_limits: Limits, limits: &DecodeLimits) -> Result<(), Error> {
+    let (width, height) = decoder.dimensions();
+    let is_too_wide = limits.max_width.is_some_and(|max_width| width > max_width);
+    let is_too_tall = limits.max_height.is_some_and(|max_height| height > max_height);
+    if is_too_wide || is_too_tall {
+        return Err(Error::DimensionsTooLarge { width, height });
+    }
+    // The decoded image counts towards the allocation limit
+    image_limits.reserve(decoder.total_bytes())?;
+    decoder.set_limits(image_limits)?;
+    Ok(())
+}
+
+/// Content of a file that may be animated
+pub enum Decoded<'a> {
+    /// Frames of an animated GIF, APNG or WebP composited onto the full
+    /// canvas and decoded one at a time, always as 8-bit RGBA
+    Animated(Frames<'a>),
+    /// Any other image, including PNG and WebP without animation, at the
+    /// bit depth it was decoded with
+    Still(DynamicImage),
+}
+
+/// Frames of an animated GIF, APNG or WebP. Still images yield a single
+/// frame without delay, their copy as 8-bit RGBA counted towards the
+/// allocation limit. Embedded ICC profiles are not applied.
+pub fn read_frames<'a>(reader: impl Read + Seek + 'a, limits: &DecodeLimits) -> Result<Frames<'a>, Error> {
+    match read_animation_with_profile(reader, limits)?.0 {
+        Decoded::Animated(frames) => Ok(frames),
+        Decoded::Still(img) => {
+            let rgba = match img {
+                DynamicImage::ImageRgba8(rgba) => rgba,
+                img => to_rgba8_within(&img, limits)?.into_owned(),
+            };
+            Ok(Frames::new(Box::new(std::iter::once(Ok(Frame::new(rgba))))))
+        },
+    }
+}
+
+/// Decodes an animation frame by frame, or a still image whole, along with
+/// the embedded ICC profile. GIF has none.
+pub fn read_animation_with_profile<'a>(reader: impl Read + Seek + 'a, limits: &DecodeLimits) -> Result<(Decoded<'a>, Option<Vec<u8>>), Error> {
+    let reader = ImageReader::new(BufReader::new(reader)).with_guessed_format()?;
+    let mut image_limits = Limits::no_limits();
+    image_limits.max_alloc = limits.max_alloc;
+    let format = reader.format();
+    let reader = reader.into_inner();
+    match format {
+        Some(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(reader)?;
+            apply_limits(&mut decoder, image_limits, limits)?;
+            Ok((Decoded::Animated(decoder.into_frames()), None))
+        },
+        Some(ImageFormat::Png) => {
+            let mut decoder = PngDecoder::new(reader)?;
+            apply_limits(&mut decoder, image_limits, limits)?;
+            let icc_profile = decoder.icc_profile()?;
+            match decoder.is_apng()? {
+                true => Ok((Decoded::Animated(decoder.apng()?.into_frames()), icc_profile)),
+                false => Ok((Decoded::Still(DynamicImage::from_decoder(decoder)?), icc_profile)),
+            }
+        },
+        Some(ImageFormat::WebP) => {
+            let mut decoder = WebPDecoder::new(reader)?;
+            apply_limits(&mut decoder, image_limits, limits)?;
+            let icc_profile = decoder.icc_profile()?;
+            match decoder.has_animation() {
+                true => Ok((Decoded::Animated(decoder.into_frames()), icc_profile)),
+                false => Ok((Decoded::Still(DynamicImage::from_decoder(decoder)?), icc_profile)),
+            }
+        },
+        _ => {
+            let (img, icc_profile) = read_img_with_profile(reader, limits)?;
+            Ok((Decoded::Still(img), icc_profile))
+        },
+    }
+}
+
+/// The image as 8-bit RGBA, borrowed when it already is. A converted copy
+/// counts towards the allocation limit along with the decoded image.
+pub fn to_rgba8_within<'a>(img: &'a DynamicImage, limits: &DecodeLimits) -> Result<Cow<'a, RgbaImage>, Error> {
+    if let DynamicImage::ImageRgba8(rgba) = img {
+        return Ok(Cow::Borrowed(rgba));
+    }
+    let (width, height) = img.dimensions();
+    let total_bytes = (img.as_bytes().len() as u64)
+        .saturating_add(width as u64 * height as u64 * 4);
+    if limits.max_alloc.is_some_and(|max_alloc| total_bytes > max_alloc) {
+        return Err(Error::AllocationTooLarge);
+    }
+    Ok(Cow::Owned(img.to_rgba8()))
+}
+
+pub fn save_img_rgba(img_dir: &str, img: &RgbaImage) -> Result<(), Error> {
+    // Encoder is chosen from the file extension
+    Ok(img.save(img_dir)?)
+}
+
+
+#[cfg(test)]
+mod test_img_io {
+    use super::*;
+    use image::{ GenericImageView, ImageBuffer, Rgba };
+
+    #[test]
+    fn test_open_img() {
+        let path = std::env::temp_dir().join("color-thief-rs-test-open-img.png");
+        let img: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_pixel(
+            2, 2, Rgba([2917, 0, 65535, 65535])
+        );
+        img.save(&path).unwrap();
+        let found = open_img(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(&img), found.as_rgba16(), "Logic Error: 16 bits");
+    }
+
+    #[test]
+    fn test_open_img_with_profile() {
+        use image::ImageEncoder;
+        use image::codecs::png::PngEncoder;
+
+        let path = std::env::temp_dir().join("color-thief-rs-test-open-img-with-profile.png");
+        // The PNG encoder stores the profile without parsing it
+        let icc_profile = b"not parsed by the encoder".to_vec();
+        let mut buffer = Vec::new();
+        let mut encoder = PngEncoder::new(&mut buffer);
+        encoder.set_icc_profile(icc_profile.clone()).unwrap();
+        encoder.write_image(&[255, 0, 0], 1, 1, image::ExtendedColorType::Rgb8).unwrap();
+        std::fs::write(&path, buffer).unwrap();
+
+        let found = open_img_with_profile(path.to_str().unwrap(), &DecodeLimits::default());
+        std::fs::remove_file(&path).unwrap();
+        let (img, found_profile) = found.unwrap();
+        assert_eq!(Some(icc_profile), found_profile, "Logic Error: profile");
+        assert_eq!([255, 0, 0, 255], img.to_rgba8().get_pixel(0, 0).0, "Logic Error: pixels");
+
+        let (_, found_profile) = open_img_with_profile("tests/data/12colors.png", &DecodeLimits::default()).unwrap();
+        assert_eq!(None, found_profile, "Logic Error: no profile");
+    }
+
+    #[test]
+    fn test_load_img() {
+        let bytes = std::fs::read("tests/data/12colors.png").unwrap();
+        let expected = open_img("tests/data/12colors.png").unwrap();
+        assert_eq!(expected, load_img(&bytes).unwrap(), "Logic Error: bytes");
+        assert_eq!(expected, read_img(Cursor::new(&bytes)).unwrap(), "Logic Error: reader");
+
+        // A misleading extension does not matter
+        let path = std::env::temp_dir().join("color-thief-rs-test-load-img.jpg");
+        std::fs::write(&path, &bytes).unwrap();
+        let found = open_img(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(expected, found.unwrap(), "Logic Error: sniffing");
+
+        assert!(load_img(b"not an image").is_err(), "Logic Error: garbage");
+    }
+
+    /// PNG holding only a header, claiming the given size, and no pixels
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let crc32 = |data: &[u8]| -> u32 {
+            let mut crc = u32::MAX;
+            for &byte in data {
+                crc ^= byte as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+                }
+            }
+            !crc
+        };
+        let mut chunk = b"IHDR".to_vec();
+        chunk.extend(width.to_be_bytes());
+        chunk.extend(height.to_be_bytes());
+        // 8-bit RGBA, default compression, filter and interlacing
+        chunk.extend([8, 6, 0, 0, 0]);
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(13_u32.to_be_bytes());
+        data.extend(&chunk);
+        data.extend(crc32(&chunk).to_be_bytes());
+        // The decoder reads up to the first, here empty, data chunk
+        data.extend(0_u32.to_be_bytes());
+        data.extend(b"IDAT");
+        data.extend(crc32(b"IDAT").to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let bytes = std::fs::read("tests/data/12colors.png").unwrap();
+        let (width, height) = load_img(&bytes).unwrap().dimensions();
+        let limits = DecodeLimits { max_width: Some(width - 1), ..Default::default() };
+        let found = load_img_with_profile(&bytes, &limits);
+        assert!(
+            matches!(found, Err(Error::DimensionsTooLarge { width: w, height: h }) if (w, h) == (width, height)),
+            "Logic Error: width",
+        );
+        let limits = DecodeLimits { max_height: Some(height), ..Default::default() };
+        assert!(load_img_with_profile(&bytes, &limits).is_ok(), "Logic Error: height");
+        let limits = DecodeLimits { max_alloc: Some(16), ..Default::default() };
+        let found = load_img_with_profile(&bytes, &limits);
+        assert!(matches!(found, Err(Error::AllocationTooLarge)), "Logic Error: allocation");
+    }
+
+    #[test]
+    fn test_to_rgba8_within() {
+        // 300 bytes decoded plus 400 bytes converted
+        let img = DynamicImage::new_rgb8(10, 10);
+        let limits = DecodeLimits { max_alloc: Some(699), ..Default::default() };
+        let found = to_rgba8_within(&img, &limits);
+        assert!(matches!(found, Err(Error::AllocationTooLarge)), "Logic Error: copy");
+        let limits = DecodeLimits { max_alloc: Some(700), ..Default::default() };
+        assert!(matches!(to_rgba8_within(&img, &limits), Ok(Cow::Owned(_))), "Logic Error: copy");
+        // RGBA images are never copied
+        let img = DynamicImage::new_rgba8(10, 10);
+        let limits = DecodeLimits { max_alloc: Some(0), ..Default::default() };
+        assert!(matches!(to_rgba8_within(&img, &limits), Ok(Cow::Borrowed(_))), "Logic Error: borrow");
+    }
+
+    #[test]
+    fn test_read_animation_with_profile() {
+        use image::ImageEncoder;
+        use image::codecs::png::PngEncoder;
+
+        // Still 16-bit PNG with a profile keeps its depth
+        let mut bytes = Vec::new();
+        let mut encoder = PngEncoder::new(&mut bytes);
+        encoder.set_icc_profile(b"profile".to_vec()).unwrap();
+        let pixels: Vec<u8> = [2917_u16, 0, 65535].iter().flat_map(|val| val.to_ne_bytes()).collect();
+        encoder.write_image(&pixels, 1, 1, image::ExtendedColorType::Rgb16).unwrap();
+        let (decoded, found_profile) = read_animation_with_profile(Cursor::new(&bytes), &DecodeLimits::default()).unwrap();
+        assert_eq!(Some(b"profile".to_vec()), found_profile, "Logic Error: profile");
+        let Decoded::Still(img) = decoded else { panic!("Logic Error: still") };
+        assert_eq!([2917, 0, 65535], img.as_rgb16().unwrap().get_pixel(0, 0).0, "Logic Error: 16 bits");
+
+        // 6 bytes decoded plus 4 bytes for the single 8-bit frame
+        let limits = DecodeLimits { max_alloc: Some(9), ..Default::default() };
+        let found = read_frames(Cursor::new(&bytes), &limits).map(|frames| frames.count());
+        assert!(matches!(found, Err(Error::AllocationTooLarge)), "Logic Error: frame copy");
+    }
+
+    #[test]
+    fn test_decompression_bomb() {
+        // 100k x 100k RGBA would take 40 GB, the default limits reject it
+        // from the header alone
+        let found = load_img(&png_header(100_000, 100_000));
+        assert!(matches!(found, Err(Error::AllocationTooLarge)), "Logic Error: {:?}", found);
+    }
+}
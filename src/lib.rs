@@ -0,0 +1,23 @@
+pub mod data_models;
+pub mod error;
+pub mod color;
+pub mod distance;
+pub mod icc;
+#[cfg(test)]
+mod test_util;
+pub mod img_io;
+pub mod histogram_io;
+pub mod stats;
+pub mod mmcq;
+pub mod upstream;
+pub mod raw;
+pub mod yuv;
+pub mod animation;
+pub mod batch;
+pub mod cache;
+pub mod server;
+pub mod ffi;
+pub mod export;
+pub mod render;
+pub mod terminal;
+pub mod theme;
@@ -1,65 +1,838 @@
-mod data_models;
-mod img_io;
-mod stats;
-mod mmcq;
-use crate::data_models::{ MinMaxBox, BoxQueue, FrequencyMap};
-use crate::mmcq::MMCQ;
+use color_thief_rs::animation::{ self, AnimationPalette, FramePalette };
+use color_thief_rs::batch::{ self, GlobFilter };
+use color_thief_rs::cache::{ CacheKey, PaletteCache };
+use color_thief_rs::data_models::{ ColorPalette, ColorSpace, FrequencyMap, MinMaxBox, QuantizeOptions };
+use color_thief_rs::distance::{ self, DistanceMetric };
+use color_thief_rs::error;
+use color_thief_rs::export::{ self, OutputFormat, PaletteRecord };
+use color_thief_rs::color::ToneMap;
+use color_thief_rs::histogram_io::{ self, SavedHistogram };
+use color_thief_rs::icc::{ self, RgbProfile, Transform, WorkingSpace };
+use color_thief_rs::img_io::{ self, DecodeLimits, Decoded };
+use color_thief_rs::mmcq::{ self, MMCQ };
+use color_thief_rs::render;
+use color_thief_rs::server::{ self, ServerConfig };
+use color_thief_rs::stats;
+use color_thief_rs::terminal::{ self, ColorSupport };
+use color_thief_rs::theme;
+use color_thief_rs::upstream::{ self, Upstream };
+use color_thief_rs::yuv::{ self, Y4mReader, YuvMatrix };
 use std::error::Error;
-use image::Rgba;
+use std::fs::File;
+use std::net::TcpListener;
+use std::path::{ Path, PathBuf };
+use std::io::{ BufRead, BufReader, Cursor, Read, Seek, SeekFrom };
+use std::time::Duration;
+use image::{ DynamicImage, ImageBuffer, Pixel, Rgba };
 
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Load Image Data
-    let img_path = "./tests/data/12colors.png";
-    let img: image::RgbaImage = img_io::open_img_rgba(img_path)?;
-    
-    // Filter pixels
-    let pixels: Vec<Rgba<u8>> = img.pixels()
-        // Filter out half-transparent pixels
-        .filter(|&pixel| {
-            pixel[3] > 125
+/// Total every image is scaled to with --merge
+const MERGE_WEIGHT: u32 = 1 << 16;
+
+const USAGE: &str = "\
+Usage: color-thief-rs [OPTIONS] <IMAGE>...
+       color-thief-rs serve [OPTIONS]
+
+Directories are searched for images recursively and files are processed in
+parallel, failed files are reported without stopping the run. Pass - as an
+image to read it from stdin. Uncompressed YUV4MPEG2 (.y4m)
+video gives one palette per frame, histograms saved with --save-histogram
+are quantized without the image.
+
+Options:
+  -f, --format <FORMAT>   text, json, csv, ndjson, css, scss, tailwind,
+                          base16, alacritty, xresources or iterm
+                          [default: text]
+  -p, --prefix <PREFIX>   Color name prefix for css, scss and tailwind
+                          [default: palette]
+      --color <WHEN>      Colored text output: auto, always or never
+                          [default: auto]
+  -c, --count <COUNT>     Number of palette colors, 2 to 256 [default: 10]
+  -q, --quality <QUALITY> Use every n-th pixel, 1 is the highest [default: 10]
+      --color-space <SPACE>
+                          Quantize in rgb, lab or oklab [default: rgb]
+      --linear            Average rgb colors in linear light
+      --tone-map <MAP>    Tone map for HDR images: clip, reinhard or aces
+                          [default: clip]
+      --working-space <SPACE>
+                          Convert pixels with an embedded ICC profile to
+                          srgb, display-p3 or adobe-rgb [default: srgb]
+      --source-space      Output colors in the embedded ICC profile space
+      --include <GLOB>    Only take files matching the glob from
+                          directories, repeatable [default: every file]
+      --exclude <GLOB>    Skip files matching the glob in directories,
+                          repeatable
+  -j, --jobs <JOBS>       Number of files processed at once [default: number
+                          of CPUs]
+      --merge             One palette for all images, each weighing the same
+      --animation         Palette per frame of animated GIF, APNG and WebP
+                          images, then one weighted by frame duration.
+                          Animated frames are binned at 8 bits
+      --max-width <PX>    Reject wider images before decoding them
+      --max-height <PX>   Reject taller images before decoding them
+      --max-alloc <MIB>   Memory the decoder may allocate [default: 512]
+      --yuv-matrix <MATRIX>
+                          Video color matrix: bt601 or bt709 [default:
+                          bt709 from 720 lines up, bt601 below]
+      --swatch <PATH>     Save the palette as a swatch strip, SVG when the
+                          path ends with .svg
+      --swatch-size <PX>  Side of a single swatch in pixels, 1 to 1024
+                          [default: 50]
+      --sheet <PATH>      Save the image with the palette as a bar underneath
+      --save-histogram <PATH>
+                          Save the binned histogram of the image to
+                          quantize it again later
+      --metric <METRIC>   Color difference for --merge-threshold and
+                          --compare: rgb, redmean, cie76, cie94, ciede2000
+                          or oklab [default: ciede2000]
+      --merge-threshold <DISTANCE>
+                          Fold palette colors closer than the distance into
+                          the more populated one [default: 0, off]
+      --compare           Print the distance between the palettes of two
+                          images instead of the palettes
+      --upstream <IMPL>   Reproduce the palettes of color-thief-py or
+                          quantize-js, quirks included, for still images
+                          in rgb. Deviates for empty boxes past the last
+                          bin, whose channels of 256 are printed as 255
+      --cache <DIR>       Reuse palettes of unchanged files computed with
+                          the same options, not used with --merge,
+                          --swatch, --sheet and --save-histogram
+  -h, --help              Print this message";
+
+const SERVE_USAGE: &str = "\
+Usage: color-thief-rs serve [OPTIONS]
+
+Answers POST /palette with the JSON palette of the image in the request
+body and POST /color with its dominant color. Both take the quality and
+color_space query parameters, /palette also takes count, e.g.
+POST /palette?count=5&quality=1
+
+Options:
+      --addr <ADDR>       Address to listen on [default: 127.0.0.1:8080]
+      --max-body <MIB>    Largest request body accepted [default: 32]
+      --timeout <SECS>    Time allowed to receive a whole request
+                          [default: 60]
+      --max-width <PX>    Reject wider images before decoding them
+      --max-height <PX>   Reject taller images before decoding them
+      --max-alloc <MIB>   Memory the decoder may allocate [default: 512]
+  -j, --jobs <JOBS>       Number of requests answered at once [default:
+                          number of CPUs]
+  -h, --help              Print this message";
+
+struct ServeArgs {
+    addr: String,
+    config: ServerConfig,
+    jobs: usize,
+}
+
+fn parse_serve_args(mut args: impl Iterator<Item = String>) -> Result<ServeArgs, Box<dyn Error>> {
+    let mut parsed = ServeArgs {
+        addr: String::from("127.0.0.1:8080"),
+        config: ServerConfig::default(),
+        jobs: std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or(format!("Missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--addr" => parsed.addr = value(&arg)?,
+            "--max-body" => parsed.config.max_body_len = parse_mebibytes(&value(&arg)?)?.try_into()?,
+            "--timeout" => parsed.config.request_timeout = Duration::from_secs(value(&arg)?.parse()?),
+            "--max-width" => parsed.config.limits.max_width = Some(value(&arg)?.parse()?),
+            "--max-height" => parsed.config.limits.max_height = Some(value(&arg)?.parse()?),
+            "--max-alloc" => parsed.config.limits.max_alloc = Some(parse_mebibytes(&value(&arg)?)?),
+            "-j" | "--jobs" => parsed.jobs = value(&arg)?.parse()?,
+            "-h" | "--help" => {
+                println!("{}", SERVE_USAGE);
+                std::process::exit(0);
+            },
+            _ => return Err(format!("Unknown option: {}\n\n{}", arg, SERVE_USAGE).into()),
+        }
+    }
+    if parsed.jobs < 1 {
+        return Err("Jobs should be 1 or higher".into());
+    }
+    Ok(parsed)
+}
+
+struct Args {
+    format: OutputFormat,
+    color_count: usize,
+    quality: usize,
+    color_space: ColorSpace,
+    linear_average: bool,
+    tone_map: ToneMap,
+    working_space: WorkingSpace,
+    source_space: bool,
+    merge: bool,
+    animation: bool,
+    limits: DecodeLimits,
+    yuv_matrix: Option<YuvMatrix>,
+    prefix: String,
+    color_support: ColorSupport,
+    swatch_path: Option<String>,
+    swatch_size: u32,
+    sheet_path: Option<String>,
+    histogram_path: Option<String>,
+    cache_dir: Option<String>,
+    metric: DistanceMetric,
+    merge_threshold: f64,
+    compare: bool,
+    upstream: Option<Upstream>,
+    filter: GlobFilter,
+    jobs: usize,
+    img_paths: Vec<String>,
+}
+
+/// Bytes in a number of MiB
+fn parse_mebibytes(value: &str) -> Result<u64, Box<dyn Error>> {
+    let mebibytes: u64 = value.parse()?;
+    match mebibytes.checked_mul(1024 * 1024) {
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("{} MiB is too large", mebibytes).into()),
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, Box<dyn Error>> {
+    let mut parsed = Args {
+        format: OutputFormat::Text,
+        color_count: 10,
+        quality: 10,
+        color_space: ColorSpace::Rgb,
+        linear_average: false,
+        tone_map: ToneMap::Clip,
+        working_space: WorkingSpace::Srgb,
+        source_space: false,
+        merge: false,
+        animation: false,
+        limits: DecodeLimits::default(),
+        yuv_matrix: None,
+        prefix: String::from(export::DEFAULT_PREFIX),
+        color_support: terminal::detect_color_support(),
+        swatch_path: None,
+        swatch_size: 50,
+        sheet_path: None,
+        histogram_path: None,
+        cache_dir: None,
+        metric: DistanceMetric::Ciede2000,
+        merge_threshold: 0.0,
+        compare: false,
+        upstream: None,
+        filter: GlobFilter::default(),
+        jobs: std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+        img_paths: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or(format!("Missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-f" | "--format" => parsed.format = value(&arg)?.parse()?,
+            "-c" | "--count" => parsed.color_count = value(&arg)?.parse()?,
+            "-q" | "--quality" => parsed.quality = value(&arg)?.parse()?,
+            "--color-space" => parsed.color_space = value(&arg)?.parse()?,
+            "--linear" => parsed.linear_average = true,
+            "--tone-map" => parsed.tone_map = value(&arg)?.parse()?,
+            "--working-space" => parsed.working_space = value(&arg)?.parse()?,
+            "--source-space" => parsed.source_space = true,
+            "--merge" => parsed.merge = true,
+            "--animation" => parsed.animation = true,
+            "--max-width" => parsed.limits.max_width = Some(value(&arg)?.parse()?),
+            "--max-height" => parsed.limits.max_height = Some(value(&arg)?.parse()?),
+            "--max-alloc" => parsed.limits.max_alloc = Some(parse_mebibytes(&value(&arg)?)?),
+            "--yuv-matrix" => parsed.yuv_matrix = Some(value(&arg)?.parse()?),
+            "-p" | "--prefix" => parsed.prefix = value(&arg)?,
+            "--color" => {
+                parsed.color_support = match value(&arg)?.as_str() {
+                    "auto" => terminal::detect_color_support(),
+                    "always" => terminal::detect_color_depth(),
+                    "never" => ColorSupport::None,
+                    when => return Err(format!("Unknown --color value: {}", when).into()),
+                }
+            },
+            "--swatch" => parsed.swatch_path = Some(value(&arg)?),
+            "--swatch-size" => parsed.swatch_size = value(&arg)?.parse()?,
+            "--sheet" => parsed.sheet_path = Some(value(&arg)?),
+            "--save-histogram" => parsed.histogram_path = Some(value(&arg)?),
+            "--cache" => parsed.cache_dir = Some(value(&arg)?),
+            "--metric" => parsed.metric = value(&arg)?.parse()?,
+            "--merge-threshold" => parsed.merge_threshold = value(&arg)?.parse()?,
+            "--compare" => parsed.compare = true,
+            "--upstream" => parsed.upstream = Some(value(&arg)?.parse()?),
+            "--include" => parsed.filter.include.push(value(&arg)?),
+            "--exclude" => parsed.filter.exclude.push(value(&arg)?),
+            "-j" | "--jobs" => parsed.jobs = value(&arg)?.parse()?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            },
+            "-" => parsed.img_paths.push(arg),
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option: {}\n\n{}", arg, USAGE).into());
+            },
+            _ => parsed.img_paths.push(arg),
+        }
+    }
+
+    // Check validity
+    if !(2..=256).contains(&parsed.color_count) {
+        return Err("Number of colors should be between 2 and 256".into());
+    }
+    if parsed.quality < 1 {
+        return Err("Quality should be 1 or higher".into());
+    }
+    if !export::is_valid_prefix(&parsed.prefix) {
+        return Err(format!("Invalid prefix: {}", parsed.prefix).into());
+    }
+    if !(1..=render::MAX_SWATCH_SIZE).contains(&parsed.swatch_size) {
+        return Err(format!("Swatch size should be between 1 and {}", render::MAX_SWATCH_SIZE).into());
+    }
+    if !parsed.merge_threshold.is_finite() || parsed.merge_threshold < 0.0 {
+        return Err("Merge threshold should be 0 or higher".into());
+    }
+    if parsed.working_space != WorkingSpace::Srgb && parsed.color_space != ColorSpace::Rgb {
+        return Err("lab and oklab binning need the srgb working space".into());
+    }
+    if parsed.img_paths.is_empty() {
+        return Err(USAGE.into());
+    }
+    let is_many = parsed.img_paths.len() > 1 || parsed.img_paths.iter().any(|path| Path::new(path).is_dir());
+    if parsed.format.is_single_record() && ((is_many && !parsed.merge) || parsed.animation) {
+        return Err("This --format holds the palette of a single still image".into());
+    }
+    if parsed.jobs < 1 {
+        return Err("Jobs should be 1 or higher".into());
+    }
+    if parsed.img_paths.iter().filter(|&path| path == "-").count() > 1 {
+        return Err("stdin can only be read once".into());
+    }
+    let is_rendering = parsed.swatch_path.is_some() || parsed.sheet_path.is_some();
+    if parsed.merge && (parsed.animation || parsed.source_space) {
+        return Err("--merge cannot be combined with --animation or --source-space".into());
+    }
+    if is_rendering && (parsed.animation || parsed.merge) {
+        return Err("--swatch and --sheet accept a single image".into());
+    }
+    if parsed.histogram_path.is_some() && (parsed.animation || parsed.merge) {
+        return Err("--save-histogram accepts a single image".into());
+    }
+    if parsed.compare && (parsed.format != OutputFormat::Text || parsed.merge || parsed.animation) {
+        return Err("--compare prints text for two still images".into());
+    }
+    let is_corrected = parsed.color_space != ColorSpace::Rgb || parsed.linear_average
+        || parsed.working_space != WorkingSpace::Srgb || parsed.source_space
+        || parsed.merge_threshold > 0.0;
+    if parsed.upstream.is_some() && (is_corrected || parsed.merge || parsed.animation || parsed.histogram_path.is_some()) {
+        return Err("--upstream only takes the count and quality of a still rgb image".into());
+    }
+    Ok(parsed)
+}
+
+/// An image path or stdin, opened once so pipes can be sniffed before
+/// they are decoded
+enum Input {
+    /// Stdin cannot seek, it is buffered whole
+    Bytes(Vec<u8>),
+    File(BufReader<File>),
+}
+
+impl Input {
+    fn open(path: &Path) -> Result<Input, error::Error> {
+        match path.to_str() {
+            Some("-") => {
+                let mut bytes = Vec::new();
+                std::io::stdin().read_to_end(&mut bytes)?;
+                Ok(Input::Bytes(bytes))
+            },
+            _ => Ok(Input::File(BufReader::new(File::open(path)?))),
+        }
+    }
+
+    /// Start of the content without consuming it
+    fn header(&mut self) -> Result<&[u8], error::Error> {
+        match self {
+            Input::Bytes(bytes) => Ok(bytes),
+            Input::File(file) => Ok(file.fill_buf()?),
+        }
+    }
+
+    /// `histogram_io::hash_source` of the whole content and its length,
+    /// leaving the input at its start
+    fn hash(&mut self) -> Result<(u64, u64), error::Error> {
+        match self {
+            Input::Bytes(bytes) => Ok((histogram_io::hash_source(bytes), bytes.len() as u64)),
+            Input::File(file) => {
+                let hash = histogram_io::hash_reader(&mut *file)?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(hash)
+            },
+        }
+    }
+
+    fn into_bytes(self) -> Result<Vec<u8>, error::Error> {
+        match self {
+            Input::Bytes(bytes) => Ok(bytes),
+            Input::File(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            },
+        }
+    }
+}
+
+/// Samples, filters and bins the pixels at the bit depth of the image,
+/// converting them to the working space first when given a transform.
+/// Pixels are read from the decoded buffer one at a time, never copied.
+fn bin_image(img: &DynamicImage, transform: Option<&Transform>, args: &Args) -> Vec<Rgba<u8>> {
+    // Sample every n-th pixel, bin using MMCQ bit shift or perceptual
+    // coordinates then filter out half-transparent pixels
+    fn bin_pixels<P: Pixel>(
+        img: &ImageBuffer<P, Vec<P::Subpixel>>,
+        quality: usize,
+        bin: impl Fn(Rgba<P::Subpixel>) -> Rgba<u8>) -> Vec<Rgba<u8>> {
+        img.pixels().step_by(quality)
+            .map(|pixel| bin(pixel.to_rgba()))
+            .filter(|pixel| pixel[3] > 125)
+            .collect()
+    }
+    let bin_pixel16 = |pixel: Rgba<u16>| match transform {
+        Some(transform) => MMCQ::bin_pixel16_as(transform.apply_rgba16(pixel), args.color_space),
+        None => MMCQ::bin_pixel16_as(pixel, args.color_space),
+    };
+    let bin_pixel32f = |pixel| MMCQ::bin_pixel32f_as(pixel, args.tone_map, args.color_space);
+    let bin_pixel8 = |pixel: Rgba<u8>| match transform {
+        // Convert at 16 bits to keep the precision of the conversion
+        Some(_) => bin_pixel16(Rgba(pixel.0.map(|val| val as u16 * 257))),
+        None => MMCQ::bin_pixel_as(pixel, args.color_space),
+    };
+    match img {
+        DynamicImage::ImageRgb32F(img) => bin_pixels(img, args.quality, bin_pixel32f),
+        DynamicImage::ImageRgba32F(img) => bin_pixels(img, args.quality, bin_pixel32f),
+        DynamicImage::ImageLuma16(img) => bin_pixels(img, args.quality, bin_pixel16),
+        DynamicImage::ImageLumaA16(img) => bin_pixels(img, args.quality, bin_pixel16),
+        DynamicImage::ImageRgb16(img) => bin_pixels(img, args.quality, bin_pixel16),
+        DynamicImage::ImageRgba16(img) => bin_pixels(img, args.quality, bin_pixel16),
+        DynamicImage::ImageLuma8(img) => bin_pixels(img, args.quality, bin_pixel8),
+        DynamicImage::ImageLumaA8(img) => bin_pixels(img, args.quality, bin_pixel8),
+        DynamicImage::ImageRgb8(img) => bin_pixels(img, args.quality, bin_pixel8),
+        DynamicImage::ImageRgba8(img) => bin_pixels(img, args.quality, bin_pixel8),
+        // Layouts added to the image crate later are converted
+        img => bin_pixels(&img.to_rgba8(), args.quality, bin_pixel8),
+    }
+}
+
+fn quantize_options(args: &Args) -> QuantizeOptions {
+    let color_count = match args.format.is_theme() {
+        true => args.color_count.max(theme::THEME_COLOR_COUNT),
+        false => args.color_count,
+    };
+    QuantizeOptions {
+        color_count,
+        color_space: args.color_space,
+        linear_average: args.linear_average,
+    }
+}
+
+/// Every option that changes the records of an input or whether it decodes
+/// at all, on one line. The crate version stands for the algorithm and the
+/// alpha threshold.
+fn cache_options(args: &Args) -> String {
+    let options = quantize_options(args);
+    format!(
+        "version={} bits={} alpha>125 count={} quality={} color_space={:?} linear={} tone_map={:?} working_space={:?} source_space={} animation={} yuv_matrix={:?} upstream={:?} max_width={:?} max_height={:?} max_alloc={:?}",
+        env!("CARGO_PKG_VERSION"), MMCQ::SIGNIFICANT_BITS, options.color_count, args.quality,
+        options.color_space, options.linear_average, args.tone_map, args.working_space,
+        args.source_space, args.animation, args.yuv_matrix, args.upstream,
+        args.limits.max_width, args.limits.max_height, args.limits.max_alloc,
+    )
+}
+
+/// Profiles to convert the pixels from and to, `None` when they are binned
+/// as decoded. Images without a usable profile are taken as sRGB.
+fn working_profiles(icc_profile: Option<Vec<u8>>, source: &str, args: &Args) -> Option<(RgbProfile, RgbProfile)> {
+    let source_profile = icc_profile.and_then(|data| {
+        icc::parse_icc(&data)
+            .inspect_err(|err| eprintln!("Ignoring ICC profile of {}: {}", source, err))
+            .ok()
+    });
+    if source_profile.is_none() && args.working_space == WorkingSpace::Srgb {
+        return None;
+    }
+    Some((source_profile.unwrap_or_else(RgbProfile::srgb), args.working_space.profile()))
+}
+
+/// Converts the palette from the working space back to the profile of the
+/// image with --source-space
+fn to_source_space(palette: &mut ColorPalette, profiles: &Option<(RgbProfile, RgbProfile)>, args: &Args) {
+    if let (Some((source_profile, working_profile)), true) = (profiles, args.source_space) {
+        let to_source = Transform::new(working_profile, source_profile);
+        for swatch in palette.swatches.iter_mut() {
+            swatch.color = to_source.apply_rgb8(swatch.color);
+        }
+    }
+}
+
+/// One record per frame named after the image and the frame number, then
+/// the duration-weighted record of the whole animation. Still images are
+/// binned like without --animation and give a single frame.
+fn calc_animation_palettes(input: Input, source: &str, args: &Args) -> Result<Vec<PaletteRecord>, error::Error> {
+    let (decoded, icc_profile) = match input {
+        Input::Bytes(bytes) => img_io::read_animation_with_profile(Cursor::new(bytes), &args.limits)?,
+        Input::File(file) => img_io::read_animation_with_profile(file, &args.limits)?,
+    };
+    let profiles = working_profiles(icc_profile, source, args);
+    let transform = profiles.as_ref().map(|(source_profile, working_profile)| Transform::new(source_profile, working_profile));
+    let mut palettes = match decoded {
+        Decoded::Animated(frames) => animation::calc_palettes(frames, args.quality, transform.as_ref(), &quantize_options(args))?,
+        Decoded::Still(img) => {
+            let pixels = bin_image(&img, transform.as_ref(), args);
+            let palette = mmcq::quantize_pixels(&pixels, &quantize_options(args))?;
+            let frame = FramePalette { start_ms: 0, duration_ms: animation::DEFAULT_DELAY_MS, palette: palette.clone() };
+            AnimationPalette { frames: vec![frame], global: palette }
+        },
+    };
+    for palette in palettes.frames.iter_mut().map(|frame| &mut frame.palette).chain([&mut palettes.global]) {
+        to_source_space(palette, &profiles, args);
+    }
+    let mut records: Vec<PaletteRecord> = palettes.frames.into_iter()
+        .enumerate()
+        .map(|(index, frame)| PaletteRecord {
+            source: format!("{}#{}", source, index),
+            palette: frame.palette,
         })
-        // Bin using MMCQ bit shift
-        .map(|&pixel| MMCQ::bin_pixel(pixel))
         .collect();
+    records.push(PaletteRecord {
+        source: source.to_string(),
+        palette: palettes.global,
+    });
+    Ok(records)
+}
 
-    let total_pixels = pixels.len();
-    if total_pixels > u32::MAX as usize {
-        // Conduct a failure mode here
-        panic!("Image was too large!");
+/// One record per frame, named after the stream and the frame number
+fn calc_frame_palettes(input: Input, source: &str, args: &Args) -> Result<Vec<PaletteRecord>, error::Error> {
+    let reader: Box<dyn Read> = match input {
+        Input::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+        Input::File(file) => Box::new(file),
+    };
+    let reader = Y4mReader::with_limits(reader, &args.limits)?;
+    let matrix = args.yuv_matrix.unwrap_or_else(|| YuvMatrix::guess(reader.dimensions().1));
+    let mut records = Vec::new();
+    for (index, palette) in reader.palettes(matrix, args.quality, &quantize_options(args)).enumerate() {
+        records.push(PaletteRecord {
+            source: format!("{}#{}", source, index),
+            palette: palette?,
+        });
     }
-    println!("Total Pixels: {}", total_pixels);
+    Ok(records)
+}
 
-    // Check validity
+/// Palette of the reference implementation, which samples the pixels as
+/// decoded and ignores embedded ICC profiles
+fn calc_upstream_palette(input: Input, source: String, upstream: Upstream, args: &Args) -> Result<PaletteRecord, error::Error> {
+    let (img, _) = match input {
+        Input::Bytes(bytes) => img_io::load_img_with_profile(&bytes, &args.limits)?,
+        Input::File(file) => img_io::read_img_with_profile(file, &args.limits)?,
+    };
+    let pixels = upstream::sample_pixels(img_io::to_rgba8_within(&img, &args.limits)?.pixels(), args.quality);
+    // Channels upstream averages to 256 saturate in the 8-bit palette
+    let palette = upstream::quantize(&pixels, quantize_options(args).color_count, upstream)?.to_color_palette();
+    save_renders(Some(&img), &palette, args)?;
+    Ok(PaletteRecord { source, palette })
+}
 
-    // Calculate Initial MinMaxBox
-    let init_minmax_box: MinMaxBox = stats::calc_minmax_box(&pixels);
-    println!("minmax: {}", init_minmax_box);
-    // Create a Box Queue
-    let init_box_queue: BoxQueue = mmcq::create_box_queue(init_minmax_box);
+/// Palette with similar colors folded together when asked for with
+/// --merge-threshold
+fn merge_similar(palette: &ColorPalette, args: &Args) -> ColorPalette {
+    match args.merge_threshold > 0.0 {
+        true => distance::merge_similar(palette, args.merge_threshold, args.metric),
+        false => palette.clone(),
+    }
+}
 
-    // Calculate Frequency Map
-    let frequency_map: FrequencyMap = stats::calc_frequency_map(
-        &pixels, &MMCQ::hash_pixel
-    );
+/// Swatches only need the palette, the contact sheet needs an image
+fn save_renders(img: Option<&DynamicImage>, palette: &ColorPalette, args: &Args) -> Result<(), error::Error> {
+    let palette = &merge_similar(palette, args);
+    if let Some(swatch_path) = &args.swatch_path {
+        if swatch_path.to_ascii_lowercase().ends_with(".svg") {
+            std::fs::write(swatch_path, render::render_swatch_svg(palette, args.swatch_size)?)?;
+        } else {
+            let strip = render::render_swatch_strip(palette, args.swatch_size)?;
+            img_io::save_img_rgba(swatch_path, &strip)?;
+        }
+    }
+    if let Some(sheet_path) = &args.sheet_path {
+        let img = img.ok_or(error::Error::Unsupported("--sheet needs an image".into()))?;
+        let img = img_io::to_rgba8_within(img, &args.limits)?;
+        let sheet = render::render_contact_sheet(&img, palette, 0)?;
+        img_io::save_img_rgba(sheet_path, &sheet)?;
+    }
+    Ok(())
+}
 
-    // Calculate Histogram per dimension
-    let dim_histograms = stats::calc_dim_histograms(&pixels);
-    println!("Red Histogram: {:?}", dim_histograms.0[0].0);
-    println!("Green Histogram: {:?}", dim_histograms.0[1].0);
-    println!("Blue Histogram: {:?}", dim_histograms.0[2].0);
+fn format_theme(record: &PaletteRecord, format: OutputFormat) -> String {
+    // Name the theme after the image file
+    let name = std::path::Path::new(&record.source)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| record.source.clone());
+    let terminal_theme = theme::generate_theme(&record.palette, &name);
+    match format {
+        OutputFormat::Base16 => theme::format_base16(&terminal_theme),
+        OutputFormat::Alacritty => theme::format_alacritty(&terminal_theme),
+        OutputFormat::Xresources => theme::format_xresources(&terminal_theme),
+        _ => theme::format_iterm(&terminal_theme),
+    }
+}
 
-    // Check validity
+/// Streams every format except the JSON array, which is collected like the
+/// palettes to --compare
+fn print_record(record: PaletteRecord, args: &Args, records: &mut Vec<PaletteRecord>) {
+    let record = PaletteRecord { palette: merge_similar(&record.palette, args), ..record };
+    if args.compare {
+        records.push(record);
+        return;
+    }
+    match args.format {
+        OutputFormat::Text => {
+            println!("{}", terminal::format_preview(&record, args.color_support))
+        },
+        OutputFormat::Csv => print!("{}", export::format_csv(&record)),
+        OutputFormat::Ndjson => println!("{}", export::format_ndjson(&record)),
+        OutputFormat::Css => print!("{}", export::format_css(&record, &args.prefix)),
+        OutputFormat::Scss => print!("{}", export::format_scss(&record, &args.prefix)),
+        OutputFormat::Tailwind => {
+            print!("{}", export::format_tailwind(&record, &args.prefix))
+        },
+        OutputFormat::Json => records.push(record),
+        OutputFormat::Base16 | OutputFormat::Alacritty
+            | OutputFormat::Xresources | OutputFormat::Iterm => {
+            print!("{}", format_theme(&record, args.format))
+        },
+    }
+}
 
+/// What a single input adds to the output
+enum Processed {
+    Records(Vec<PaletteRecord>),
+    /// Normalized statistics for --merge, `None` when no pixel survived
+    /// filtering
+    Merged(Option<(FrequencyMap, MinMaxBox)>),
+}
+
+/// Runs the whole pipeline on one input, on any worker thread. Records
+/// are looked up in and added to the cache when there are no other side
+/// effects to reproduce.
+fn process_input(path: &Path, args: &Args, cache: Option<&PaletteCache>) -> Result<Processed, error::Error> {
+    let source = path.to_string_lossy().into_owned();
+    let mut input = Input::open(path)?;
+    let has_side_effects = args.merge || args.swatch_path.is_some()
+        || args.sheet_path.is_some() || args.histogram_path.is_some();
+    let cache = match cache {
+        Some(cache) if !has_side_effects => {
+            let (content_hash, content_len) = input.hash()?;
+            let key = CacheKey { content_hash, content_len, options: cache_options(args) };
+            if let Some(records) = cache.load(&key, &source) {
+                return Ok(Processed::Records(records));
+            }
+            Some((cache, key))
+        },
+        _ => None,
+    };
+    let processed = run_pipeline(input, &source, args)?;
+    if let (Some((cache, key)), Processed::Records(records)) = (cache, &processed) {
+        // A failed write only costs the next run some time
+        if let Err(err) = cache.store(&key, &source, records) {
+            eprintln!("Not caching {}: {}", source, err);
+        }
+    }
+    Ok(processed)
+}
 
-    // Modified Median Cut Quantization
-    // let box_queue_itersplit: BoxQueue = mmcq::iterative_split(
-    //     frequency_map, init_box_queue
-    // );
-    // println!("After Iterative Split: {:?}", box_queue_itersplit);
+fn run_pipeline(mut input: Input, source: &str, args: &Args) -> Result<Processed, error::Error> {
+    let unsupported = |reason: &str| Err(error::Error::Unsupported(reason.into()));
+    let source = source.to_string();
+    // Video streams and saved histograms are recognised by their signature
+    let header = input.header()?;
+    let (is_video, is_histogram) = (yuv::is_y4m(header), histogram_io::is_histogram(header));
+    if (is_video || is_histogram) && args.upstream.is_some() {
+        return unsupported("--upstream needs a still image");
+    }
+    if is_video {
+        if args.swatch_path.is_some() || args.sheet_path.is_some() {
+            return unsupported("--swatch and --sheet do not accept video");
+        }
+        if args.merge || args.histogram_path.is_some() {
+            return unsupported("--merge and --save-histogram do not accept video");
+        }
+        return Ok(Processed::Records(calc_frame_palettes(input, &source, args)?));
+    }
+    if is_histogram {
+        if args.histogram_path.is_some() {
+            return unsupported("--save-histogram needs an image");
+        }
+        let mut histogram = SavedHistogram::from_bytes(&input.into_bytes()?)?;
+        if args.merge {
+            if histogram.color_space != args.color_space {
+                return unsupported("the histogram was binned in another color space");
+            }
+            let minmax_box = stats::calc_frequency_minmax_box(&histogram.frequency_map);
+            histogram.frequency_map.normalize(MERGE_WEIGHT);
+            return Ok(Processed::Merged(minmax_box.map(|minmax_box| (histogram.frequency_map, minmax_box))));
+        }
+        let palette = histogram.quantize(&quantize_options(args));
+        save_renders(None, &palette, args)?;
+        return Ok(Processed::Records(vec![PaletteRecord { source, palette }]));
+    }
+    if args.animation {
+        return Ok(Processed::Records(calc_animation_palettes(input, &source, args)?));
+    }
+    if let Some(upstream) = args.upstream {
+        return Ok(Processed::Records(vec![calc_upstream_palette(input, source, upstream, args)?]));
+    }
 
-    // Calculate average color per MinMaxBox
+    // Load Image Data, keeping the bytes when their hash is needed
+    let (img, icc_profile, source_hash) = match (input, &args.histogram_path) {
+        (Input::File(file), None) => {
+            let (img, icc_profile) = img_io::read_img_with_profile(file, &args.limits)?;
+            (img, icc_profile, None)
+        },
+        (input, _) => {
+            let bytes = input.into_bytes()?;
+            let (img, icc_profile) = img_io::load_img_with_profile(&bytes, &args.limits)?;
+            (img, icc_profile, Some(histogram_io::hash_source(&bytes)))
+        },
+    };
+    let profiles = working_profiles(icc_profile, &source, args);
+    let transform = profiles.as_ref().map(|(source_profile, working_profile)| Transform::new(source_profile, working_profile));
+    let pixels = bin_image(&img, transform.as_ref(), args);
+    if args.merge {
+        // Keep only the statistics of every image, never its pixels
+        if pixels.is_empty() {
+            return Ok(Processed::Merged(None));
+        }
+        let mut frequency_map = stats::calc_frequency_map(&pixels, &MMCQ::hash_pixel);
+        frequency_map.normalize(MERGE_WEIGHT);
+        return Ok(Processed::Merged(Some((frequency_map, stats::calc_minmax_box(&pixels)))));
+    }
+    if let (Some(histogram_path), Some(source_hash)) = (&args.histogram_path, source_hash) {
+        let histogram = SavedHistogram::new(&pixels, args.color_space, source_hash)?;
+        histogram_io::save_histogram(histogram_path, &histogram)?;
+    }
+    let mut palette = mmcq::quantize_pixels(&pixels, &quantize_options(args))?;
+    to_source_space(&mut palette, &profiles, args);
+    save_renders(Some(&img), &palette, args)?;
+    Ok(Processed::Records(vec![PaletteRecord { source, palette }]))
+}
 
-    // Find nearest colors
+/// Files and stdin given on the command line plus the files found in
+/// directories, along with the directory entries that could not be read
+fn collect_inputs(args: &Args) -> (Vec<PathBuf>, Vec<(PathBuf, error::Error)>) {
+    let mut inputs = Vec::new();
+    let mut failures = Vec::new();
+    for img_path in &args.img_paths {
+        let path = PathBuf::from(img_path);
+        if path.is_dir() {
+            let (files, walk_failures) = batch::walk_dir(&path, &args.filter);
+            inputs.extend(files);
+            failures.extend(walk_failures);
+        } else {
+            inputs.push(path);
+        }
+    }
+    (inputs, failures)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut cli_args = std::env::args().skip(1).peekable();
+    if cli_args.peek().is_some_and(|arg| arg == "serve") {
+        let serve_args = parse_serve_args(cli_args.skip(1))?;
+        let listener = TcpListener::bind(&serve_args.addr)?;
+        eprintln!("Listening on http://{}", listener.local_addr()?);
+        server::serve(&listener, &serve_args.config, serve_args.jobs);
+        return Ok(());
+    }
+    let args = parse_args(cli_args)?;
+    let cache = args.cache_dir.as_ref().map(PaletteCache::open).transpose()?;
+
+    let (inputs, walk_failures) = collect_inputs(&args);
+    let is_single = args.swatch_path.is_some() || args.sheet_path.is_some() || args.histogram_path.is_some();
+    if is_single && inputs.len() > 1 {
+        return Err("--swatch, --sheet and --save-histogram accept a single image".into());
+    }
+    // Report failed files and carry on with the others
+    let report = |path: &Path, err: &error::Error| eprintln!("{}: {}", path.display(), err);
+    for (path, err) in &walk_failures {
+        report(path, err);
+    }
+    let input_count = inputs.len() + walk_failures.len();
+    let mut failure_count = walk_failures.len();
+
+    let mut records: Vec<PaletteRecord> = Vec::new();
+    if args.format == OutputFormat::Csv {
+        println!("{}", export::CSV_HEADER);
+    }
+    let mut merged: Option<(FrequencyMap, MinMaxBox)> = None;
+    // A single job keeps the palettes to --compare in the order given
+    let jobs = match args.compare {
+        true => 1,
+        false => args.jobs,
+    };
+    batch::run_parallel(
+        inputs,
+        jobs,
+        |path| {
+            let processed = process_input(&path, &args, cache.as_ref());
+            (path, processed)
+        },
+        |(path, processed)| match processed {
+            Ok(Processed::Records(new_records)) => {
+                for record in new_records {
+                    print_record(record, &args, &mut records);
+                }
+            },
+            Ok(Processed::Merged(Some((frequency_map, minmax_box)))) => {
+                merged = match merged.take() {
+                    None => Some((frequency_map, minmax_box)),
+                    Some((mut merged_map, merged_box)) => {
+                        merged_map.add(&frequency_map);
+                        Some((merged_map, merged_box.union(&minmax_box)))
+                    },
+                };
+            },
+            Ok(Processed::Merged(None)) => (),
+            Err(err) => {
+                report(&path, &err);
+                failure_count += 1;
+            },
+        },
+    );
+    if args.merge {
+        let palette = match merged {
+            Some((frequency_map, minmax_box)) => {
+                mmcq::quantize(&frequency_map, minmax_box, &quantize_options(&args))
+            },
+            None => ColorPalette { swatches: Vec::new(), pixel_count: 0 },
+        };
+        let record = PaletteRecord {
+            source: String::from("merged"),
+            palette,
+        };
+        print_record(record, &args, &mut records);
+    }
+    if args.format == OutputFormat::Json {
+        println!("{}", export::format_json(&records));
+    }
+    if args.compare && failure_count == 0 {
+        let [a, b] = records.as_slice() else {
+            return Err(format!("--compare needs 2 palettes, found {}", records.len()).into());
+        };
+        let distance = distance::compare_palettes(&a.palette, &b.palette, args.metric);
+        println!("{} {} {:.4}", a.source, b.source, distance);
+    }
+    if failure_count > 0 {
+        return Err(format!("{} of {} inputs failed", failure_count, input_count).into());
+    }
     Ok(())
 }
@@ -1,44 +1,75 @@
 use std::vec::Vec;
 use std::cmp;
 use crate::data_models::{
-    ColorChannel, MinMaxBox, Histogram,
-    DimHistograms, BoxQueue, FrequencyMap
+    ColorChannel, MinMaxBox, Histogram, BoxQueue, FrequencyMap,
+    ColorPalette, Swatch, ColorSpace, QuantizeOptions
 };
+use crate::color::{ self, Lab, Oklab, ToneMap };
+use crate::error::Error;
 use crate::stats;
-use image::Rgba;
+use image::{ Rgb, Rgba };
 
 
 pub fn create_box_queue(minmax_box: MinMaxBox) -> BoxQueue {
     // Put MinMaxBox in a BoxQueue
-    let mut init_box_queue: Vec<MinMaxBox> = Vec::new();
-    init_box_queue.push(minmax_box);
-    BoxQueue {
-        0: init_box_queue
+    BoxQueue(vec![minmax_box])
+}
+
+/// Runs MMCQ on pixels that are already sampled, filtered and binned with
+/// the color space of the options
+pub fn quantize_pixels(pixels: &Vec<Rgba<u8>>, options: &QuantizeOptions) -> Result<ColorPalette, Error> {
+    // Check validity
+    if pixels.len() > u32::MAX as usize {
+        return Err(Error::TooManyPixels);
     }
+    if pixels.is_empty() {
+        return Ok(ColorPalette { swatches: Vec::new(), pixel_count: 0 });
+    }
+
+    // Calculate Initial MinMaxBox
+    let init_minmax_box: MinMaxBox = stats::calc_minmax_box(pixels);
+
+    // Calculate Frequency Map
+    let frequency_map: FrequencyMap = stats::calc_frequency_map(pixels, &MMCQ::hash_pixel);
+
+    // Modified Median Cut Quantization
+    Ok(quantize(&frequency_map, init_minmax_box, options))
+}
+
+/// Runs both phases of MMCQ and maps every resulting MinMaxBox to its
+/// average color. The first 75% of the boxes are split by pixel count,
+/// the rest by pixel count times volume so large sparse regions of the
+/// color space still receive a color. The frequency map may be merged from
+/// several images as long as the box spans all of them.
+pub fn quantize(frequency_map: &FrequencyMap, minmax_box: MinMaxBox, options: &QuantizeOptions) -> ColorPalette {
+    let box_queue = create_box_queue(minmax_box);
+    let box_queue = two_phase_split(frequency_map, box_queue, options.color_count);
+    calc_average_colors(frequency_map, box_queue, options)
 }
 
-pub fn iterative_split(frequency_map: FrequencyMap, mut box_queue: BoxQueue) -> BoxQueue {
+pub fn iterative_split(
+    frequency_map: &FrequencyMap,
+    mut box_queue: BoxQueue,
+    sort_key: &dyn Fn(u32, u32) -> u64,
+    target_count: usize) -> BoxQueue {
     const MAX_ITERATIONS: u32 = 1000;
-    // While the following conditions are met
-    // - flag: max iterartions met
-    let mut is_below_iter_limit = true;
-    // - flag: result of split has data
-    let mut is_split_valid = true;
-    // - flag: target number colors hasn't been reached yet
-    let mut is_target_colors_count = true;
     let mut iter = 0;
-    while is_below_iter_limit && is_split_valid && is_target_colors_count {
+    // Split until target number colors is reached or max iterations met
+    while box_queue.0.len() < target_count && iter < MAX_ITERATIONS {
         iter += 1;
-        if iter >= MAX_ITERATIONS {
-            is_below_iter_limit = false;
+        sort_box_queue(frequency_map, &mut box_queue, sort_key);
+        // Unsplittable boxes are sorted first, if the last box is one of
+        // them then no box can be split any further
+        match box_queue.0.last() {
+            Some(minmax_box) if is_splittable(frequency_map, minmax_box) => (),
+            _ => break,
         }
-        box_queue = split_at_mmcqmedian(&frequency_map, box_queue);
+        box_queue = split_at_mmcqmedian(frequency_map, box_queue);
     }
     box_queue
 }
 
 fn split_at_mmcqmedian(frequency_map: &FrequencyMap, mut box_queue: BoxQueue) -> BoxQueue {
-    // If current box has nothing in it, skip iteration
     // Get highest MinMaxBox from a count-sorted vector
     let minmax_box: MinMaxBox = match box_queue.0.pop() {
         Some(val) => val,
@@ -64,21 +95,25 @@ fn split_at_mmcqmedian(frequency_map: &FrequencyMap, mut box_queue: BoxQueue) ->
     let (cumulative_histo, total) = stats::calc_cumul_histo(frequency_map, &longest_channel, minmax_box.clone());
 
     // Calculate MMCQ Median
-    dbg!(&cumulative_histo);
-    dbg!(&minmax_box);
-    dbg!(&longest_channel);
-    dbg!(&total);
     let median = calc_mmcqmedian(&cumulative_histo, minmax_box.clone(), &longest_channel, total);
+    let min = match longest_channel {
+        ColorChannel::Red => minmax_box.rmin,
+        ColorChannel::Green => minmax_box.gmin,
+        ColorChannel::Blue => minmax_box.bmin,
+    };
+    let lower_count = cumulative_histo.0[(median - min) as usize];
+    let counts = [lower_count, total - lower_count];
 
     // Split the largest MinMaxBox
     let splitted_box: [MinMaxBox; 2] = split_box(
         minmax_box, longest_channel, median
     );
-// Push new MinMaxBoxes back into BoxQueue
-    for mmbox in splitted_box {
-        box_queue.0.push(mmbox)
+    // Push new MinMaxBoxes back into BoxQueue, empty halves are dropped
+    for (mmbox, count) in splitted_box.into_iter().zip(counts) {
+        if count > 0 {
+            box_queue.0.push(mmbox)
+        }
     }
-    // Until max iterations reached
     box_queue
 }
 
@@ -98,7 +133,6 @@ fn calc_mmcqmedian(cumsum_histogram: &Histogram, minmax_box: MinMaxBox, color_ch
     let median_target: u32 = total / 2;
     let cumsum_histogram = &cumsum_histogram.0;
     let mut median: u8 = 0;
-    dbg!(median_target);
     // Find the median based on count (true median)
     for (i, &count) in cumsum_histogram.iter().enumerate() {
         if count > median_target {
@@ -106,34 +140,33 @@ fn calc_mmcqmedian(cumsum_histogram: &Histogram, minmax_box: MinMaxBox, color_ch
             break;
         }
     }
-    dbg!(median);
     median += min;
 
     // Adjust the median to the larger cut
     let lower_range: u8 = median - min;
     let upper_range: u8 = max - median;
-    dbg!(lower_range);
-    dbg!(upper_range);
     // If lower half is larger or equivalent to upper half
     if lower_range <= upper_range {
         // Adjust median (NOTE what if median was at maximum value?)
         // NOTE color-thief-py rounds a float here thus modulo was used
         median = cmp::min(max - 1, median + (upper_range / 2) + upper_range % 2);
     } else {
-        dbg!(median - 1 - lower_range / 2);
-        median = cmp::max(min, median - 1 - (lower_range / 2 + lower_range % 2));
+        // Signed arithmetic, the cut may land below zero before clamping
+        let lower_cut = median as i16 - 1 - (lower_range / 2 + lower_range % 2) as i16;
+        median = cmp::max(min as i16, lower_cut) as u8;
     }
     // Adjust the median to a bin with a count
-    dbg!(cumsum_histogram[median as usize]);
     while cumsum_histogram[(median - min) as usize] == 0 {
         median += 1;
     }
     // If walked median is the total, move back when possible
-    dbg!((total - cumsum_histogram[median as usize] == 0) && cumsum_histogram[(median - 1) as usize] != 0);
-    while (total - cumsum_histogram[median as usize] == 0) && cumsum_histogram[(median - 1) as usize] != 0 {
+    while median > min
+        && total - cumsum_histogram[(median - min) as usize] == 0
+        && cumsum_histogram[(median - min - 1) as usize] != 0 {
         median -= 1;
     }
-    median
+    // The upper box must keep at least one bin
+    cmp::min(median, max - 1)
 }
 
 fn split_box(minmax_box: MinMaxBox, color_channel: ColorChannel, split_val: u8) -> [MinMaxBox; 2]{
@@ -144,7 +177,6 @@ fn split_box(minmax_box: MinMaxBox, color_channel: ColorChannel, split_val: u8)
     let mut bmin = (minmax_box.bmin, minmax_box.bmin);
     let mut bmax = (minmax_box.bmax, minmax_box.bmax);
 
-    dbg!(split_val);
     match color_channel {
         ColorChannel::Red => {
             rmin = (minmax_box.rmin, split_val + 1);
@@ -180,14 +212,72 @@ fn split_box(minmax_box: MinMaxBox, color_channel: ColorChannel, split_val: u8)
     ]
 }
 
-fn two_phase_split(dim_histograms: DimHistograms, minmax_boxes: Vec<MinMaxBox>) {
-    println!("Begin Two-Phase Split");
-    // Get highest MinMaxBox from a volume-count-sorted vector
-    // Get median Split Box
-    // Until max iterations reached
+pub fn two_phase_split(frequency_map: &FrequencyMap, box_queue: BoxQueue, color_count: usize) -> BoxQueue {
+    const FRACT_BY_POPULATIONS: f64 = 0.75;
+    // Split by pixel count until 75% of the target colors
+    let population_target = (color_count as f64 * FRACT_BY_POPULATIONS).ceil() as usize;
+    let box_queue = iterative_split(
+        frequency_map, box_queue, &sort_by_count, population_target
+    );
+    // Split by pixel count times volume for the remaining colors
+    iterative_split(
+        frequency_map, box_queue, &sort_by_count_volume, color_count
+    )
 }
 
-fn sort_box_queue(box_queue: BoxQueue) {
+/// Sorts a BoxQueue in ascending order so that `pop` returns the box with
+/// the highest `sort_key(count, volume)`. Boxes that cannot be split are
+/// always placed first.
+fn sort_box_queue(frequency_map: &FrequencyMap, box_queue: &mut BoxQueue, sort_key: &dyn Fn(u32, u32) -> u64) {
+    box_queue.0.sort_by_cached_key(|minmax_box| {
+        let count = stats::calc_box_count(frequency_map, minmax_box);
+        let volume = stats::calc_box_volume(minmax_box);
+        (count > 1 && volume > 1, sort_key(count, volume))
+    });
+}
+
+fn sort_by_count(count: u32, _volume: u32) -> u64 {
+    count as u64
+}
+
+fn sort_by_count_volume(count: u32, volume: u32) -> u64 {
+    count as u64 * volume as u64
+}
+
+/// A box can only be split when it holds more than one pixel spread over
+/// more than one bin
+fn is_splittable(frequency_map: &FrequencyMap, minmax_box: &MinMaxBox) -> bool {
+    stats::calc_box_volume(minmax_box) > 1
+        && stats::calc_box_count(frequency_map, minmax_box) > 1
+}
+
+/// Maps every MinMaxBox to its average color, most populated color first.
+/// Boxes are averaged in the color space they were binned in, or in linear
+/// light when asked for with RGB binning.
+pub fn calc_average_colors(frequency_map: &FrequencyMap, box_queue: BoxQueue, options: &QuantizeOptions) -> ColorPalette {
+    let calc_color = |minmax_box: &MinMaxBox| -> Rgb<u8> {
+        match (options.color_space, options.linear_average) {
+            (ColorSpace::Rgb, true) => Rgb(
+                stats::calc_linear_average(frequency_map, minmax_box).map(color::linear_to_srgb)
+            ),
+            (color_space, _) => MMCQ::unbin_color(
+                stats::calc_average_bin(frequency_map, minmax_box), color_space
+            ),
+        }
+    };
+    let mut swatches: Vec<Swatch> = box_queue.0.iter()
+        .map(|minmax_box| Swatch {
+            color: calc_color(minmax_box),
+            population: stats::calc_box_count(frequency_map, minmax_box),
+        })
+        .filter(|swatch| swatch.population > 0)
+        .collect();
+    swatches.sort_by_key(|swatch| std::cmp::Reverse(swatch.population));
+    let pixel_count = swatches.iter().map(|swatch| swatch.population).sum();
+    ColorPalette {
+        swatches,
+        pixel_count,
+    }
 }
 
 /// Modified Median Cut Quantization (MMCQ) encapsulates all the
@@ -196,12 +286,18 @@ fn sort_box_queue(box_queue: BoxQueue) {
 /// valued bits and leaves the larger-valued bits (i.e. 00001111 becomes
 /// 00001 taking away the right-most bits) to build its palette with.
 /// Uses 5 significant bits.
+#[allow(clippy::upper_case_acronyms)]
 pub struct MMCQ;
 
 impl MMCQ {
     // Settings for color binning, how many bits to preserve
-    const SIGNIFICANT_BITS: u8 = 5;
-    const BIT_SHIFT: u8 = 8 - Self::SIGNIFICANT_BITS;
+    pub const SIGNIFICANT_BITS: u8 = 5;
+    pub const BIT_SHIFT: u8 = 8 - Self::SIGNIFICANT_BITS;
+    // Number of bins per channel
+    const LEVELS: f64 = (1 << Self::SIGNIFICANT_BITS) as f64;
+    // Channel ranges covering the sRGB gamut in perceptual color spaces
+    const LAB_RANGES: [(f64, f64); 3] = [(0.0, 100.0), (-87.0, 99.0), (-108.0, 95.0)];
+    const OKLAB_RANGES: [(f64, f64); 3] = [(0.0, 1.0), (-0.24, 0.28), (-0.32, 0.2)];
 
     /// Creates an hashed color for each binned color
     /// combination, particularly important when implementing HashMap.
@@ -210,16 +306,26 @@ impl MMCQ {
     ///
     /// # Examples
     /// ```rust
-    /// let result = mmcq::MMCQ::color_hash(15, 12, 10)
+    /// use color_thief_rs::mmcq::MMCQ;
+    /// let result = MMCQ::hash_rgb(15, 12, 10);
     /// assert_eq!(result, 15754);
     /// ```
     pub fn hash_rgb(r: u8, g: u8, b: u8) -> u32 {
-        let r_lshift: u32 = (r as u32) << 2 * Self::SIGNIFICANT_BITS;
+        let r_lshift: u32 = (r as u32) << (2 * Self::SIGNIFICANT_BITS);
         let g_lshift: u32 = (g as u32) << Self::SIGNIFICANT_BITS;
         let b_lshift: u32 = b as u32;
         r_lshift + g_lshift + b_lshift
     }
 
+    /// Binned channels of a hash, the inverse of `hash_rgb`
+    pub fn unhash(hash: u32) -> [u8; 3] {
+        let mask: u32 = (1 << Self::SIGNIFICANT_BITS) - 1;
+        let r = (hash >> (2 * Self::SIGNIFICANT_BITS)) & mask;
+        let g = (hash >> Self::SIGNIFICANT_BITS) & mask;
+        let b = hash & mask;
+        [r as u8, g as u8, b as u8]
+    }
+
     pub fn hash_pixel(pixel: &Rgba<u8>) -> u32 {
         let r = pixel.0[0];
         let g = pixel.0[1];
@@ -234,13 +340,106 @@ impl MMCQ {
         let g_rshift: u8 = pixel[1] >> Self::BIT_SHIFT;
         let b_rshift: u8 = pixel[2] >> Self::BIT_SHIFT;
 
-        Rgba {
-            0: [r_rshift, g_rshift, b_rshift, pixel[3]]
+        Rgba([r_rshift, g_rshift, b_rshift, pixel[3]])
+    }
+
+    /// Bins a pixel like `bin_pixel` but in the coordinates of the given
+    /// color space. Perceptual channels are split into equally wide bins
+    /// over the range the sRGB gamut covers.
+    pub fn bin_pixel_as(pixel: Rgba<u8>, color_space: ColorSpace) -> Rgba<u8> {
+        match color_space {
+            ColorSpace::Rgb => Self::bin_pixel(pixel),
+            _ => {
+                let linear = [pixel[0], pixel[1], pixel[2]].map(color::srgb_to_linear);
+                Self::bin_linear_as(linear, pixel[3], color_space)
+            },
+        }
+    }
+
+    /// Bins a 16-bit pixel like `bin_pixel_as` from the full 16 bits, rgb
+    /// bins by bit shift and perceptual coordinates alike. Palette colors
+    /// are averaged from bin centers, so the extra bits only decide which
+    /// bin a pixel lands in, most visibly in the dark `lab` and `oklab`
+    /// bins.
+    pub fn bin_pixel16_as(pixel: Rgba<u16>, color_space: ColorSpace) -> Rgba<u8> {
+        let alpha = (pixel[3] as f64 / 257.0).round() as u8;
+        match color_space {
+            ColorSpace::Rgb => {
+                let shift = 16 - Self::SIGNIFICANT_BITS;
+                let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|val| (val >> shift) as u8);
+                Rgba([r, g, b, alpha])
+            },
+            _ => {
+                let encoded = [pixel[0], pixel[1], pixel[2]].map(|val| val as f64 / 65535.0);
+                Self::bin_linear_as(encoded.map(color::decode_srgb), alpha, color_space)
+            },
+        }
+    }
+
+    /// Bins a floating-point pixel holding linear light, as decoded from
+    /// HDR and OpenEXR files. Channels are tone mapped into the range 0 to
+    /// 1 first.
+    pub fn bin_pixel32f_as(pixel: Rgba<f32>, tone_map: ToneMap, color_space: ColorSpace) -> Rgba<u8> {
+        let alpha = (pixel[3].clamp(0.0, 1.0) * 255.0).round() as u8;
+        let linear = [pixel[0], pixel[1], pixel[2]]
+            .map(|val| color::tone_map(val as f64, tone_map));
+        Self::bin_linear_as(linear, alpha, color_space)
+    }
+
+    /// Bins linear light sRGB channels between 0 and 1
+    fn bin_linear_as(linear: [f64; 3], alpha: u8, color_space: ColorSpace) -> Rgba<u8> {
+        let (coordinates, ranges) = match color_space {
+            ColorSpace::Rgb => return Self::bin_pixel(Rgba([
+                color::linear_to_srgb(linear[0]),
+                color::linear_to_srgb(linear[1]),
+                color::linear_to_srgb(linear[2]),
+                alpha,
+            ])),
+            ColorSpace::Lab => {
+                let lab = color::xyz_to_lab(color::linear_to_xyz(linear));
+                ([lab.l, lab.a, lab.b], Self::LAB_RANGES)
+            },
+            ColorSpace::Oklab => {
+                let oklab = color::linear_to_oklab(linear);
+                ([oklab.l, oklab.a, oklab.b], Self::OKLAB_RANGES)
+            },
+        };
+        let bin = |i: usize| {
+            let (min, max) = ranges[i];
+            let level = (coordinates[i] - min) / (max - min) * Self::LEVELS;
+            level.clamp(0.0, Self::LEVELS - 1.0) as u8
+        };
+        Rgba([bin(0), bin(1), bin(2), alpha])
+    }
+
+    /// Converts an average bin, in bin units, of the given color space back
+    /// to an sRGB color
+    pub fn unbin_color(bin: [f64; 3], color_space: ColorSpace) -> Rgb<u8> {
+        let unbin = |ranges: [(f64, f64); 3], i: usize| {
+            let (min, max) = ranges[i];
+            min + bin[i] / Self::LEVELS * (max - min)
+        };
+        match color_space {
+            ColorSpace::Rgb => {
+                let bin_size = (1 << Self::BIT_SHIFT) as f64;
+                Rgb(bin.map(|val| (val * bin_size) as u8))
+            },
+            ColorSpace::Lab => color::lab_to_rgb(Lab {
+                l: unbin(Self::LAB_RANGES, 0),
+                a: unbin(Self::LAB_RANGES, 1),
+                b: unbin(Self::LAB_RANGES, 2),
+            }),
+            ColorSpace::Oklab => color::oklab_to_rgb(Oklab {
+                l: unbin(Self::OKLAB_RANGES, 0),
+                a: unbin(Self::OKLAB_RANGES, 1),
+                b: unbin(Self::OKLAB_RANGES, 2),
+            }),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast, clippy::init_numbered_fields)]
 mod test_mmcq {
     use super::*;
 
@@ -352,6 +551,12 @@ mod test_mmcq {
         assert_eq!(expected, found, "Logic Error:");
     }
 
+    #[test]
+    fn test_unhash() {
+        assert_eq!([15, 12, 10], MMCQ::unhash(MMCQ::hash_rgb(15, 12, 10)), "Logic Error:");
+        assert_eq!([31, 0, 31], MMCQ::unhash(MMCQ::hash_rgb(31, 0, 31)), "Logic Error: edges");
+    }
+
     #[test]
     fn test_bin_color() {
         let input = Rgba::from([255 as u8; 4]);
@@ -359,4 +564,188 @@ mod test_mmcq {
         let expected = Rgba::from([31, 31, 31, 255]);
         assert_eq!(expected, found, "Logic Error:");
     }
+
+    #[test]
+    fn test_quantize() {
+        // 3 black, 2 white and 1 red binned pixel
+        let frequency_map: FrequencyMap = FrequencyMap(
+            HashMap::from([
+                (MMCQ::hash_rgb(0, 0, 0), 3),
+                (MMCQ::hash_rgb(31, 31, 31), 2),
+                (MMCQ::hash_rgb(31, 0, 0), 1),
+            ])
+        );
+        let minmax_box = MinMaxBox {
+            rmin: 0,
+            rmax: 31,
+            gmin: 0,
+            gmax: 31,
+            bmin: 0,
+            bmax: 31,
+        };
+        let found = quantize(&frequency_map, minmax_box, &QuantizeOptions::default());
+        let expected = ColorPalette {
+            swatches: vec![
+                Swatch { color: image::Rgb([4, 4, 4]), population: 3 },
+                Swatch { color: image::Rgb([252, 252, 252]), population: 2 },
+                Swatch { color: image::Rgb([252, 4, 4]), population: 1 },
+            ],
+            pixel_count: 6,
+        };
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_quantize_color_count() {
+        let frequency_map: FrequencyMap = FrequencyMap(
+            (0..32).map(|i| (MMCQ::hash_rgb(i, i, i), 1)).collect()
+        );
+        let minmax_box = MinMaxBox {
+            rmin: 0,
+            rmax: 31,
+            gmin: 0,
+            gmax: 31,
+            bmin: 0,
+            bmax: 31,
+        };
+        let options = QuantizeOptions { color_count: 5, ..Default::default() };
+        let found = quantize(&frequency_map, minmax_box, &options);
+        assert_eq!(5, found.swatches.len(), "Logic Error:");
+        assert_eq!(32, found.pixel_count, "Logic Error: pixel count");
+    }
+
+    #[test]
+    fn test_quantize_merged() {
+        // Merging the statistics of two shards equals quantizing all pixels
+        let img = crate::img_io::open_img_rgba("tests/data/12colors.png").unwrap();
+        let pixels: Vec<Rgba<u8>> = img.pixels().map(|&pixel| MMCQ::bin_pixel(pixel)).collect();
+        let options = QuantizeOptions { color_count: 4, ..Default::default() };
+        let expected = quantize_pixels(&pixels, &options).unwrap();
+
+        let (left, right) = pixels.split_at(5);
+        let (left, right) = (left.to_vec(), right.to_vec());
+        let mut frequency_map = stats::calc_frequency_map(&left, &MMCQ::hash_pixel);
+        frequency_map.add(&stats::calc_frequency_map(&right, &MMCQ::hash_pixel));
+        let minmax_box = stats::calc_minmax_box(&left).union(&stats::calc_minmax_box(&right));
+        let found = quantize(&frequency_map, minmax_box, &options);
+        assert_eq!(expected, found, "Logic Error:");
+    }
+
+    #[test]
+    fn test_bin_pixel_as() {
+        let input = Rgba::from([255_u8; 4]);
+        let found = MMCQ::bin_pixel_as(input, ColorSpace::Rgb);
+        assert_eq!(MMCQ::bin_pixel(input), found, "Logic Error: rgb");
+        // White sits at the top of L and near the middle of a and b
+        let found = MMCQ::bin_pixel_as(input, ColorSpace::Lab);
+        assert_eq!(Rgba::from([31, 14, 17, 255]), found, "Logic Error: lab");
+        let found = MMCQ::bin_pixel_as(input, ColorSpace::Oklab);
+        assert_eq!(Rgba::from([31, 14, 19, 255]), found, "Logic Error: oklab");
+    }
+
+    #[test]
+    fn test_quantize_perceptual() {
+        // Two dark colors that share a single sRGB bin
+        let pixels = [
+            Rgba::from([0_u8, 0, 0, 255]),
+            Rgba::from([6_u8, 6, 6, 255]),
+            Rgba::from([255_u8, 255, 255, 255]),
+        ];
+        for (color_space, expected_count) in [(ColorSpace::Rgb, 2), (ColorSpace::Oklab, 3)] {
+            let binned: Vec<Rgba<u8>> = pixels.iter()
+                .map(|&pixel| MMCQ::bin_pixel_as(pixel, color_space))
+                .collect();
+            let frequency_map = stats::calc_frequency_map(&binned, &MMCQ::hash_pixel);
+            let minmax_box = stats::calc_minmax_box(&binned);
+            let options = QuantizeOptions { color_count: 4, color_space, ..Default::default() };
+            let found = quantize(&frequency_map, minmax_box, &options);
+            assert_eq!(expected_count, found.swatches.len(), "Logic Error: {:?}", color_space);
+        }
+
+        // Colors come back close to the original in sRGB
+        let binned = vec![MMCQ::bin_pixel_as(Rgba::from([200_u8, 40, 30, 255]), ColorSpace::Lab)];
+        let frequency_map = stats::calc_frequency_map(&binned, &MMCQ::hash_pixel);
+        let minmax_box = stats::calc_minmax_box(&binned);
+        let options = QuantizeOptions {
+            color_count: 2, color_space: ColorSpace::Lab, ..Default::default()
+        };
+        let found = quantize(&frequency_map, minmax_box, &options).swatches[0].color;
+        for (expected, found) in [200_i32, 40, 30].iter().zip(found.0) {
+            assert!((expected - found as i32).abs() <= 8, "Logic Error: {:?}", found);
+        }
+    }
+
+    #[test]
+    fn test_quantize_linear_average() {
+        let img = crate::img_io::open_img_rgba("tests/data/12colors.png").unwrap();
+        let binned: Vec<Rgba<u8>> = img.pixels()
+            .filter(|&pixel| pixel[3] > 125)
+            .map(|&pixel| MMCQ::bin_pixel(pixel))
+            .collect();
+        let frequency_map = stats::calc_frequency_map(&binned, &MMCQ::hash_pixel);
+        let minmax_box = stats::calc_minmax_box(&binned);
+        // Fewer colors than the image has, so boxes mix colors
+        let options = QuantizeOptions { color_count: 4, ..Default::default() };
+        let plain = quantize(&frequency_map, minmax_box.clone(), &options);
+        let options = QuantizeOptions { linear_average: true, ..options };
+        let linear = quantize(&frequency_map, minmax_box, &options);
+
+        // Same boxes, only the averaging differs
+        assert_eq!(plain.pixel_count, linear.pixel_count, "Logic Error: pixel count");
+        let populations = |palette: &ColorPalette| -> Vec<u32> {
+            palette.swatches.iter().map(|swatch| swatch.population).collect()
+        };
+        assert_eq!(populations(&plain), populations(&linear), "Logic Error: populations");
+
+        // Decoding sRGB is convex, so averaging in linear light never comes
+        // out darker than averaging the sRGB values, and mixed boxes come
+        // out lighter
+        let mut is_lighter = false;
+        for (plain, linear) in plain.swatches.iter().zip(&linear.swatches) {
+            for (a, b) in plain.color.0.iter().zip(linear.color.0) {
+                let (a, b) = (*a as i32, b as i32);
+                // The plain average truncates to the bin, allow that much
+                assert!(b + 4 >= a, "Logic Error: {:?} {:?}", plain.color, linear.color);
+                is_lighter |= b > a + 4;
+            }
+        }
+        assert!(is_lighter, "Logic Error: no mixed box");
+    }
+
+    #[test]
+    fn test_bin_pixel16_as() {
+        // 16-bit values that are exact multiples of 8-bit ones bin the same
+        for color_space in [ColorSpace::Rgb, ColorSpace::Lab, ColorSpace::Oklab] {
+            for pixel in [[0_u8, 0, 0, 255], [255, 255, 255, 255], [200, 40, 30, 126], [7, 8, 9, 0]] {
+                let wide = Rgba(pixel.map(|val| val as u16 * 257));
+                let expected = MMCQ::bin_pixel_as(Rgba(pixel), color_space);
+                let found = MMCQ::bin_pixel16_as(wide, color_space);
+                assert_eq!(expected, found, "Logic Error: {:?} {:?}", color_space, pixel);
+            }
+        }
+        // Gray 2917 sits above a lightness bin edge that its 8-bit rounding,
+        // 11, falls below
+        let found = MMCQ::bin_pixel16_as(Rgba([2917, 2917, 2917, 65535]), ColorSpace::Lab);
+        assert_eq!(1, found[0], "Logic Error: 16 bits");
+        let found = MMCQ::bin_pixel_as(Rgba([11, 11, 11, 255]), ColorSpace::Lab);
+        assert_eq!(0, found[0], "Logic Error: 8 bits");
+        // 2047 is the top of the first rgb bin, rounded to 8 bits it would
+        // be 8 and land in the second
+        let found = MMCQ::bin_pixel16_as(Rgba([2047, 2048, 0, 65535]), ColorSpace::Rgb);
+        assert_eq!(Rgba([0, 1, 0, 255]), found, "Logic Error: rgb 16 bits");
+    }
+
+    #[test]
+    fn test_bin_pixel32f_as() {
+        let white = MMCQ::bin_pixel_as(Rgba([255, 255, 255, 255]), ColorSpace::Rgb);
+        let found = MMCQ::bin_pixel32f_as(Rgba([4.0, 4.0, 4.0, 1.0]), ToneMap::Clip, ColorSpace::Rgb);
+        assert_eq!(white, found, "Logic Error: clip");
+        // Reinhard maps 1 to 0.5 in linear light, sRGB 188
+        let found = MMCQ::bin_pixel32f_as(Rgba([1.0, 1.0, 1.0, 1.0]), ToneMap::Reinhard, ColorSpace::Rgb);
+        let expected = MMCQ::bin_pixel(Rgba([188, 188, 188, 255]));
+        assert_eq!(expected, found, "Logic Error: reinhard");
+        // Tone mapping keeps the hue of a bright red highlight
+        let found = MMCQ::bin_pixel32f_as(Rgba([8.0, 1.0, 0.0, 1.0]), ToneMap::Reinhard, ColorSpace::Rgb);
+        assert!(found[0] > found[1], "Logic Error: hue");
+    }
 }
@@ -0,0 +1,213 @@
+use std::str::FromStr;
+use image::Rgba;
+use crate::data_models::{ ColorPalette, QuantizeOptions };
+use crate::error::Error;
+use crate::mmcq::{ self, MMCQ };
+
+
+/// Byte order of a single pixel in a raw buffer
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum PixelLayout {
+    Rgb,
+    Bgr,
+    Rgba,
+    Bgra,
+    /// 16-bit little-endian, 5 bits red, 6 bits green, 5 bits blue
+    Rgb565,
+    /// Single 8-bit luma channel
+    Gray,
+}
+
+impl PixelLayout {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelLayout::Rgb | PixelLayout::Bgr => 3,
+            PixelLayout::Rgba | PixelLayout::Bgra => 4,
+            PixelLayout::Rgb565 => 2,
+            PixelLayout::Gray => 1,
+        }
+    }
+
+    /// Reads the pixel starting at the first byte, layouts without alpha
+    /// are opaque
+    fn read_pixel(&self, bytes: &[u8]) -> Rgba<u8> {
+        match self {
+            PixelLayout::Rgb => Rgba([bytes[0], bytes[1], bytes[2], 255]),
+            PixelLayout::Bgr => Rgba([bytes[2], bytes[1], bytes[0], 255]),
+            PixelLayout::Rgba => Rgba([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            PixelLayout::Bgra => Rgba([bytes[2], bytes[1], bytes[0], bytes[3]]),
+            PixelLayout::Rgb565 => {
+                let val = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let r = (val >> 11) as u8;
+                let g = ((val >> 5) & 0x3f) as u8;
+                let b = (val & 0x1f) as u8;
+                // Repeat the high bits so full intensity maps to 255
+                Rgba([(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2), 255])
+            },
+            PixelLayout::Gray => Rgba([bytes[0], bytes[0], bytes[0], 255]),
+        }
+    }
+}
+
+impl FromStr for PixelLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgb" => Ok(PixelLayout::Rgb),
+            "bgr" => Ok(PixelLayout::Bgr),
+            "rgba" => Ok(PixelLayout::Rgba),
+            "bgra" => Ok(PixelLayout::Bgra),
+            "rgb565" => Ok(PixelLayout::Rgb565),
+            "gray" | "grey" => Ok(PixelLayout::Gray),
+            _ => Err(format!("Unknown pixel layout: {}", s)),
+        }
+    }
+}
+
+/// Borrowed framebuffer, rows are `stride` bytes apart so padded rows and
+/// sub-regions of a larger buffer can be read in place
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct RawImage<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    layout: PixelLayout,
+}
+
+impl<'a> RawImage<'a> {
+    /// Checks that `data` holds `height` rows of `width` pixels, `stride`
+    /// being the number of bytes from one row to the next
+    pub fn new(data: &'a [u8], width: usize, height: usize, stride: usize, layout: PixelLayout) -> Result<RawImage<'a>, Error> {
+        let row_size = width.checked_mul(layout.bytes_per_pixel())
+            .ok_or(Error::InvalidBuffer("width overflows".into()))?;
+        if stride < row_size {
+            return Err(Error::InvalidBuffer(format!(
+                "stride of {} bytes is shorter than a row of {} bytes", stride, row_size
+            )));
+        }
+        // The last row does not need its padding
+        let required = match height {
+            0 => 0,
+            _ => stride.checked_mul(height - 1)
+                .and_then(|size| size.checked_add(row_size))
+                .ok_or(Error::InvalidBuffer("height overflows".into()))?,
+        };
+        if data.len() < required {
+            return Err(Error::InvalidBuffer(format!(
+                "{} bytes given, {} bytes required", data.len(), required
+            )));
+        }
+        Ok(RawImage { data, width, height, stride, layout })
+    }
+
+    /// Tightly packed buffer without row padding
+    pub fn packed(data: &'a [u8], width: usize, height: usize, layout: PixelLayout) -> Result<RawImage<'a>, Error> {
+        RawImage::new(data, width, height, width.saturating_mul(layout.bytes_per_pixel()), layout)
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Every pixel in row-major order, decoded on the fly
+    pub fn pixels(&self) -> impl Iterator<Item = Rgba<u8>> + 'a {
+        let RawImage { data, width, height, stride, layout } = *self;
+        let bytes_per_pixel = layout.bytes_per_pixel();
+        (0..height).flat_map(move |y| {
+            let row = &data[y * stride..y * stride + width * bytes_per_pixel];
+            row.chunks_exact(bytes_per_pixel).map(move |bytes| layout.read_pixel(bytes))
+        })
+    }
+}
+
+/// Palette of a raw framebuffer through the same sampling, alpha filter,
+/// binning and MMCQ steps as decoded images. `quality` uses every n-th
+/// pixel like the CLI option, 0 is taken as 1.
+pub fn calc_palette(raw: &RawImage, quality: usize, options: &QuantizeOptions) -> Result<ColorPalette, Error> {
+    let pixels: Vec<Rgba<u8>> = raw.pixels()
+        // Sample every n-th pixel
+        .step_by(quality.max(1))
+        // Filter out half-transparent pixels
+        .filter(|pixel| pixel[3] > 125)
+        // Bin using MMCQ bit shift or perceptual coordinates
+        .map(|pixel| MMCQ::bin_pixel_as(pixel, options.color_space))
+        .collect();
+    mmcq::quantize_pixels(&pixels, options)
+}
+
+
+#[cfg(test)]
+mod test_raw {
+    use super::*;
+
+    #[test]
+    fn test_read_pixel() {
+        let expected = Rgba([255, 128, 0, 255]);
+        let cases: [(PixelLayout, &[u8]); 5] = [
+            (PixelLayout::Rgb, &[255, 128, 0]),
+            (PixelLayout::Bgr, &[0, 128, 255]),
+            (PixelLayout::Rgba, &[255, 128, 0, 255]),
+            (PixelLayout::Bgra, &[0, 128, 255, 255]),
+            // 0b11111_100000_00000, green 32 of 63 expands to 130
+            (PixelLayout::Rgb565, &[0x00, 0xfc]),
+        ];
+        for (layout, bytes) in cases {
+            let found = layout.read_pixel(bytes);
+            let expected = match layout {
+                PixelLayout::Rgb565 => Rgba([255, 130, 0, 255]),
+                _ => expected,
+            };
+            assert_eq!(expected, found, "Logic Error: {:?}", layout);
+        }
+        assert_eq!(Rgba([255, 255, 255, 255]), PixelLayout::Rgb565.read_pixel(&[0xff, 0xff]), "Logic Error: white");
+        assert_eq!(Rgba([9, 9, 9, 255]), PixelLayout::Gray.read_pixel(&[9]), "Logic Error: gray");
+    }
+
+    #[test]
+    fn test_raw_image() {
+        // 2x2 gray image with two bytes of padding per row
+        let data = [1, 2, 0, 0, 3, 4];
+        let raw = RawImage::new(&data, 2, 2, 4, PixelLayout::Gray).unwrap();
+        let found: Vec<u8> = raw.pixels().map(|pixel| pixel[0]).collect();
+        assert_eq!(vec![1, 2, 3, 4], found, "Logic Error: stride");
+
+        let found = RawImage::new(&data, 2, 2, 1, PixelLayout::Gray);
+        assert!(matches!(found, Err(Error::InvalidBuffer(_))), "Logic Error: short stride");
+        let found = RawImage::packed(&data, 2, 2, PixelLayout::Rgb);
+        assert!(matches!(found, Err(Error::InvalidBuffer(_))), "Logic Error: short buffer");
+        let found = RawImage::packed(&[], 0, 0, PixelLayout::Rgba).unwrap();
+        assert_eq!(0, found.pixels().count(), "Logic Error: empty");
+    }
+
+    #[test]
+    fn test_calc_palette() {
+        // Same palette as going through a decoded image
+        let img = crate::img_io::open_img_rgba("tests/data/12colors.png").unwrap();
+        let (width, height) = img.dimensions();
+        let options = QuantizeOptions { color_count: 4, ..Default::default() };
+        let pixels: Vec<Rgba<u8>> = img.pixels()
+            .filter(|pixel| pixel[3] > 125)
+            .map(|&pixel| MMCQ::bin_pixel(pixel))
+            .collect();
+        let expected = mmcq::quantize_pixels(&pixels, &options).unwrap();
+
+        let raw = RawImage::packed(img.as_raw(), width as usize, height as usize, PixelLayout::Rgba).unwrap();
+        let found = calc_palette(&raw, 1, &options).unwrap();
+        assert_eq!(expected, found, "Logic Error: rgba");
+
+        // Swapping the channels of the buffer and the layout cancel out
+        let bgra: Vec<u8> = img.as_raw().chunks_exact(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+            .collect();
+        let raw = RawImage::packed(&bgra, width as usize, height as usize, PixelLayout::Bgra).unwrap();
+        let found = calc_palette(&raw, 1, &options).unwrap();
+        assert_eq!(expected, found, "Logic Error: bgra");
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt::Write;
+use image::{ Rgba, RgbaImage, imageops };
+use crate::data_models::ColorPalette;
+use crate::error::Error;
+
+
+/// Largest side of a single swatch, a strip of 256 swatches then stays
+/// below 1 GiB of pixels
+pub const MAX_SWATCH_SIZE: u32 = 1024;
+
+/// Renders the palette as a horizontal strip of square swatches, most
+/// populated color on the left. An empty palette renders a single
+/// transparent square.
+pub fn render_swatch_strip(palette: &ColorPalette, swatch_size: u32) -> Result<RgbaImage, Error> {
+    let width = strip_width(palette, swatch_size)?;
+    let mut strip = RgbaImage::new(width, swatch_size);
+    for (i, swatch) in palette.swatches.iter().enumerate() {
+        let [r, g, b] = swatch.color.0;
+        let x = i as u32 * swatch_size;
+        fill_rect(&mut strip, x, 0, swatch_size, swatch_size, Rgba([r, g, b, 255]));
+    }
+    Ok(strip)
+}
+
+/// Same layout as `render_swatch_strip` written as an SVG document, every
+/// swatch carries its hex code as a tooltip
+pub fn render_swatch_svg(palette: &ColorPalette, swatch_size: u32) -> Result<String, Error> {
+    let width = strip_width(palette, swatch_size)?;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        width, swatch_size, width, swatch_size,
+    );
+    for (i, swatch) in palette.swatches.iter().enumerate() {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            svg,
+            "  <rect x=\"{}\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"{}\"><title>{}</title></rect>",
+            i as u32 * swatch_size, swatch_size, swatch_size, swatch.hex(), swatch.hex(),
+        );
+    }
+    svg.push_str("</svg>\n");
+    Ok(svg)
+}
+
+/// Width of a strip of every swatch, or an error when it does not fit a
+/// `u32`. Every swatch offset is smaller, so it cannot overflow either.
+fn strip_width(palette: &ColorPalette, swatch_size: u32) -> Result<u32, Error> {
+    let count = palette.swatches.len().max(1);
+    u32::try_from(count).ok()
+        .and_then(|count| count.checked_mul(swatch_size))
+        .ok_or_else(|| Error::Unsupported(format!(
+            "A strip of {} swatches of {}px is too large", count, swatch_size,
+        )))
+}
+
+/// Renders the source image with the palette as a bar underneath, like
+/// the color-thief demo page. The bar is split in equal parts across the
+/// width of the image, `bar_height` of 0 picks a fifth of the image height.
+pub fn render_contact_sheet(img: &RgbaImage, palette: &ColorPalette, bar_height: u32) -> Result<RgbaImage, Error> {
+    let (width, height) = img.dimensions();
+    let bar_height = match bar_height {
+        0 => (height / 5).max(1),
+        val => val,
+    };
+    let sheet_height = height.checked_add(bar_height)
+        .ok_or(Error::DimensionsTooLarge { width, height })?;
+    let mut sheet = RgbaImage::new(width, sheet_height);
+    imageops::replace(&mut sheet, img, 0, 0);
+
+    let count = palette.swatches.len() as u64;
+    for (i, swatch) in palette.swatches.iter().enumerate() {
+        let [r, g, b] = swatch.color.0;
+        // Spread the remainder of the division over the first swatches,
+        // the offsets never exceed the width
+        let x_start = (i as u64 * width as u64 / count) as u32;
+        let x_end = ((i as u64 + 1) * width as u64 / count) as u32;
+        fill_rect(
+            &mut sheet, x_start, height, x_end - x_start, bar_height,
+            Rgba([r, g, b, 255]),
+        );
+    }
+    Ok(sheet)
+}
+
+fn fill_rect(img: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    for j in y..(y + height) {
+        for i in x..(x + width) {
+            img.put_pixel(i, j, color);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_render {
+    use super::*;
+    use crate::data_models::Swatch;
+    use image::Rgb;
+
+    fn sample_palette() -> ColorPalette {
+        ColorPalette {
+            swatches: vec![
+                Swatch { color: Rgb([255, 0, 0]), population: 2 },
+                Swatch { color: Rgb([0, 0, 255]), population: 1 },
+            ],
+            pixel_count: 3,
+        }
+    }
+
+    #[test]
+    fn test_render_swatch_strip() {
+        let found = render_swatch_strip(&sample_palette(), 4).unwrap();
+        assert_eq!((8, 4), found.dimensions(), "Logic Error: dimensions");
+        assert_eq!(Rgba([255, 0, 0, 255]), *found.get_pixel(3, 3), "Logic Error:");
+        assert_eq!(Rgba([0, 0, 255, 255]), *found.get_pixel(4, 0), "Logic Error:");
+    }
+
+    #[test]
+    fn test_render_swatch_svg() {
+        let found = render_swatch_svg(&sample_palette(), 10).unwrap();
+        let expected = concat!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"20\" height=\"10\" viewBox=\"0 0 20 10\">\n",
+            "  <rect x=\"0\" y=\"0\" width=\"10\" height=\"10\" fill=\"#ff0000\"><title>#ff0000</title></rect>\n",
+            "  <rect x=\"10\" y=\"0\" width=\"10\" height=\"10\" fill=\"#0000ff\"><title>#0000ff</title></rect>\n",
+            "</svg>\n",
+        );
+        assert_eq!(expected, found, "Logic Error:");
+
+        let found = render_swatch_svg(&sample_palette(), u32::MAX);
+        assert!(matches!(found, Err(Error::Unsupported(_))), "Logic Error: overflow");
+    }
+
+    #[test]
+    fn test_render_contact_sheet() {
+        let img = RgbaImage::from_pixel(5, 10, Rgba([9, 9, 9, 255]));
+        let found = render_contact_sheet(&img, &sample_palette(), 0).unwrap();
+        assert_eq!((5, 12), found.dimensions(), "Logic Error: dimensions");
+        assert_eq!(Rgba([9, 9, 9, 255]), *found.get_pixel(4, 9), "Logic Error: image");
+        assert_eq!(Rgba([255, 0, 0, 255]), *found.get_pixel(1, 10), "Logic Error: bar");
+        assert_eq!(Rgba([0, 0, 255, 255]), *found.get_pixel(4, 11), "Logic Error: bar");
+
+        let found = render_contact_sheet(&img, &sample_palette(), u32::MAX);
+        assert!(matches!(found, Err(Error::DimensionsTooLarge { .. })), "Logic Error: overflow");
+    }
+}
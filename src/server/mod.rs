@@ -0,0 +1,460 @@
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::net::{ Shutdown, TcpListener, TcpStream };
+use std::time::{ Duration, Instant };
+use image::ImageError;
+use crate::data_models::{ ColorPalette, ColorSpace, QuantizeOptions };
+use crate::error::Error;
+use crate::export;
+use crate::img_io::{ self, DecodeLimits };
+use crate::raw::{ self, PixelLayout, RawImage };
+
+
+/// Longest request line plus headers accepted
+pub const MAX_HEAD_LEN: usize = 8 * 1024;
+/// Colors quantized for `POST /color`, like `get_color` of color-thief
+pub const DOMINANT_COLOR_COUNT: usize = 5;
+/// Connections idle for longer are dropped
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Time left to drain the unread body after the response
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Unread body bytes discarded before closing, so the client receives the
+/// error instead of a reset connection
+const MAX_DRAIN_LEN: u64 = 1024 * 1024;
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ServerConfig {
+    /// Larger request bodies are refused before they are read
+    pub max_body_len: usize,
+    /// Time allowed for the whole head and body to arrive, however often
+    /// the client sends a few bytes
+    pub request_timeout: Duration,
+    pub limits: DecodeLimits,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_body_len: 32 * 1024 * 1024,
+            request_timeout: Duration::from_secs(60),
+            limits: DecodeLimits::default(),
+        }
+    }
+}
+
+/// Reads from the connection until a deadline. Every read waits at most
+/// for the idle timeout and never past the deadline, after which reads
+/// fail with `TimedOut`. A timeout too long to add to the clock leaves
+/// only the idle timeout.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(TIMEOUT),
+            None => TIMEOUT,
+        };
+        if timeout.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// JSON response, errors are `{"error": message}`
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn error(status: u16, message: &str) -> Response {
+        Response { status, body: format!("{{\"error\":{}}}", export::escape_json(message)) }
+    }
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+enum Endpoint {
+    Palette,
+    Color,
+}
+
+/// Query parameters shared by both endpoints, `count` is only accepted by
+/// `/palette`
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+struct Params {
+    quality: usize,
+    options: QuantizeOptions,
+}
+
+/// A request that passed every check, only the body is left to process
+struct Request {
+    endpoint: Endpoint,
+    params: Params,
+    body: Vec<u8>,
+}
+
+/// HTTP status of a failed pipeline: 413 for inputs beyond the limits, 415
+/// for unknown formats, 422 for corrupt data and 500 for server faults.
+/// Bodies are decoded from memory, so truncated and malformed data are the
+/// only expected I/O errors.
+pub fn status_of(err: &Error) -> u16 {
+    match err {
+        Error::Io(err) => match err.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => 422,
+            _ => 500,
+        },
+        Error::Decode(ImageError::Limits(_)) => 413,
+        Error::Decode(ImageError::Unsupported(_)) => 415,
+        Error::Decode(ImageError::Decoding(_)) => 422,
+        Error::Decode(ImageError::Parameter(_)) => 400,
+        Error::Decode(_) => 500,
+        Error::DimensionsTooLarge { .. } | Error::AllocationTooLarge | Error::TooManyPixels => 413,
+        Error::InvalidBuffer(_) | Error::InvalidStream(_) | Error::InvalidHistogram(_) => 422,
+        Error::Unsupported(_) => 415,
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Answers connections on `jobs` threads until the process is stopped
+pub fn serve(listener: &TcpListener, config: &ServerConfig, jobs: usize) {
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                match listener.accept() {
+                    Ok((stream, _)) => handle_connection(stream, config),
+                    Err(err) => eprintln!("Could not accept a connection: {}", err),
+                }
+            });
+        }
+    });
+}
+
+/// Reads a single request, answers it and closes the connection. The
+/// request line and status are logged to stderr.
+pub fn handle_connection(stream: TcpStream, config: &ServerConfig) {
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+    let deadline = Instant::now().checked_add(config.request_timeout);
+    let mut reader = BufReader::new(DeadlineReader { stream: &stream, deadline });
+    let (request_line, response) = match read_request(&mut reader, &stream, config) {
+        (request_line, Ok(request)) => (request_line, respond(&request, config)),
+        (request_line, Err(response)) => (request_line, response),
+    };
+    eprintln!("{} {}", request_line, response.status);
+
+    let allow = match response.status {
+        405 => "Allow: POST\r\n",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status, reason_phrase(response.status), response.body.len(), allow,
+    );
+    let mut writer = &stream;
+    if writer.write_all(head.as_bytes()).and_then(|_| writer.write_all(response.body.as_bytes())).is_err() {
+        return;
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    reader.get_mut().deadline = Instant::now().checked_add(DRAIN_TIMEOUT);
+    let _ = io::copy(&mut reader.take(MAX_DRAIN_LEN), &mut io::sink());
+}
+
+/// Request line, for the log, along with the request or the error response.
+/// Methods, paths, parameters and sizes are all checked before the body is
+/// read.
+fn read_request(reader: &mut impl BufRead, mut writer: impl Write, config: &ServerConfig) -> (String, Result<Request, Response>) {
+    let read_error = |err: io::Error| match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Response::error(408, "Timed out reading the request"),
+        _ => Response::error(400, "Could not read the request"),
+    };
+
+    // Request line and headers up to the empty line
+    let mut lines: Vec<String> = Vec::new();
+    let mut head_len = 0;
+    loop {
+        let mut line = String::new();
+        let read = reader.by_ref()
+            .take((MAX_HEAD_LEN + 1 - head_len) as u64)
+            .read_line(&mut line);
+        head_len += match read {
+            Ok(read) => read,
+            Err(err) => return (String::from("-"), Err(read_error(err))),
+        };
+        if head_len > MAX_HEAD_LEN {
+            return (String::from("-"), Err(Response::error(431, "Request head is too large")));
+        }
+        if !line.ends_with('\n') {
+            return (String::from("-"), Err(Response::error(400, "Incomplete request")));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let request_line = lines.first().cloned().unwrap_or_default();
+    let fail = |status: u16, message: &str| (request_line.clone(), Err(Response::error(status, message)));
+    let [method, target, version] = request_line.split(' ').collect::<Vec<&str>>()[..] else {
+        return fail(400, "Malformed request line");
+    };
+    if !version.starts_with("HTTP/1.") {
+        return fail(400, "Only HTTP/1.x is supported");
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let endpoint = match path {
+        "/palette" => Endpoint::Palette,
+        "/color" => Endpoint::Color,
+        _ => return fail(404, "Unknown path, use POST /palette or POST /color"),
+    };
+    if method != "POST" {
+        return fail(405, "Only POST is allowed");
+    }
+    let params = match parse_params(query, endpoint) {
+        Ok(params) => params,
+        Err(message) => return fail(400, &message),
+    };
+
+    let mut content_len: Option<usize> = None;
+    let mut is_continue_expected = false;
+    for line in &lines[1..] {
+        let Some((name, value)) = line.split_once(':') else {
+            return fail(400, "Malformed header");
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => match value.parse() {
+                Ok(len) => content_len = Some(len),
+                Err(_) => return fail(400, "Invalid Content-Length"),
+            },
+            "transfer-encoding" => return fail(411, "Send the body with a Content-Length, not chunked"),
+            "expect" => is_continue_expected = value.eq_ignore_ascii_case("100-continue"),
+            _ => (),
+        }
+    }
+    let Some(content_len) = content_len else {
+        return fail(411, "Content-Length is required");
+    };
+    if content_len > config.max_body_len {
+        return fail(413, &format!("Body exceeds {} bytes", config.max_body_len));
+    }
+
+    if is_continue_expected {
+        let _ = writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+    let mut body = vec![0; content_len];
+    if let Err(err) = reader.read_exact(&mut body) {
+        return (request_line, Err(read_error(err)));
+    }
+    (request_line, Ok(Request { endpoint, params, body }))
+}
+
+/// `count`, `quality` and `color_space`, defaulting like the CLI
+fn parse_params(query: &str, endpoint: Endpoint) -> Result<Params, String> {
+    let mut params = Params { quality: 10, options: QuantizeOptions::default() };
+    if endpoint == Endpoint::Color {
+        params.options.color_count = DOMINANT_COLOR_COUNT;
+    }
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let invalid = || format!("Invalid {}: {}", name, value);
+        match name {
+            "count" if endpoint == Endpoint::Palette => {
+                params.options.color_count = value.parse().ok()
+                    .filter(|count| (2..=256).contains(count))
+                    .ok_or_else(invalid)?;
+            },
+            "quality" => {
+                params.quality = value.parse().ok().filter(|&quality| quality >= 1).ok_or_else(invalid)?;
+            },
+            "color_space" => params.options.color_space = value.parse::<ColorSpace>()?,
+            _ => return Err(format!("Unknown parameter: {}", name)),
+        }
+    }
+    Ok(params)
+}
+
+fn respond(request: &Request, config: &ServerConfig) -> Response {
+    let palette = match calc_palette(&request.body, &request.params, config) {
+        Ok(palette) => palette,
+        Err(err) => return Response::error(status_of(&err), &err.to_string()),
+    };
+    match request.endpoint {
+        Endpoint::Palette => {
+            let swatches: Vec<String> = palette.swatches.iter().map(export::format_json_swatch).collect();
+            Response {
+                status: 200,
+                body: format!("{{\"pixel_count\":{},\"palette\":[{}]}}", palette.pixel_count, swatches.join(",")),
+            }
+        },
+        Endpoint::Color => match palette.swatches.first() {
+            Some(swatch) => Response { status: 200, body: export::format_json_swatch(swatch) },
+            None => Response::error(422, "No pixel is opaque enough to take a color from"),
+        },
+    }
+}
+
+/// Decodes the body within the limits and runs the raw buffer pipeline on
+/// it. Embedded ICC profiles are not applied, colors are taken as sRGB.
+fn calc_palette(body: &[u8], params: &Params, config: &ServerConfig) -> Result<ColorPalette, Error> {
+    let (img, _) = img_io::load_img_with_profile(body, &config.limits)?;
+    let img = img_io::to_rgba8_within(&img, &config.limits)?;
+    let (width, height) = img.dimensions();
+    let raw = RawImage::packed(img.as_raw(), width as usize, height as usize, PixelLayout::Rgba)?;
+    raw::calc_palette(&raw, params.quality, &params.options)
+}
+
+
+#[cfg(test)]
+mod test_server {
+    use super::*;
+
+    /// Sends the raw request to a server on localhost, returns the status of
+    /// the last response and its body
+    fn send(request: &[u8], config: &ServerConfig) -> (u16, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| handle_connection(listener.accept().unwrap().0, config));
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            // Skip interim responses
+            let last = response.rfind("HTTP/1.1 ").unwrap();
+            let (head, body) = response[last..].split_once("\r\n\r\n").unwrap();
+            (head[9..12].parse().unwrap(), body.to_string())
+        })
+    }
+
+    fn post(target: &str, body: &[u8]) -> Vec<u8> {
+        let mut request = format!("POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", target, body.len()).into_bytes();
+        request.extend(body);
+        request
+    }
+
+    #[test]
+    fn test_status_of() {
+        let cases = [
+            (500, Error::Io(io::Error::other("disk"))),
+            (413, Error::DimensionsTooLarge { width: 1, height: 1 }),
+            (413, Error::AllocationTooLarge),
+            (422, Error::InvalidBuffer("short".into())),
+            (415, Error::Unsupported("video".into())),
+            (415, img_io::load_img(b"not an image").unwrap_err()),
+            (422, img_io::load_img(b"\x89PNG\r\n\x1a\n\0").unwrap_err()),
+        ];
+        for (expected, err) in cases {
+            assert_eq!(expected, status_of(&err), "Logic Error: {:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let found = parse_params("count=4&quality=1&color_space=oklab", Endpoint::Palette).unwrap();
+        let options = QuantizeOptions { color_count: 4, color_space: ColorSpace::Oklab, ..Default::default() };
+        assert_eq!(Params { quality: 1, options }, found, "Logic Error: palette");
+        let found = parse_params("", Endpoint::Color).unwrap();
+        assert_eq!(DOMINANT_COLOR_COUNT, found.options.color_count, "Logic Error: color count");
+        for query in ["count=1", "quality=0", "color_space=hsv", "colour=red", "count=4x"] {
+            assert!(parse_params(query, Endpoint::Palette).is_err(), "Logic Error: {}", query);
+        }
+        assert!(parse_params("count=4", Endpoint::Color).is_err(), "Logic Error: count of /color");
+    }
+
+    #[test]
+    fn test_handle_connection() {
+        let config = ServerConfig::default();
+        let img = std::fs::read("tests/data/12colors.png").unwrap();
+        let options = QuantizeOptions { color_count: 4, ..Default::default() };
+        let expected = calc_palette(&img, &Params { quality: 1, options }, &config).unwrap();
+
+        let (status, body) = send(&post("/palette?count=4&quality=1", &img), &config);
+        assert_eq!(200, status, "Logic Error: palette status");
+        let prefix = format!("{{\"pixel_count\":{},\"palette\":[{}", expected.pixel_count, export::format_json_swatch(&expected.swatches[0]));
+        assert!(body.starts_with(&prefix), "Logic Error: palette body {}", body);
+
+        let (status, body) = send(&post("/color", &img), &config);
+        assert_eq!(200, status, "Logic Error: color status");
+        assert!(body.starts_with("{\"hex\":\"#"), "Logic Error: color body {}", body);
+
+        // Interim response before the body
+        let mut request = post("/color", &img);
+        let expect = b"Expect: 100-continue\r\n";
+        let at = request.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 2;
+        request.splice(at..at, expect.iter().copied());
+        assert_eq!(200, send(&request, &config).0, "Logic Error: continue");
+
+        let small = ServerConfig { max_body_len: 10, ..Default::default() };
+        let cases: [(u16, &str, Vec<u8>, &ServerConfig); 8] = [
+            (415, "unknown format", post("/palette", b"not an image"), &config),
+            (413, "body size", post("/palette", &img), &small),
+            (400, "parameter", post("/palette?count=1", &img), &config),
+            (404, "path", post("/nope", &img), &config),
+            (405, "method", b"GET /palette HTTP/1.1\r\n\r\n".to_vec(), &config),
+            (411, "length", b"POST /palette HTTP/1.1\r\n\r\n".to_vec(), &config),
+            (400, "request line", b"POST /palette\r\n\r\n".to_vec(), &config),
+            (431, "head size", format!("POST /palette HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_LEN)).into_bytes(), &config),
+        ];
+        for (expected, name, request, config) in cases {
+            let (status, body) = send(&request, config);
+            assert_eq!(expected, status, "Logic Error: {}", name);
+            assert!(body.starts_with("{\"error\":\""), "Logic Error: {} body {}", name, body);
+        }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        // A header line every 50 ms never leaves the connection idle, the
+        // whole request still has to arrive within the timeout
+        let config = ServerConfig { request_timeout: Duration::from_millis(300), ..Default::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let start = Instant::now();
+        let status = std::thread::scope(|scope| {
+            scope.spawn(|| handle_connection(listener.accept().unwrap().0, &config));
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"POST /palette HTTP/1.1\r\n").unwrap();
+            for _ in 0..40 {
+                if stream.write_all(b"X: a\r\n").is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response.get(9..12).map(str::to_string)
+        });
+        assert_eq!(Some(String::from("408")), status, "Logic Error: status");
+        assert!(start.elapsed() < Duration::from_secs(2) + Duration::from_millis(300), "Logic Error: deadline");
+    }
+}
@@ -1,5 +1,6 @@
 use image::Rgba;
 use std::collections::HashMap;
+use crate::color;
 use crate::mmcq::MMCQ;
 use crate::data_models::{
     Histogram, DimHistograms, FrequencyMap, MinMaxBox, ColorChannel
@@ -8,13 +9,11 @@ use crate::data_models::{
 
 pub fn calc_dim_histograms(pixels: &Vec<Rgba<u8>>) -> DimHistograms {
     // NOTE this can be a good place to do multithreading
-    let rhistogram = calc_histogram(ColorChannel::Red, &pixels);
-    let ghistogram = calc_histogram(ColorChannel::Green, &pixels);
-    let bhistogram = calc_histogram(ColorChannel::Blue, &pixels);
+    let rhistogram = calc_histogram(ColorChannel::Red, pixels);
+    let ghistogram = calc_histogram(ColorChannel::Green, pixels);
+    let bhistogram = calc_histogram(ColorChannel::Blue, pixels);
 
-    DimHistograms {
-        0: [rhistogram, ghistogram, bhistogram]
-    }
+    DimHistograms([rhistogram, ghistogram, bhistogram])
 }
 
 pub fn calc_histogram(
@@ -22,13 +21,13 @@ pub fn calc_histogram(
     // Match algorithm to ColorChannel
     match color_ch {
         ColorChannel::Red => {
-            generate_histogram(color_ch, &pixels)
+            generate_histogram(color_ch, pixels)
         }
         ColorChannel::Green => {
-            generate_histogram(color_ch, &pixels)
+            generate_histogram(color_ch, pixels)
         }
         ColorChannel::Blue => {
-            generate_histogram(color_ch, &pixels)
+            generate_histogram(color_ch, pixels)
         }
     }
 }
@@ -61,9 +60,7 @@ fn generate_histogram(color_ch: ColorChannel, pixels: &Vec<Rgba<u8>>) -> Histogr
     }
     // Remove all values from zero to minimum value
     histogram.drain(..(min as usize));
-    Histogram{
-        0: histogram
-    }
+    Histogram(histogram)
 }
 
 pub fn calc_frequency_map(pixels: &Vec<Rgba<u8>>, hash_algo: &dyn Fn(&Rgba<u8>) -> u32) -> FrequencyMap {
@@ -73,9 +70,7 @@ pub fn calc_frequency_map(pixels: &Vec<Rgba<u8>>, hash_algo: &dyn Fn(&Rgba<u8>)
         let count = frequency_map.entry(hash).or_insert(0);
         *count += 1;
     }
-    FrequencyMap{
-        0: frequency_map
-    }
+    FrequencyMap(frequency_map)
 }
 
 pub fn calc_minmax_box(pixels: &Vec<Rgba<u8>>) -> MinMaxBox {
@@ -101,16 +96,27 @@ pub fn calc_minmax_box(pixels: &Vec<Rgba<u8>>) -> MinMaxBox {
 
     // Generate the MinMaxBox
     MinMaxBox {
-        rmin: rmin,
-        rmax: rmax,
-        gmin: gmin,
-        gmax: gmax,
-        bmin: bmin,
-        bmax: bmax,
+        rmin,
+        rmax,
+        gmin,
+        gmax,
+        bmin,
+        bmax,
     }
 }
 
-fn replace_minmax(val: u8, min: &mut u8, max: &mut u8) -> () {
+/// Smallest MinMaxBox holding every bin of a frequency map, such as one
+/// loaded or merged without its pixels. `None` for an empty map.
+pub fn calc_frequency_minmax_box(frequency_map: &FrequencyMap) -> Option<MinMaxBox> {
+    frequency_map.0.keys()
+        .map(|&hash| {
+            let [r, g, b] = MMCQ::unhash(hash);
+            MinMaxBox { rmin: r, rmax: r, gmin: g, gmax: g, bmin: b, bmax: b }
+        })
+        .reduce(|merged_box, minmax_box| merged_box.union(&minmax_box))
+}
+
+fn replace_minmax(val: u8, min: &mut u8, max: &mut u8) {
     if val < *min {
         *min = val;
     }
@@ -125,43 +131,151 @@ pub fn calc_cumul_histo(frequency_map: &FrequencyMap, color_channel: &ColorChann
     // Iterate through the bounding box min maxes
     let mut total: u32 = 0;
     let mut partialsum = Vec::new();
-    for i in minmax_box.rmin..(minmax_box.rmax + 1) {
-        let mut isum: u32 = 0;
-        for j in minmax_box.gmin..(minmax_box.gmax + 1) {
-            isum = 0;
-            for k in minmax_box.bmin..(minmax_box.bmax + 1) {
+    // Walk the longest channel first, the other two channels are summed
+    let (main_range, side_range1, side_range2) = match color_channel {
+        ColorChannel::Red => (
+            minmax_box.rmin..=minmax_box.rmax,
+            minmax_box.gmin..=minmax_box.gmax,
+            minmax_box.bmin..=minmax_box.bmax,
+        ),
+        ColorChannel::Green => (
+            minmax_box.gmin..=minmax_box.gmax,
+            minmax_box.bmin..=minmax_box.bmax,
+            minmax_box.rmin..=minmax_box.rmax,
+        ),
+        ColorChannel::Blue => (
+            minmax_box.bmin..=minmax_box.bmax,
+            minmax_box.rmin..=minmax_box.rmax,
+            minmax_box.gmin..=minmax_box.gmax,
+        ),
+    };
+    for i in main_range {
+        for j in side_range1.clone() {
+            let mut isum: u32 = 0;
+            for k in side_range2.clone() {
                 let rgb: [u8; 3] = match color_channel {
                     ColorChannel::Red => {
                         [i, j, k]
                     },
                     ColorChannel::Green => {
-                        [j, k, i]
+                        [k, i, j]
                     }
                     ColorChannel::Blue => {
-                        [k, i, j]
+                        [j, k, i]
                     }
                 };
                 let color_hash = MMCQ::hash_rgb(rgb[0], rgb[1], rgb[2]);
-                let val = match frequency_map.get(&color_hash) {
-                    Some(v) => v,
-                    None => &0
-                };
-                isum += val;
+                isum += frequency_map.get(&color_hash).unwrap_or(&0);
             }
             total += isum;
         }
         partialsum.push(total);
     }
-    (
-        Histogram {
-            0: partialsum
-        },
-        total
-    )
+    (Histogram(partialsum), total)
+}
+
+/// Number of pixels inside a MinMaxBox, counted by visiting every bin of
+/// the box. Boxes produced by splitting never overlap, so summing this over
+/// a whole BoxQueue costs at most one lookup per bin of the color space.
+pub fn calc_box_count(frequency_map: &FrequencyMap, minmax_box: &MinMaxBox) -> u32 {
+    let frequency_map = &frequency_map.0;
+    let mut count: u32 = 0;
+    for i in minmax_box.rmin..=minmax_box.rmax {
+        for j in minmax_box.gmin..=minmax_box.gmax {
+            for k in minmax_box.bmin..=minmax_box.bmax {
+                let color_hash = MMCQ::hash_rgb(i, j, k);
+                count += frequency_map.get(&color_hash).unwrap_or(&0);
+            }
+        }
+    }
+    count
+}
+
+/// Number of bins inside a MinMaxBox
+pub fn calc_box_volume(minmax_box: &MinMaxBox) -> u32 {
+    let red_width = (minmax_box.rmax - minmax_box.rmin) as u32 + 1;
+    let green_width = (minmax_box.gmax - minmax_box.gmin) as u32 + 1;
+    let blue_width = (minmax_box.bmax - minmax_box.bmin) as u32 + 1;
+    red_width * green_width * blue_width
+}
+
+/// Population-weighted average bin of a MinMaxBox in bin units, each bin
+/// is represented by its center (bin 3 averages to 3.5). Empty boxes fall
+/// back to the center of the box.
+pub fn calc_average_bin(frequency_map: &FrequencyMap, minmax_box: &MinMaxBox) -> [f64; 3] {
+    let frequency_map = &frequency_map.0;
+
+    let mut total: u64 = 0;
+    let mut rsum: f64 = 0.0;
+    let mut gsum: f64 = 0.0;
+    let mut bsum: f64 = 0.0;
+    for i in minmax_box.rmin..=minmax_box.rmax {
+        for j in minmax_box.gmin..=minmax_box.gmax {
+            for k in minmax_box.bmin..=minmax_box.bmax {
+                let color_hash = MMCQ::hash_rgb(i, j, k);
+                let count = *frequency_map.get(&color_hash).unwrap_or(&0) as f64;
+                total += count as u64;
+                rsum += count * (i as f64 + 0.5);
+                gsum += count * (j as f64 + 0.5);
+                bsum += count * (k as f64 + 0.5);
+            }
+        }
+    }
+
+    if total == 0 {
+        let center = |min: u8, max: u8| (min as f64 + max as f64 + 1.0) / 2.0;
+        return [
+            center(minmax_box.rmin, minmax_box.rmax),
+            center(minmax_box.gmin, minmax_box.gmax),
+            center(minmax_box.bmin, minmax_box.bmax),
+        ];
+    }
+    let total = total as f64;
+    [rsum / total, gsum / total, bsum / total]
+}
+
+/// Population-weighted average of a MinMaxBox in linear light, each bin is
+/// represented by the sRGB value at its center. Empty boxes fall back to
+/// the center of the box.
+pub fn calc_linear_average(frequency_map: &FrequencyMap, minmax_box: &MinMaxBox) -> [f64; 3] {
+    let frequency_map = &frequency_map.0;
+    // sRGB value at the center of a bin
+    let to_linear = |bin: u8| {
+        color::srgb_to_linear((bin << MMCQ::BIT_SHIFT) + (1 << (MMCQ::BIT_SHIFT - 1)))
+    };
+
+    let mut total: u64 = 0;
+    let mut rsum: f64 = 0.0;
+    let mut gsum: f64 = 0.0;
+    let mut bsum: f64 = 0.0;
+    for i in minmax_box.rmin..=minmax_box.rmax {
+        for j in minmax_box.gmin..=minmax_box.gmax {
+            for k in minmax_box.bmin..=minmax_box.bmax {
+                let color_hash = MMCQ::hash_rgb(i, j, k);
+                let count = *frequency_map.get(&color_hash).unwrap_or(&0) as f64;
+                total += count as u64;
+                rsum += count * to_linear(i);
+                gsum += count * to_linear(j);
+                bsum += count * to_linear(k);
+            }
+        }
+    }
+
+    if total == 0 {
+        let center = |min: u8, max: u8| (to_linear(min) + to_linear(max)) / 2.0;
+        return [
+            center(minmax_box.rmin, minmax_box.rmax),
+            center(minmax_box.gmin, minmax_box.gmax),
+            center(minmax_box.bmin, minmax_box.bmax),
+        ];
+    }
+    let total = total as f64;
+    [rsum / total, gsum / total, bsum / total]
 }
 
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast, clippy::init_numbered_fields)]
 mod test_stats {
     use super::*;
     use image::Rgba;
@@ -297,6 +411,19 @@ mod test_stats {
         assert_eq!(expected.bmax, found.bmax, "Logic Error: bmax");
     }
 
+    #[test]
+    fn test_calc_frequency_minmax_box() {
+        let pixels = vec![
+            Rgba::from([1, 20, 3, 255]),
+            Rgba::from([4, 5, 31, 255]),
+            Rgba::from([0, 9, 8, 255]),
+        ];
+        let frequency_map = calc_frequency_map(&pixels, &MMCQ::hash_pixel);
+        let found = calc_frequency_minmax_box(&frequency_map);
+        assert_eq!(Some(calc_minmax_box(&pixels)), found, "Logic Error:");
+        assert_eq!(None, calc_frequency_minmax_box(&FrequencyMap::default()), "Logic Error: empty");
+    }
+
     #[test]
     fn test_replace_minmax() {
         let mut min: u8 = 255;
@@ -340,4 +467,146 @@ mod test_stats {
         let found = calc_cumul_histo(&frequency_map, &color_channel, minmax_box);
         assert_eq!(expected.0, found.0.0, "Logic Error:");
     }
+
+    #[test]
+    fn test_calc_cumul_histo_green_blue() {
+        // Bins (0, 0, 0), (1, 2, 1) and (0, 3, 2) with 1, 2 and 4 pixels
+        let frequency_map: FrequencyMap = FrequencyMap(
+            HashMap::from([
+                (MMCQ::hash_rgb(0, 0, 0), 1),
+                (MMCQ::hash_rgb(1, 2, 1), 2),
+                (MMCQ::hash_rgb(0, 3, 2), 4),
+            ])
+        );
+        let minmax_box: MinMaxBox = MinMaxBox {
+            rmin: 0,
+            rmax: 1,
+            gmin: 0,
+            gmax: 3,
+            bmin: 0,
+            bmax: 2,
+        };
+        // Every entry sums the planes of the channel up to and including it
+        let found = calc_cumul_histo(&frequency_map, &ColorChannel::Green, minmax_box.clone());
+        assert_eq!((vec![1, 1, 3, 7], 7), (found.0.0, found.1), "Logic Error: green");
+        let found = calc_cumul_histo(&frequency_map, &ColorChannel::Blue, minmax_box);
+        assert_eq!((vec![1, 3, 7], 7), (found.0.0, found.1), "Logic Error: blue");
+    }
+    #[test]
+    fn test_calc_box_count() {
+        let frequency_map: FrequencyMap = FrequencyMap(
+            HashMap::from([
+                (MMCQ::hash_rgb(1, 1, 1), 3),
+                (MMCQ::hash_rgb(2, 3, 4), 2),
+                (MMCQ::hash_rgb(9, 9, 9), 7),
+            ])
+        );
+        let minmax_box = MinMaxBox {
+            rmin: 0,
+            rmax: 4,
+            gmin: 0,
+            gmax: 4,
+            bmin: 0,
+            bmax: 4,
+        };
+        let found = calc_box_count(&frequency_map, &minmax_box);
+        assert_eq!(5, found, "Logic Error:");
+        assert_eq!(125, calc_box_volume(&minmax_box), "Logic Error: volume");
+    }
+
+    #[test]
+    fn test_calc_average_bin() {
+        let frequency_map: FrequencyMap = FrequencyMap(
+            HashMap::from([
+                (MMCQ::hash_rgb(0, 0, 0), 1),
+                (MMCQ::hash_rgb(2, 4, 6), 1),
+            ])
+        );
+        let minmax_box = MinMaxBox {
+            rmin: 0,
+            rmax: 2,
+            gmin: 0,
+            gmax: 4,
+            bmin: 0,
+            bmax: 6,
+        };
+        let found = calc_average_bin(&frequency_map, &minmax_box);
+        // Bin centers are (0.5, 0.5, 0.5) and (2.5, 4.5, 6.5)
+        let expected = [1.5, 2.5, 3.5];
+        assert_eq!(expected, found, "Logic Error:");
+
+        let empty_box = MinMaxBox {
+            rmin: 10,
+            rmax: 11,
+            gmin: 10,
+            gmax: 11,
+            bmin: 10,
+            bmax: 11,
+        };
+        let found = calc_average_bin(&frequency_map, &empty_box);
+        let expected = [11.0, 11.0, 11.0];
+        assert_eq!(expected, found, "Logic Error: empty box");
+    }
+
+    #[test]
+    fn test_calc_linear_average() {
+        // Bin centers are sRGB 4 and 252
+        let frequency_map: FrequencyMap = FrequencyMap(
+            HashMap::from([
+                (MMCQ::hash_rgb(0, 0, 0), 1),
+                (MMCQ::hash_rgb(31, 31, 31), 1),
+            ])
+        );
+        let minmax_box = MinMaxBox {
+            rmin: 0,
+            rmax: 31,
+            gmin: 0,
+            gmax: 31,
+            bmin: 0,
+            bmax: 31,
+        };
+        let found = calc_linear_average(&frequency_map, &minmax_box);
+        let expected = (color::srgb_to_linear(4) + color::srgb_to_linear(252)) / 2.0;
+        assert_eq!([expected; 3], found, "Logic Error:");
+        // Half the light encodes well above the sRGB midpoint of 128
+        assert_eq!(185, color::linear_to_srgb(found[0]), "Logic Error: encoding");
+    }
+
+    #[test]
+    fn test_merge_frequency_map() {
+        let black = MMCQ::hash_rgb(0, 0, 0);
+        let white = MMCQ::hash_rgb(31, 31, 31);
+        let mut frequency_map = FrequencyMap(HashMap::from([(black, 3), (white, 1)]));
+        let other = FrequencyMap(HashMap::from([(white, 2)]));
+        frequency_map.add(&other);
+        assert_eq!(FrequencyMap(HashMap::from([(black, 3), (white, 3)])), frequency_map, "Logic Error: add");
+
+        frequency_map.weight(2);
+        assert_eq!(12, frequency_map.total(), "Logic Error: weight");
+        // Totals past u32::MAX are scaled to fit, keeping the proportions
+        frequency_map.weight(u32::MAX);
+        let half = u32::MAX / 2;
+        assert_eq!(FrequencyMap(HashMap::from([(black, half), (white, half)])), frequency_map, "Logic Error: weight overflow");
+        frequency_map.add(&FrequencyMap(HashMap::from([(black, u32::MAX)])));
+        assert!(frequency_map.total() <= u32::MAX as u64, "Logic Error: add overflow");
+        let (black_count, white_count) = (frequency_map.0[&black], frequency_map.0[&white]);
+        assert_eq!(3, black_count / white_count, "Logic Error: add proportions");
+
+        // 999 black and 1 white, white rounds to zero and is dropped
+        let mut frequency_map = FrequencyMap(HashMap::from([(black, 999), (white, 1)]));
+        frequency_map.normalize(100);
+        assert_eq!(FrequencyMap(HashMap::from([(black, 100)])), frequency_map, "Logic Error: normalize");
+        let mut empty = FrequencyMap::default();
+        empty.normalize(100);
+        assert_eq!(0, empty.total(), "Logic Error: normalize empty");
+    }
+
+    #[test]
+    fn test_minmax_box_union() {
+        let dark = MinMaxBox { rmin: 0, rmax: 4, gmin: 2, gmax: 3, bmin: 10, bmax: 12 };
+        let light = MinMaxBox { rmin: 20, rmax: 31, gmin: 0, gmax: 1, bmin: 11, bmax: 11 };
+        let expected = MinMaxBox { rmin: 0, rmax: 31, gmin: 0, gmax: 3, bmin: 10, bmax: 12 };
+        assert_eq!(expected, dark.union(&light), "Logic Error:");
+        assert_eq!(expected, light.union(&dark), "Logic Error: symmetry");
+    }
 }
@@ -0,0 +1,148 @@
+use std::fmt::Write;
+use std::io::IsTerminal;
+use image::Rgb;
+use crate::export::{ self, PaletteRecord };
+
+
+/// Escape sequences the terminal is able to display
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum ColorSupport {
+    None,
+    Ansi256,
+    TrueColor,
+}
+
+/// Picks the richest escape sequences stdout understands. Color is
+/// disabled when stdout is not a TTY or `NO_COLOR` is set, otherwise the
+/// depth follows `detect_color_depth`.
+pub fn detect_color_support() -> ColorSupport {
+    if !std::io::stdout().is_terminal() {
+        return ColorSupport::None;
+    }
+    let env = |key: &str| std::env::var(key).unwrap_or_default();
+    if !env("NO_COLOR").is_empty() || env("TERM") == "dumb" {
+        return ColorSupport::None;
+    }
+    detect_color_depth()
+}
+
+/// 24-bit color when `COLORTERM` advertises it and 256 colors otherwise,
+/// whether or not stdout is a terminal
+pub fn detect_color_depth() -> ColorSupport {
+    match std::env::var("COLORTERM").unwrap_or_default().as_str() {
+        "truecolor" | "24bit" => ColorSupport::TrueColor,
+        _ => ColorSupport::Ansi256,
+    }
+}
+
+/// Nearest entry of the xterm 256-color palette, either from the 6x6x6
+/// color cube or from the 24 step grayscale ramp
+pub fn rgb_to_ansi256(color: Rgb<u8>) -> u8 {
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let to_cube = |val: u8| -> u8 {
+        if val < 48 {
+            0
+        } else if val < 115 {
+            1
+        } else {
+            (val - 35) / 40
+        }
+    };
+    let [r, g, b] = color.0;
+    let (ri, gi, bi) = (to_cube(r), to_cube(g), to_cube(b));
+    let cube_color = [CUBE_LEVELS[ri as usize], CUBE_LEVELS[gi as usize], CUBE_LEVELS[bi as usize]];
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = if average > 238 { 23 } else { average.saturating_sub(3) / 10 };
+    let gray_level = (8 + 10 * gray_index) as u8;
+
+    let distance = |other: [u8; 3]| -> u32 {
+        color.0.iter().zip(other)
+            .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+            .sum()
+    };
+    if distance([gray_level; 3]) < distance(cube_color) {
+        232 + gray_index as u8
+    } else {
+        16 + 36 * ri + 6 * gi + bi
+    }
+}
+
+/// Background escape sequence for a color block
+fn background(color: Rgb<u8>, support: ColorSupport) -> String {
+    let [r, g, b] = color.0;
+    match support {
+        ColorSupport::None => String::new(),
+        ColorSupport::Ansi256 => format!("\x1b[48;5;{}m", rgb_to_ansi256(color)),
+        ColorSupport::TrueColor => format!("\x1b[48;2;{};{};{}m", r, g, b),
+    }
+}
+
+/// Formats a record with every swatch drawn as a colored block next to
+/// its hex code and share of the filtered pixels. Without color support
+/// this is the plain text output.
+pub fn format_preview(record: &PaletteRecord, support: ColorSupport) -> String {
+    if support == ColorSupport::None {
+        return export::format_text(record);
+    }
+    const RESET: &str = "\x1b[0m";
+    let mut preview = format!(
+        "{} ({} pixels)\n", record.source, record.palette.pixel_count
+    );
+    for swatch in &record.palette.swatches {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            preview,
+            "  {}      {}  {}  {:>5.1}%",
+            background(swatch.color, support), RESET, swatch.hex(),
+            export::calc_percentage(swatch.population, record.palette.pixel_count),
+        );
+    }
+    preview
+}
+
+
+#[cfg(test)]
+mod test_terminal {
+    use super::*;
+    use crate::data_models::{ ColorPalette, Swatch };
+
+    #[test]
+    fn test_rgb_to_ansi256() {
+        assert_eq!(16, rgb_to_ansi256(Rgb([0, 0, 0])), "Logic Error: black");
+        assert_eq!(231, rgb_to_ansi256(Rgb([255, 255, 255])), "Logic Error: white");
+        assert_eq!(196, rgb_to_ansi256(Rgb([255, 0, 0])), "Logic Error: red");
+        assert_eq!(244, rgb_to_ansi256(Rgb([128, 128, 128])), "Logic Error: gray");
+        assert_eq!(67, rgb_to_ansi256(Rgb([95, 135, 175])), "Logic Error: cube");
+    }
+
+    #[test]
+    fn test_format_preview() {
+        let record = PaletteRecord {
+            source: String::from("a.png"),
+            palette: ColorPalette {
+                swatches: vec![
+                    Swatch { color: Rgb([255, 0, 0]), population: 3 },
+                    Swatch { color: Rgb([0, 0, 255]), population: 1 },
+                ],
+                pixel_count: 4,
+            },
+        };
+        let found = format_preview(&record, ColorSupport::TrueColor);
+        let expected = concat!(
+            "a.png (4 pixels)\n",
+            "  \x1b[48;2;255;0;0m      \x1b[0m  #ff0000   75.0%\n",
+            "  \x1b[48;2;0;0;255m      \x1b[0m  #0000ff   25.0%\n",
+        );
+        assert_eq!(expected, found, "Logic Error: truecolor");
+
+        let found = format_preview(&record, ColorSupport::Ansi256);
+        assert!(found.contains("\x1b[48;5;196m"), "Logic Error: 256 colors");
+
+        let found = format_preview(&record, ColorSupport::None);
+        assert!(!found.contains('\x1b'), "Logic Error: escapes without color");
+    }
+}
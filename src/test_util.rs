@@ -0,0 +1,24 @@
+use image::Rgb;
+use crate::data_models::{ ColorPalette, Swatch };
+
+
+/// Palette of the given colors and populations in that order, every pixel
+/// counted
+pub fn palette(swatches: &[([u8; 3], u32)]) -> ColorPalette {
+    let swatches: Vec<Swatch> = swatches.iter()
+        .map(|&(color, population)| Swatch { color: Rgb(color), population })
+        .collect();
+    let pixel_count = swatches.iter().map(|swatch| swatch.population).sum();
+    ColorPalette { swatches, pixel_count }
+}
+
+/// Fails when any of the three values is further from the expected one
+/// than the tolerance
+pub fn assert_close(expected: [f64; 3], found: [f64; 3], tolerance: f64) {
+    for i in 0..3 {
+        assert!(
+            (expected[i] - found[i]).abs() <= tolerance,
+            "Logic Error: expected {:?}, found {:?}", expected, found,
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt::Write;
+use image::Rgb;
+use crate::color::{ self, Hsl, rgb_to_hsl, hsl_to_rgb };
+use crate::data_models::{ ColorPalette, Swatch };
+
+
+/// Number of colors to quantize an image into before assigning the 16
+/// ANSI slots, more colors give more hues to choose from
+pub const THEME_COLOR_COUNT: usize = 32;
+
+/// Minimum contrast ratio between foreground and background (WCAG AAA)
+const FOREGROUND_CONTRAST: f64 = 7.0;
+/// Minimum contrast ratio between the ANSI hues and the background
+const ANSI_CONTRAST: f64 = 3.0;
+/// Hues further than this from every swatch are synthesized instead
+const MAX_HUE_DISTANCE: f64 = 30.0;
+
+/// ANSI color names in slot order, bright variants are offset by 8
+pub const ANSI_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+/// Target hue in degrees for the chromatic slots 1 to 6
+const ANSI_HUES: [f64; 6] = [0.0, 120.0, 60.0, 240.0, 300.0, 180.0];
+
+/// A terminal color scheme, `ansi` holds the 8 normal colors followed by
+/// their 8 bright variants
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct TerminalTheme {
+    pub name: String,
+    pub background: Rgb<u8>,
+    pub foreground: Rgb<u8>,
+    pub ansi: [Rgb<u8>; 16],
+}
+
+/// Assigns the ANSI slots from a palette. The dominant color decides
+/// between a dark and a light theme and becomes the background. Every
+/// chromatic slot takes the swatch closest in hue with its lightness kept
+/// in a readable range, hues missing from the palette are synthesized.
+/// Control characters are dropped from the name, which ends up in comments.
+pub fn generate_theme(palette: &ColorPalette, name: &str) -> TerminalTheme {
+    let hsl_swatches: Vec<(Hsl, &Swatch)> = palette.swatches.iter()
+        .map(|swatch| (rgb_to_hsl(swatch.color), swatch))
+        .collect();
+
+    // Dominant color decides the background, gray when there is none
+    let dominant = hsl_swatches.first()
+        .map(|(hsl, _)| *hsl)
+        .unwrap_or(Hsl { h: 0.0, s: 0.0, l: 0.0 });
+    let is_dark = dominant.l < 0.5;
+    let background = Hsl {
+        s: dominant.s.min(0.3),
+        l: if is_dark { dominant.l.min(0.12) } else { dominant.l.max(0.92) },
+        ..dominant
+    };
+
+    // Foreground is the color at the other end of the lightness range
+    let by_lightness = |a: &&(Hsl, &Swatch), b: &&(Hsl, &Swatch)| a.0.l.total_cmp(&b.0.l);
+    let opposite = if is_dark {
+        hsl_swatches.iter().max_by(by_lightness)
+    } else {
+        hsl_swatches.iter().min_by(by_lightness)
+    };
+    let opposite = opposite.map(|(hsl, _)| *hsl).unwrap_or(dominant);
+    let background_rgb = hsl_to_rgb(background);
+    let foreground = ensure_contrast(
+        Hsl { s: opposite.s.min(0.2), ..opposite }, background_rgb, FOREGROUND_CONTRAST,
+    );
+
+    let (dark_base, light_base) = if is_dark {
+        (background, rgb_to_hsl(foreground))
+    } else {
+        (rgb_to_hsl(foreground), background)
+    };
+
+    let chromatic: Vec<Hsl> = hsl_swatches.iter()
+        .map(|(hsl, _)| *hsl)
+        .filter(|hsl| hsl.s >= 0.15 && hsl.l >= 0.15 && hsl.l <= 0.85)
+        .collect();
+    let mean_saturation = match chromatic.len() {
+        0 => 0.5,
+        len => chromatic.iter().map(|hsl| hsl.s).sum::<f64>() / len as f64,
+    };
+
+    let mut ansi = [Rgb([0, 0, 0]); 16];
+    ansi[0] = hsl_to_rgb(Hsl { l: 0.15, ..dark_base });
+    ansi[8] = hsl_to_rgb(Hsl { l: 0.40, ..dark_base });
+    ansi[7] = hsl_to_rgb(Hsl { l: 0.80, ..light_base });
+    ansi[15] = hsl_to_rgb(Hsl { l: 0.95, ..light_base });
+    for (i, &target_hue) in ANSI_HUES.iter().enumerate() {
+        let closest = chromatic.iter()
+            .map(|hsl| (hue_distance(hsl.h, target_hue), hsl))
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        let normal = match closest {
+            Some((distance, hsl)) if distance <= MAX_HUE_DISTANCE => Hsl {
+                h: hsl.h,
+                s: hsl.s.max(0.4),
+                l: hsl.l.clamp(0.4, 0.6),
+            },
+            _ => Hsl { h: target_hue, s: mean_saturation.max(0.4), l: 0.5 },
+        };
+        ansi[i + 1] = ensure_contrast(normal, background_rgb, ANSI_CONTRAST);
+        // Bright variant starts from the readable normal color
+        let normal = rgb_to_hsl(ansi[i + 1]);
+        let bright = Hsl { l: (normal.l + 0.15).min(0.85), ..normal };
+        ansi[i + 9] = ensure_contrast(bright, background_rgb, ANSI_CONTRAST);
+    }
+
+    TerminalTheme {
+        name: name.chars().filter(|c| !c.is_control()).collect(),
+        background: background_rgb,
+        foreground,
+        ansi,
+    }
+}
+
+/// Formats a theme as a base16 scheme. base00 to base07 are a ramp from
+/// background to foreground, base08 to base0F are the accent hues.
+pub fn format_base16(theme: &TerminalTheme) -> String {
+    let ramp = |t: f64| hex_digits(mix(theme.background, theme.foreground, t));
+    let orange = mix(theme.ansi[1], theme.ansi[3], 0.5);
+    let brown = mix(theme.ansi[1], theme.background, 0.4);
+    let bases = [
+        hex_digits(theme.background), ramp(0.1), ramp(0.2), ramp(0.4),
+        ramp(0.6), hex_digits(theme.foreground), ramp(1.05), ramp(1.1),
+        hex_digits(theme.ansi[1]), hex_digits(orange),
+        hex_digits(theme.ansi[3]), hex_digits(theme.ansi[2]),
+        hex_digits(theme.ansi[6]), hex_digits(theme.ansi[4]),
+        hex_digits(theme.ansi[5]), hex_digits(brown),
+    ];
+    let mut yaml = format!(
+        "scheme: \"{}\"\nauthor: \"color-thief-rs\"\n", theme.name.replace(['"', '\\'], "'")
+    );
+    for (i, base) in bases.iter().enumerate() {
+        // Writing to a String cannot fail
+        let _ = writeln!(yaml, "base0{:X}: \"{}\"", i, base);
+    }
+    yaml
+}
+
+/// Formats a theme as an Alacritty TOML color table
+pub fn format_alacritty(theme: &TerminalTheme) -> String {
+    let mut toml = format!(
+        "# {}\n[colors.primary]\nbackground = \"{}\"\nforeground = \"{}\"\n",
+        theme.name, hex(theme.background), hex(theme.foreground),
+    );
+    for (table, offset) in [("normal", 0), ("bright", 8)] {
+        let _ = write!(toml, "\n[colors.{}]\n", table);
+        for (i, name) in ANSI_NAMES.iter().enumerate() {
+            let _ = writeln!(toml, "{} = \"{}\"", name, hex(theme.ansi[i + offset]));
+        }
+    }
+    toml
+}
+
+/// Formats a theme as X resources
+pub fn format_xresources(theme: &TerminalTheme) -> String {
+    let mut resources = format!(
+        "! {}\n*.background: {}\n*.foreground: {}\n*.cursorColor: {}\n",
+        theme.name, hex(theme.background), hex(theme.foreground), hex(theme.foreground),
+    );
+    for (i, color) in theme.ansi.iter().enumerate() {
+        let _ = writeln!(resources, "*.color{}: {}", i, hex(*color));
+    }
+    resources
+}
+
+/// Formats a theme as an iTerm2 `.itermcolors` property list
+pub fn format_iterm(theme: &TerminalTheme) -> String {
+    let mut plist = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+        "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n<dict>\n",
+    ));
+    let mut entries: Vec<(String, Rgb<u8>)> = theme.ansi.iter().enumerate()
+        .map(|(i, color)| (format!("Ansi {} Color", i), *color))
+        .collect();
+    entries.push((String::from("Background Color"), theme.background));
+    entries.push((String::from("Foreground Color"), theme.foreground));
+    entries.push((String::from("Cursor Color"), theme.foreground));
+    for (key, color) in entries {
+        let [r, g, b] = color.0;
+        let _ = write!(
+            plist,
+            concat!(
+                "\t<key>{}</key>\n\t<dict>\n",
+                "\t\t<key>Alpha Component</key>\n\t\t<real>1</real>\n",
+                "\t\t<key>Blue Component</key>\n\t\t<real>{:.6}</real>\n",
+                "\t\t<key>Color Space</key>\n\t\t<string>sRGB</string>\n",
+                "\t\t<key>Green Component</key>\n\t\t<real>{:.6}</real>\n",
+                "\t\t<key>Red Component</key>\n\t\t<real>{:.6}</real>\n",
+                "\t</dict>\n",
+            ),
+            key, b as f64 / 255.0, g as f64 / 255.0, r as f64 / 255.0,
+        );
+    }
+    plist.push_str("</dict>\n</plist>\n");
+    plist
+}
+
+fn hex(color: Rgb<u8>) -> String {
+    format!("#{}", hex_digits(color))
+}
+
+fn hex_digits(color: Rgb<u8>) -> String {
+    let [r, g, b] = color.0;
+    format!("{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Linear blend between two colors, `t` outside of 0 to 1 extrapolates
+fn mix(from: Rgb<u8>, to: Rgb<u8>, t: f64) -> Rgb<u8> {
+    let blend = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round().clamp(0.0, 255.0) as u8;
+    Rgb([
+        blend(from.0[0], to.0[0]),
+        blend(from.0[1], to.0[1]),
+        blend(from.0[2], to.0[2]),
+    ])
+}
+
+/// Moves the lightness of a color away from `against` until the contrast
+/// ratio is met or the lightness runs out. Backgrounds are clamped close
+/// to black or white, so even a ratio of 7 is always reachable.
+fn ensure_contrast(color: Hsl, against: Rgb<u8>, min_ratio: f64) -> Rgb<u8> {
+    let step = if relative_luminance(against) < 0.18 { 0.01 } else { -0.01 };
+    let mut color = color;
+    let mut rgb = hsl_to_rgb(color);
+    while contrast_ratio(rgb, against) < min_ratio && (0.0..=1.0).contains(&(color.l + step)) {
+        color.l += step;
+        rgb = hsl_to_rgb(color);
+    }
+    rgb
+}
+
+fn hue_distance(a: f64, b: f64) -> f64 {
+    let distance = (a - b).abs() % 360.0;
+    distance.min(360.0 - distance)
+}
+
+/// WCAG relative luminance of an sRGB color
+fn relative_luminance(color: Rgb<u8>) -> f64 {
+    let [r, g, b] = color.0.map(color::srgb_to_linear);
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// WCAG contrast ratio, from 1 for equal colors to 21 for black on white
+fn contrast_ratio(a: Rgb<u8>, b: Rgb<u8>) -> f64 {
+    let (la, lb) = (relative_luminance(a), relative_luminance(b));
+    (la.max(lb) + 0.05) / (la.min(lb) + 0.05)
+}
+
+
+#[cfg(test)]
+mod test_theme {
+    use super::*;
+
+    fn palette(colors: &[[u8; 3]]) -> ColorPalette {
+        let swatches: Vec<Swatch> = colors.iter().enumerate()
+            .map(|(i, &color)| Swatch {
+                color: Rgb(color),
+                population: (colors.len() - i) as u32,
+            })
+            .collect();
+        let pixel_count = swatches.iter().map(|swatch| swatch.population).sum();
+        ColorPalette { swatches, pixel_count }
+    }
+
+    #[test]
+    fn test_contrast_ratio() {
+        let found = contrast_ratio(Rgb([0, 0, 0]), Rgb([255, 255, 255]));
+        assert!((found - 21.0).abs() < 1e-9, "Logic Error:");
+        let found = contrast_ratio(Rgb([118, 118, 118]), Rgb([255, 255, 255]));
+        assert!((found - 4.54).abs() < 0.01, "Logic Error:");
+    }
+
+    #[test]
+    fn test_generate_theme_dark() {
+        let found = generate_theme(
+            &palette(&[[20, 30, 40], [200, 40, 30], [40, 160, 60], [230, 230, 210]]),
+            "dark",
+        );
+        assert!(relative_luminance(found.background) < 0.18, "Logic Error: dark background");
+        assert!(
+            contrast_ratio(found.foreground, found.background) >= FOREGROUND_CONTRAST,
+            "Logic Error: foreground contrast",
+        );
+        // Red and green come from the palette, blue is synthesized
+        let red = rgb_to_hsl(found.ansi[1]);
+        let green = rgb_to_hsl(found.ansi[2]);
+        let blue = rgb_to_hsl(found.ansi[4]);
+        assert!(hue_distance(red.h, rgb_to_hsl(Rgb([200, 40, 30])).h) < 1.0, "Logic Error: red");
+        assert!(hue_distance(green.h, rgb_to_hsl(Rgb([40, 160, 60])).h) < 1.0, "Logic Error: green");
+        assert!(hue_distance(blue.h, 240.0) < 1.0, "Logic Error: blue");
+        for color in found.ansi[1..7].iter().chain(&found.ansi[9..15]) {
+            assert!(
+                contrast_ratio(*color, found.background) >= ANSI_CONTRAST,
+                "Logic Error: ANSI contrast",
+            );
+        }
+    }
+
+    #[test]
+    fn test_generate_theme_light() {
+        let found = generate_theme(&palette(&[[250, 250, 250], [255, 255, 240]]), "light");
+        assert!(relative_luminance(found.background) > 0.18, "Logic Error: light background");
+        assert!(
+            contrast_ratio(found.foreground, found.background) >= FOREGROUND_CONTRAST,
+            "Logic Error: foreground contrast",
+        );
+        let found = generate_theme(&palette(&[]), "empty\nline\r");
+        assert_eq!("emptyline", found.name, "Logic Error: control characters");
+        assert!(
+            contrast_ratio(found.foreground, found.background) >= FOREGROUND_CONTRAST,
+            "Logic Error: empty palette",
+        );
+    }
+
+    #[test]
+    fn test_theme_formats() {
+        let theme = generate_theme(&palette(&[[20, 30, 40], [200, 40, 30]]), "sample");
+        let base16 = format_base16(&theme);
+        assert!(base16.starts_with("scheme: \"sample\"\n"), "Logic Error: base16");
+        assert!(base16.contains("base0F: \""), "Logic Error: base16");
+        assert_eq!(18, base16.lines().count(), "Logic Error: base16");
+        let quoted = generate_theme(&palette(&[]), "a\"b\\c");
+        assert!(format_base16(&quoted).starts_with("scheme: \"a'b'c\"\n"), "Logic Error: base16 quotes");
+
+        let alacritty = format_alacritty(&theme);
+        let expected = format!("[colors.bright]\nblack = \"{}\"\n", hex(theme.ansi[8]));
+        assert!(alacritty.contains(&expected), "Logic Error: alacritty");
+
+        let xresources = format_xresources(&theme);
+        let expected = format!("*.color15: {}\n", hex(theme.ansi[15]));
+        assert!(xresources.ends_with(&expected), "Logic Error: xresources");
+
+        let iterm = format_iterm(&theme);
+        assert_eq!(19, iterm.matches("<key>Red Component</key>").count(), "Logic Error: iterm");
+        assert!(iterm.contains("<key>Ansi 15 Color</key>"), "Logic Error: iterm");
+    }
+}
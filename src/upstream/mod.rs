@@ -0,0 +1,571 @@
+use std::str::FromStr;
+use image::{ Rgb, Rgba };
+use crate::data_models::{ ColorPalette, Swatch };
+use crate::error::Error;
+
+
+/// Significant bits per channel
+const SIGBITS: i32 = 5;
+/// Bits dropped when binning a channel
+const RSHIFT: i32 = 8 - SIGBITS;
+/// Iterations after which a phase gives up on reaching its target
+const MAX_ITERATION: usize = 1000;
+/// Share of the colors found in the first phase, split by pixel count
+const FRACT_BY_POPULATIONS: f64 = 0.75;
+
+/// Reference implementation whose palettes are reproduced exactly, quirks
+/// included. The corrected default of `mmcq` differs from both in the
+/// median rounding, empty boxes and the number of colors.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum Upstream {
+    /// `MMCQ` of color-thief-py, whose second phase counts its colors from 1
+    /// so palettes often have a color less, or with 2 colors one more,
+    /// than asked for
+    ColorThiefPy,
+    /// lokesh/quantize, the MMCQ of Color Thief in the browser and Node.js
+    QuantizeJs,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "color-thief-py" | "colorthief" => Ok(Upstream::ColorThiefPy),
+            "quantize-js" | "quantize" => Ok(Upstream::QuantizeJs),
+            _ => Err(format!("Unknown upstream implementation: {}", s)),
+        }
+    }
+}
+
+/// Palette exactly as upstream returns it
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct UpstreamPalette {
+    pub swatches: Vec<UpstreamSwatch>,
+    /// Sum of every swatch population
+    pub pixel_count: u32,
+}
+
+/// Color of a box with the number of sampled pixels inside it. Channels
+/// are the truncated upstream averages, which reach 256 for an empty box
+/// past the last bin.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct UpstreamSwatch {
+    pub color: [i32; 3],
+    pub population: u32,
+}
+
+impl UpstreamPalette {
+    /// Palette with every channel saturated to 0..=255
+    pub fn to_color_palette(&self) -> ColorPalette {
+        let swatches = self.swatches.iter()
+            .map(|swatch| Swatch {
+                color: Rgb(swatch.color.map(|channel| channel.clamp(0, 255) as u8)),
+                population: swatch.population,
+            })
+            .collect();
+        ColorPalette { swatches, pixel_count: self.pixel_count }
+    }
+}
+
+/// Samples every `quality`-th pixel and keeps the mostly opaque ones that
+/// are not white, like `get_palette` of every Color Thief port
+pub fn sample_pixels<'a>(pixels: impl IntoIterator<Item = &'a Rgba<u8>>, quality: usize) -> Vec<Rgb<u8>> {
+    pixels.into_iter()
+        .step_by(quality.max(1))
+        .filter(|pixel| pixel[3] >= 125 && !(pixel[0] > 250 && pixel[1] > 250 && pixel[2] > 250))
+        .map(|pixel| Rgb([pixel[0], pixel[1], pixel[2]]))
+        .collect()
+}
+
+/// Quantizes sampled pixels exactly like `upstream`. Swatches keep the
+/// upstream order, by pixel count times volume, and empty boxes stay in
+/// the palette with a population of 0.
+///
+/// Empty pixels give an empty palette where color-thief-py raises and
+/// quantize.js returns `false`.
+pub fn quantize(pixels: &[Rgb<u8>], color_count: usize, upstream: Upstream) -> Result<UpstreamPalette, Error> {
+    if !(2..=256).contains(&color_count) {
+        return Err(Error::Unsupported(format!("cannot quantize to {} colors", color_count)));
+    }
+    if pixels.len() > u32::MAX as usize {
+        return Err(Error::TooManyPixels);
+    }
+    if pixels.is_empty() {
+        return Ok(UpstreamPalette { swatches: Vec::new(), pixel_count: 0 });
+    }
+
+    let histo = Histo::new(pixels);
+    let vbox = VBox::from_pixels(pixels, &histo, upstream);
+    let mut pq = PQueue::new(|vbox: &VBox| vbox.count);
+    pq.push(vbox);
+    let target = FRACT_BY_POPULATIONS * color_count as f64;
+    iterate(&histo, &mut pq, target, upstream);
+
+    // Re-sort by pixel count times volume
+    let mut pq2 = PQueue::new(|vbox: &VBox| vbox.count * vbox.volume());
+    while let Some(vbox) = pq.pop() {
+        pq2.push(vbox);
+    }
+    let target = match upstream {
+        Upstream::ColorThiefPy => color_count as f64 - pq2.size() as f64,
+        Upstream::QuantizeJs => color_count as f64,
+    };
+    iterate(&histo, &mut pq2, target, upstream);
+
+    let mut swatches = Vec::with_capacity(pq2.size());
+    while let Some(vbox) = pq2.pop() {
+        swatches.push(UpstreamSwatch { color: vbox.avg(&histo), population: vbox.count as u32 });
+    }
+    // Pixels quantize.js left out of every box are not counted
+    let pixel_count = swatches.iter().map(|swatch| swatch.population).sum();
+    Ok(UpstreamPalette { swatches, pixel_count })
+}
+
+/// Pixel count of every bin
+struct Histo(Vec<u32>);
+
+impl Histo {
+    fn new(pixels: &[Rgb<u8>]) -> Histo {
+        let mut histo = vec![0; 1 << (3 * SIGBITS)];
+        for pixel in pixels {
+            let [r, g, b] = pixel.0.map(|channel| channel as i32 >> RSHIFT);
+            histo[color_index(r, g, b)] += 1;
+        }
+        Histo(histo)
+    }
+
+    fn get(&self, r: i32, g: i32, b: i32) -> u64 {
+        self.0[color_index(r, g, b)] as u64
+    }
+}
+
+fn color_index(r: i32, g: i32, b: i32) -> usize {
+    ((r << (2 * SIGBITS)) + (g << SIGBITS) + b) as usize
+}
+
+/// Inclusive ranges of bins. A cut may leave `r1 > r2` and alike, an empty
+/// box with a volume of 0 that upstream keeps.
+#[derive(Clone)]
+#[derive(Debug)]
+struct VBox {
+    r1: i32,
+    r2: i32,
+    g1: i32,
+    g2: i32,
+    b1: i32,
+    b2: i32,
+    count: u64,
+}
+
+impl VBox {
+    /// quantize.js only raises the maximum when the minimum stays, so the
+    /// box can miss pixels or turn out empty depending on the pixel order
+    fn from_pixels(pixels: &[Rgb<u8>], histo: &Histo, upstream: Upstream) -> VBox {
+        let (mut min, mut max) = ([1000000; 3], [0; 3]);
+        for pixel in pixels {
+            for (i, &channel) in pixel.0.iter().enumerate() {
+                let value = channel as i32 >> RSHIFT;
+                match upstream {
+                    Upstream::ColorThiefPy => {
+                        min[i] = min[i].min(value);
+                        max[i] = max[i].max(value);
+                    },
+                    Upstream::QuantizeJs => {
+                        if value < min[i] {
+                            min[i] = value;
+                        } else if value > max[i] {
+                            max[i] = value;
+                        }
+                    },
+                }
+            }
+        }
+        VBox::new([min[0], max[0], min[1], max[1], min[2], max[2]], histo)
+    }
+
+    fn new([r1, r2, g1, g2, b1, b2]: [i32; 6], histo: &Histo) -> VBox {
+        let mut vbox = VBox { r1, r2, g1, g2, b1, b2, count: 0 };
+        vbox.count = vbox.bins().map(|(r, g, b)| histo.get(r, g, b)).sum();
+        vbox
+    }
+
+    fn bounds(&self) -> [i32; 6] {
+        [self.r1, self.r2, self.g1, self.g2, self.b1, self.b2]
+    }
+
+    fn volume(&self) -> u64 {
+        let width = |lower: i32, upper: i32| (upper - lower + 1).max(0) as u64;
+        width(self.r1, self.r2) * width(self.g1, self.g2) * width(self.b1, self.b2)
+    }
+
+    /// Every bin in red, green, blue loop order
+    fn bins(&self) -> impl Iterator<Item = (i32, i32, i32)> + '_ {
+        (self.r1..=self.r2).flat_map(move |r| {
+            (self.g1..=self.g2).flat_map(move |g| (self.b1..=self.b2).map(move |b| (r, g, b)))
+        })
+    }
+
+    /// Average of the bin centers weighted by count, or the center of the
+    /// box when it is empty. The float sums add up in the upstream order.
+    fn avg(&self, histo: &Histo) -> [i32; 3] {
+        let mult = (1 << RSHIFT) as f64;
+        let (mut ntot, mut sums) = (0, [0.0; 3]);
+        for (r, g, b) in self.bins() {
+            let hval = histo.get(r, g, b);
+            ntot += hval;
+            for (sum, i) in sums.iter_mut().zip([r, g, b]) {
+                *sum += hval as f64 * (i as f64 + 0.5) * mult;
+            }
+        }
+        let [r1, r2, g1, g2, b1, b2] = self.bounds();
+        match ntot {
+            0 => [r1 + r2, g1 + g2, b1 + b2].map(|sum| (mult * (sum + 1) as f64 / 2.0) as i32),
+            _ => sums.map(|sum| (sum / ntot as f64) as i32),
+        }
+    }
+}
+
+/// Priority queue sorted lazily on pop by a stable ascending sort, so ties
+/// keep the upstream push order
+struct PQueue<F: Fn(&VBox) -> u64> {
+    contents: Vec<VBox>,
+    sorted: bool,
+    sort_key: F,
+}
+
+impl<F: Fn(&VBox) -> u64> PQueue<F> {
+    fn new(sort_key: F) -> PQueue<F> {
+        PQueue { contents: Vec::new(), sorted: false, sort_key }
+    }
+
+    fn push(&mut self, vbox: VBox) {
+        self.contents.push(vbox);
+        self.sorted = false;
+    }
+
+    fn pop(&mut self) -> Option<VBox> {
+        if !self.sorted {
+            self.contents.sort_by_key(|vbox| (self.sort_key)(vbox));
+            self.sorted = true;
+        }
+        self.contents.pop()
+    }
+
+    fn size(&self) -> usize {
+        self.contents.len()
+    }
+}
+
+/// Splits the most important box until the target is reached, with the
+/// loop bookkeeping of each upstream
+fn iterate<F: Fn(&VBox) -> u64>(histo: &Histo, lh: &mut PQueue<F>, target: f64, upstream: Upstream) {
+    match upstream {
+        Upstream::ColorThiefPy => {
+            // Counts from 1 however many boxes the queue holds, and checks
+            // the target only after a cut
+            let mut n_color = 1;
+            let mut n_iter = 0;
+            while n_iter < MAX_ITERATION {
+                let Some(vbox) = lh.pop() else { return };
+                if vbox.count == 0 {
+                    lh.push(vbox);
+                    n_iter += 1;
+                    continue;
+                }
+                let (vbox1, vbox2) = median_cut_apply(histo, &vbox);
+                lh.push(vbox1);
+                if let Some(vbox2) = vbox2 {
+                    lh.push(vbox2);
+                    n_color += 1;
+                }
+                if n_color as f64 >= target {
+                    return;
+                }
+                n_iter += 1;
+            }
+        },
+        Upstream::QuantizeJs => {
+            let mut ncolors = lh.size();
+            let mut niters = 0;
+            while niters < MAX_ITERATION {
+                if ncolors as f64 >= target {
+                    return;
+                }
+                niters += 1;
+                let Some(vbox) = lh.pop() else { return };
+                if vbox.count == 0 {
+                    lh.push(vbox);
+                    niters += 1;
+                    continue;
+                }
+                let (vbox1, vbox2) = median_cut_apply(histo, &vbox);
+                lh.push(vbox1);
+                if let Some(vbox2) = vbox2 {
+                    lh.push(vbox2);
+                    ncolors += 1;
+                }
+            }
+        },
+    }
+}
+
+/// Cuts the box along its widest dimension, red winning ties over green
+/// and green over blue. A box holding a single pixel comes back uncut.
+fn median_cut_apply(histo: &Histo, vbox: &VBox) -> (VBox, Option<VBox>) {
+    if vbox.count == 1 {
+        return (vbox.clone(), None);
+    }
+    let [r1, r2, g1, g2, b1, b2] = vbox.bounds();
+    let (rw, gw, bw) = (r2 - r1 + 1, g2 - g1 + 1, b2 - b1 + 1);
+    let maxw = rw.max(gw).max(bw);
+    // Index of the lower and upper bound of the cut dimension
+    let dim = match maxw {
+        _ if maxw == rw => 0,
+        _ if maxw == gw => 2,
+        _ => 4,
+    };
+    let bounds = vbox.bounds();
+    let (dim1, dim2) = (bounds[dim], bounds[dim + 1]);
+
+    // Pixels up to and including every plane of the cut dimension
+    let mut total = 0;
+    let mut partialsum = Vec::with_capacity((dim2 - dim1 + 1) as usize);
+    for plane in dim1..=dim2 {
+        let mut plane_box = bounds;
+        plane_box[dim] = plane;
+        plane_box[dim + 1] = plane;
+        total += VBox::new(plane_box, histo).count;
+        partialsum.push(total);
+    }
+    // Missing planes read as 0 like the sparse upstream lookups
+    let partial = |d: i32| match d < dim1 || d > dim2 {
+        true => 0,
+        false => partialsum[(d - dim1) as usize],
+    };
+    let lookahead = |d: i32| match d < dim1 || d > dim2 {
+        true => 0,
+        false => total - partialsum[(d - dim1) as usize],
+    };
+
+    // First plane past half of the pixels, the last plane at the latest
+    let i = (dim1..=dim2).find(|&i| partial(i) as f64 > total as f64 / 2.0).unwrap_or(dim2);
+    let left = i - dim1;
+    let right = dim2 - i;
+    // The float is truncated toward zero, not rounded
+    let mut d2 = match left <= right {
+        true => (dim2 - 1).min((i as f64 + right as f64 / 2.0) as i32),
+        false => dim1.max((i as f64 - 1.0 - left as f64 / 2.0) as i32),
+    };
+    // Avoid 0-count boxes
+    while partial(d2) == 0 {
+        d2 += 1;
+    }
+    while lookahead(d2) == 0 && partial(d2 - 1) != 0 {
+        d2 -= 1;
+    }
+
+    let mut bounds1 = bounds;
+    bounds1[dim + 1] = d2;
+    let mut bounds2 = bounds;
+    bounds2[dim] = d2 + 1;
+    (VBox::new(bounds1, histo), Some(VBox::new(bounds2, histo)))
+}
+
+
+#[cfg(test)]
+mod test_upstream {
+    use super::*;
+    use serde_json::Value;
+
+    fn palette_colors(palette: &UpstreamPalette) -> Vec<[i32; 3]> {
+        palette.swatches.iter().map(|swatch| swatch.color).collect()
+    }
+
+    fn golden(name: &str) -> Value {
+        let path = format!("{}/tests/golden/{}", env!("CARGO_MANIFEST_DIR"), name);
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn golden_pixels(case: &Value) -> Vec<Rgb<u8>> {
+        case["pixels"].as_array().unwrap().iter()
+            .map(|pixel| Rgb([0, 1, 2].map(|i| pixel[i].as_u64().unwrap() as u8)))
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_pixels() {
+        let pixels = [
+            Rgba([10, 20, 30, 255]),
+            Rgba([1, 2, 3, 255]),
+            Rgba([40, 50, 60, 125]),
+            Rgba([40, 50, 60, 124]),
+            Rgba([251, 251, 251, 255]),
+            Rgba([251, 250, 251, 255]),
+        ];
+        let expected = vec![Rgb([10, 20, 30]), Rgb([1, 2, 3]), Rgb([40, 50, 60]), Rgb([251, 250, 251])];
+        assert_eq!(expected, sample_pixels(&pixels, 1), "Logic Error: filter");
+        let expected = vec![Rgb([10, 20, 30]), Rgb([40, 50, 60])];
+        assert_eq!(expected, sample_pixels(&pixels, 2), "Logic Error: quality");
+    }
+
+    #[test]
+    fn test_median_cut_apply() {
+        // 3 pixels at red bin 0 and 2 at red bin 5, the cut truncates
+        // 0 + 5 / 2 to plane 2 where the corrected default rounds up to 3
+        let pixels: Vec<Rgb<u8>> = [0, 0, 0, 40, 40].iter().map(|&r| Rgb([r, 0, 0])).collect();
+        let histo = Histo::new(&pixels);
+        let (vbox1, vbox2) = median_cut_apply(&histo, &VBox::from_pixels(&pixels, &histo, Upstream::ColorThiefPy));
+        let vbox2 = vbox2.unwrap();
+        assert_eq!([0, 2, 0, 0, 0, 0], vbox1.bounds(), "Logic Error: lower box");
+        assert_eq!([3, 5, 0, 0, 0, 0], vbox2.bounds(), "Logic Error: upper box");
+        assert_eq!((3, 2), (vbox1.count, vbox2.count), "Logic Error: counts");
+
+        // A single filled bin is cut into itself and an empty box past it
+        let pixels = vec![Rgb([0, 0, 0]); 3];
+        let histo = Histo::new(&pixels);
+        let (vbox1, vbox2) = median_cut_apply(&histo, &VBox::from_pixels(&pixels, &histo, Upstream::ColorThiefPy));
+        let vbox2 = vbox2.unwrap();
+        assert_eq!([0, 0, 0, 0, 0, 0], vbox1.bounds(), "Logic Error: filled box");
+        assert_eq!((1, 0, 0), (vbox2.r1, vbox2.r2, vbox2.volume()), "Logic Error: empty box");
+        assert_eq!([8, 4, 4], vbox2.avg(&histo), "Logic Error: empty box average");
+    }
+
+    #[test]
+    fn test_quantize() {
+        // One opaque black image, upstream pads the palette with empty boxes
+        let pixels = vec![Rgb([0, 0, 0]); 16];
+        let palette = quantize(&pixels, 5, Upstream::ColorThiefPy).unwrap();
+        let expected = vec![[4, 4, 4], [8, 4, 4], [8, 4, 4], [8, 4, 4], [8, 4, 4]];
+        assert_eq!(expected, palette_colors(&palette), "Logic Error: color-thief-py colors");
+        assert_eq!(16, palette.swatches[0].population, "Logic Error: population");
+        assert_eq!(16, palette.pixel_count, "Logic Error: pixel count");
+
+        // The empty box past the last bin averages to 256
+        let pixels = vec![Rgb([249, 249, 249]); 4];
+        let palette = quantize(&pixels, 2, Upstream::QuantizeJs).unwrap();
+        assert_eq!(vec![[252, 252, 252], [256, 252, 252]], palette_colors(&palette), "Logic Error: last bin");
+        let expected = vec![Rgb([252, 252, 252]), Rgb([255, 252, 252])];
+        let found: Vec<_> = palette.to_color_palette().swatches.iter().map(|swatch| swatch.color).collect();
+        assert_eq!(expected, found, "Logic Error: saturated");
+
+        let found = quantize(&[], 5, Upstream::QuantizeJs).unwrap();
+        assert!(found.swatches.is_empty(), "Logic Error: empty pixels");
+        for color_count in [1, 257] {
+            let found = quantize(&pixels, color_count, Upstream::QuantizeJs);
+            assert!(matches!(found, Err(Error::Unsupported(_))), "Logic Error: color count");
+        }
+        assert_eq!(Ok(Upstream::QuantizeJs), "quantize-js".parse(), "Logic Error: parse");
+    }
+
+    /// Pseudo random channels from a fixed xorshift seed
+    fn random_channels(state: &mut u32, max: u8) -> [u8; 3] {
+        [0; 3].map(|_| {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            (*state % (max as u32 + 1)) as u8
+        })
+    }
+
+    /// Writes the sampled pixels of every golden case, run with
+    /// `cargo test write_golden_cases -- --ignored` and then regenerate the
+    /// reference palettes with the scripts in tests/golden
+    #[test]
+    #[ignore]
+    fn write_golden_cases() {
+        let mut cases: Vec<(String, String, Vec<[u8; 3]>)> = Vec::new();
+        for name in ["12colors.png", "12colors.jpg", "000000FF.png"] {
+            let path = format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name);
+            let bytes = std::fs::read(path).unwrap();
+            let (img, _) = crate::img_io::load_img_with_profile(&bytes, &Default::default()).unwrap();
+            let pixels = sample_pixels(img.to_rgba8().pixels(), 1).iter().map(|pixel| pixel.0).collect();
+            cases.push((name.into(), format!("tests/data/{} at quality 1", name), pixels));
+        }
+
+        let mut state = 0x2545f491;
+        let mut synthetic = |name: &str, source: &str, pixels: Vec<[u8; 3]>| {
+            cases.push((name.into(), source.into(), pixels));
+        };
+        let pixels = (0..1000).map(|_| random_channels(&mut state, 255)).collect();
+        synthetic("noise", "uniform random colors", pixels);
+        let centers: [[u8; 3]; 6] = [[230, 40, 40], [40, 180, 60], [30, 60, 200], [240, 200, 40], [120, 40, 160], [20, 20, 20]];
+        let pixels = centers.iter().zip([400, 250, 150, 100, 60, 40])
+            .flat_map(|(center, size)| (0..size).map(|_| center).collect::<Vec<_>>())
+            .map(|center| {
+                let offset = random_channels(&mut state, 32);
+                [0, 1, 2].map(|i| (center[i] as i32 + offset[i] as i32 - 16).clamp(0, 255) as u8)
+            })
+            .collect();
+        synthetic("clusters", "six noisy clusters of decreasing size", pixels);
+        let pixels = (0..=255).map(|i| [i, 255 - i, 128]).collect();
+        synthetic("gradient", "red to green ramp", pixels);
+        let pixels = (0..=250).flat_map(|i| [[i, i, i]; 2]).collect();
+        synthetic("grayscale", "gray ramp short of the white filter", pixels);
+        let mut pixels = vec![[200, 60, 30]; 950];
+        pixels.extend((0..50).map(|_| random_channels(&mut state, 255)));
+        synthetic("dominant", "one color and a little noise", pixels);
+        let mut pixels = vec![[220, 20, 60]; 300];
+        pixels.extend([[30, 144, 255]; 200]);
+        synthetic("two-colors", "two flat colors", pixels);
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0], [0, 255, 255], [255, 0, 255], [128, 128, 128]];
+        let pixels = colors.iter().zip([1, 2, 3, 5, 8, 13, 21])
+            .flat_map(|(&color, count)| vec![color; count])
+            .collect();
+        synthetic("few-colors", "seven colors with Fibonacci counts", pixels);
+        synthetic("single-pixel", "one pixel", vec![[10, 200, 30]]);
+        let pixels = (0..800).map(|_| random_channels(&mut state, 24)).collect();
+        synthetic("dark", "random colors below 25", pixels);
+        synthetic("near-white", "one color in the last bin", vec![[249, 249, 249]; 40]);
+
+        let cases: Vec<String> = cases.iter()
+            .map(|(name, source, pixels)| {
+                let pixels: Vec<String> = pixels.iter()
+                    .map(|[r, g, b]| format!("[{},{},{}]", r, g, b))
+                    .collect();
+                format!("    {:?}: {{\"source\": {:?}, \"pixels\": [{}]}}", name, source, pixels.join(","))
+            })
+            .collect();
+        let text = format!(
+            "{{\n  \"color_counts\": [2, 3, 4, 5, 8, 10, 16, 20],\n  \"cases\": {{\n{}\n  }}\n}}\n",
+            cases.join(",\n"),
+        );
+        std::fs::create_dir_all(format!("{}/tests/golden", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        std::fs::write(format!("{}/tests/golden/cases.json", env!("CARGO_MANIFEST_DIR")), text).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs tests/golden/color_thief_py.json and quantize_js.json, see the README"]
+    fn test_golden() {
+        let cases = golden("cases.json");
+        let cases = cases["cases"].as_object().unwrap();
+        for (name, upstream) in [
+            ("color_thief_py.json", Upstream::ColorThiefPy),
+            ("quantize_js.json", Upstream::QuantizeJs),
+        ] {
+            let expected = golden(name);
+            let palettes = expected["palettes"].as_object().unwrap();
+            assert_eq!(cases.len(), palettes.len(), "Logic Error: {} cases", name);
+            for (case_name, case) in cases {
+                let pixels = golden_pixels(case);
+                for (color_count, colors) in palettes[case_name].as_object().unwrap() {
+                    let expected: Vec<[i32; 3]> = colors.as_array().unwrap().iter()
+                        .map(|color| [0, 1, 2].map(|i| color[i].as_i64().unwrap() as i32))
+                        .collect();
+                    let found = quantize(&pixels, color_count.parse().unwrap(), upstream).unwrap();
+                    assert_eq!(
+                        expected, palette_colors(&found),
+                        "Logic Error: {} {} with {} colors", name, case_name, color_count,
+                    );
+                }
+            }
+        }
+    }
+}
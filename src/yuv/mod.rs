@@ -0,0 +1,445 @@
+use std::io::{ self, Read };
+use std::str::FromStr;
+use image::Rgba;
+use crate::data_models::{ ColorPalette, QuantizeOptions };
+use crate::error::Error;
+use crate::img_io::DecodeLimits;
+use crate::mmcq::{ self, MMCQ };
+
+
+/// Signature starting every YUV4MPEG2 stream
+pub const Y4M_MAGIC: &[u8] = b"YUV4MPEG2";
+/// Longest stream or frame header read before giving up
+const MAX_HEADER_LEN: usize = 1024;
+
+/// Plane layout of an 8-bit 4:2:0 frame
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum YuvFormat {
+    /// Y plane, then U plane, then V plane
+    I420,
+    /// Y plane, then one plane of interleaved U and V
+    Nv12,
+}
+
+/// Coefficients turning luma and chroma back into RGB
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum YuvMatrix {
+    /// Standard definition video
+    Bt601,
+    /// High definition video
+    Bt709,
+}
+
+impl YuvMatrix {
+    /// Matrix players assume for untagged video, BT.709 from 720 lines up
+    pub fn guess(height: usize) -> YuvMatrix {
+        match height {
+            720.. => YuvMatrix::Bt709,
+            _ => YuvMatrix::Bt601,
+        }
+    }
+
+    /// Red and blue luma weights
+    fn weights(&self) -> (f32, f32) {
+        match self {
+            YuvMatrix::Bt601 => (0.299, 0.114),
+            YuvMatrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+impl FromStr for YuvMatrix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bt601" | "601" => Ok(YuvMatrix::Bt601),
+            "bt709" | "709" => Ok(YuvMatrix::Bt709),
+            _ => Err(format!("Unknown YUV matrix: {}", s)),
+        }
+    }
+}
+
+/// Range of the stored values
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum YuvRange {
+    /// Luma from 16 to 235, chroma from 16 to 240
+    Limited,
+    /// Every value from 0 to 255
+    Full,
+}
+
+impl FromStr for YuvRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "limited" | "tv" => Ok(YuvRange::Limited),
+            "full" | "pc" => Ok(YuvRange::Full),
+            _ => Err(format!("Unknown YUV range: {}", s)),
+        }
+    }
+}
+
+/// Converts a single sample to opaque RGB
+pub fn yuv_to_rgb(y: u8, u: u8, v: u8, matrix: YuvMatrix, range: YuvRange) -> Rgba<u8> {
+    let (luma, chroma) = match range {
+        YuvRange::Limited => ((y as f32 - 16.0) / 219.0, 224.0),
+        YuvRange::Full => (y as f32 / 255.0, 255.0),
+    };
+    let pb = (u as f32 - 128.0) / chroma;
+    let pr = (v as f32 - 128.0) / chroma;
+    let (kr, kb) = matrix.weights();
+    let r = luma + 2.0 * (1.0 - kr) * pr;
+    let b = luma + 2.0 * (1.0 - kb) * pb;
+    let g = (luma - kr * r - kb * b) / (1.0 - kr - kb);
+    let to_u8 = |val: f32| (val.clamp(0.0, 1.0) * 255.0).round() as u8;
+    Rgba([to_u8(r), to_u8(g), to_u8(b), 255])
+}
+
+/// Borrowed, tightly packed 8-bit 4:2:0 frame. Odd sizes round the chroma
+/// planes up.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct YuvFrame<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    format: YuvFormat,
+    matrix: YuvMatrix,
+    range: YuvRange,
+}
+
+impl<'a> YuvFrame<'a> {
+    /// Checks that `data` holds the luma and both chroma planes
+    pub fn new(data: &'a [u8], width: usize, height: usize, format: YuvFormat, matrix: YuvMatrix, range: YuvRange) -> Result<YuvFrame<'a>, Error> {
+        let required = frame_size(width, height)
+            .ok_or(Error::InvalidBuffer("frame size overflows".into()))?;
+        if data.len() < required {
+            return Err(Error::InvalidBuffer(format!(
+                "{} bytes given, {} bytes required", data.len(), required
+            )));
+        }
+        Ok(YuvFrame { data, width, height, format, matrix, range })
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Every pixel in row-major order, converted on the fly
+    pub fn pixels(&self) -> impl Iterator<Item = Rgba<u8>> + 'a {
+        let YuvFrame { data, width, height, format, matrix, range } = *self;
+        let chroma_width = width.div_ceil(2);
+        let chroma_size = chroma_width * height.div_ceil(2);
+        let (luma, chroma) = data.split_at(width * height);
+        (0..height).flat_map(move |y| {
+            (0..width).map(move |x| {
+                // One chroma sample covers a 2x2 block
+                let index = (y / 2) * chroma_width + x / 2;
+                let (u, v) = match format {
+                    YuvFormat::I420 => (chroma[index], chroma[chroma_size + index]),
+                    YuvFormat::Nv12 => (chroma[2 * index], chroma[2 * index + 1]),
+                };
+                yuv_to_rgb(luma[y * width + x], u, v, matrix, range)
+            })
+        })
+    }
+}
+
+/// Bytes in a 4:2:0 frame, `None` on overflow
+fn frame_size(width: usize, height: usize) -> Option<usize> {
+    let luma = width.checked_mul(height)?;
+    let chroma = width.div_ceil(2).checked_mul(height.div_ceil(2))?;
+    luma.checked_add(chroma.checked_mul(2)?)
+}
+
+/// Palette of a YUV frame through the same sampling, alpha filter, binning
+/// and MMCQ steps as decoded images. `quality` uses every n-th pixel like
+/// the CLI option, 0 is taken as 1.
+pub fn calc_palette(frame: &YuvFrame, quality: usize, options: &QuantizeOptions) -> Result<ColorPalette, Error> {
+    let pixels: Vec<Rgba<u8>> = frame.pixels()
+        // Sample every n-th pixel
+        .step_by(quality.max(1))
+        // Bin using MMCQ bit shift or perceptual coordinates, frames are
+        // opaque so nothing is filtered out
+        .map(|pixel| MMCQ::bin_pixel_as(pixel, options.color_space))
+        .collect();
+    mmcq::quantize_pixels(&pixels, options)
+}
+
+/// True when the data starts like a YUV4MPEG2 stream
+pub fn is_y4m(header: &[u8]) -> bool {
+    header.starts_with(Y4M_MAGIC)
+}
+
+/// Reads uncompressed 8-bit 4:2:0 frames from a YUV4MPEG2 stream. Iterating
+/// yields the frames as I420 buffers and stops after the first error.
+#[derive(Debug)]
+pub struct Y4mReader<R: Read> {
+    reader: R,
+    width: usize,
+    height: usize,
+    range: YuvRange,
+    is_done: bool,
+}
+
+impl<R: Read> Y4mReader<R> {
+    /// Parses the stream header with the default decode limits
+    pub fn new(reader: R) -> Result<Y4mReader<R>, Error> {
+        Y4mReader::with_limits(reader, &DecodeLimits::default())
+    }
+
+    /// Parses the stream header, rejecting frames beyond `limits` before
+    /// any frame buffer is allocated
+    pub fn with_limits(mut reader: R, limits: &DecodeLimits) -> Result<Y4mReader<R>, Error> {
+        let header = read_line(&mut reader)?
+            .ok_or(Error::InvalidStream("empty stream".into()))?;
+        let mut params = header.split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(Error::InvalidStream("missing YUV4MPEG2 signature".into()));
+        }
+        let (mut width, mut height) = (None, None);
+        let mut range = YuvRange::Limited;
+        for param in params.filter(|param| !param.is_empty()) {
+            let mut chars = param.chars();
+            let (tag, value) = (chars.next(), chars.as_str());
+            match tag {
+                Some('W') => width = value.parse::<u32>().ok(),
+                Some('H') => height = value.parse::<u32>().ok(),
+                // Chroma siting variants all share the I420 layout
+                Some('C') if !matches!(value, "420" | "420jpeg" | "420paldv" | "420mpeg2") => {
+                    return Err(Error::InvalidStream(format!("unsupported colorspace {}", value)));
+                },
+                Some('X') => if let Some(value) = value.strip_prefix("COLORRANGE=") {
+                    range = value.parse().map_err(Error::InvalidStream)?;
+                },
+                // Frame rate, interlacing and aspect ratio do not matter
+                _ => (),
+            }
+        }
+        let (Some(width), Some(height)) = (width, height) else {
+            return Err(Error::InvalidStream("missing frame dimensions".into()));
+        };
+        let is_too_wide = limits.max_width.is_some_and(|max| width > max);
+        let is_too_tall = limits.max_height.is_some_and(|max| height > max);
+        if is_too_wide || is_too_tall {
+            return Err(Error::DimensionsTooLarge { width, height });
+        }
+        let (width, height) = (width as usize, height as usize);
+        let size = frame_size(width, height).ok_or(Error::AllocationTooLarge)?;
+        if limits.max_alloc.is_some_and(|max| size as u64 > max) {
+            return Err(Error::AllocationTooLarge);
+        }
+        Ok(Y4mReader { reader, width, height, range, is_done: false })
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn range(&self) -> YuvRange {
+        self.range
+    }
+
+    /// Next frame as an I420 buffer, `None` at the end of the stream
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(header) = read_line(&mut self.reader)? else {
+            return Ok(None);
+        };
+        if header != "FRAME" && !header.starts_with("FRAME ") {
+            return Err(Error::InvalidStream("missing FRAME marker".into()));
+        }
+        let size = frame_size(self.width, self.height).ok_or(Error::AllocationTooLarge)?;
+        let mut data = vec![0; size];
+        self.reader.read_exact(&mut data).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::InvalidStream("truncated frame".into()),
+            _ => Error::Io(err),
+        })?;
+        Ok(Some(data))
+    }
+
+    /// Palette of every frame in order
+    pub fn palettes(self, matrix: YuvMatrix, quality: usize, options: &QuantizeOptions) -> impl Iterator<Item = Result<ColorPalette, Error>> {
+        let (width, height) = self.dimensions();
+        let range = self.range;
+        let options = options.clone();
+        self.map(move |data| {
+            let data = data?;
+            let frame = YuvFrame::new(&data, width, height, YuvFormat::I420, matrix, range)?;
+            calc_palette(&frame, quality, &options)
+        })
+    }
+}
+
+impl<R: Read> Iterator for Y4mReader<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_done {
+            return None;
+        }
+        let frame = self.read_frame().transpose();
+        self.is_done = !matches!(frame, Some(Ok(_)));
+        frame
+    }
+}
+
+/// Reads up to a newline byte by byte so nothing past it is consumed, `None`
+/// when the stream ends before the first byte
+fn read_line(reader: &mut impl Read) -> Result<Option<String>, Error> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return match line.is_empty() {
+                true => Ok(None),
+                false => Err(Error::InvalidStream("truncated header".into())),
+            };
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_HEADER_LEN {
+            return Err(Error::InvalidStream("header too long".into()));
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| Error::InvalidStream("header is not text".into()))
+}
+
+
+#[cfg(test)]
+mod test_yuv {
+    use super::*;
+
+    // BT.601 limited range red and blue
+    const RED_601: [u8; 3] = [81, 90, 240];
+    const BLUE_601: [u8; 3] = [41, 240, 110];
+
+    /// Solid I420 frame of a single sample
+    fn solid_frame(width: usize, height: usize, [y, u, v]: [u8; 3]) -> Vec<u8> {
+        let chroma_size = width.div_ceil(2) * height.div_ceil(2);
+        let mut data = vec![y; width * height];
+        data.extend(std::iter::repeat_n(u, chroma_size));
+        data.extend(std::iter::repeat_n(v, chroma_size));
+        data
+    }
+
+    fn is_close(expected: Rgba<u8>, found: Rgba<u8>) -> bool {
+        expected.0.iter().zip(found.0).all(|(&a, b)| a.abs_diff(b) <= 1)
+    }
+
+    #[test]
+    fn test_yuv_to_rgb() {
+        let cases = [
+            ([16, 128, 128], YuvMatrix::Bt601, YuvRange::Limited, Rgba([0, 0, 0, 255])),
+            ([235, 128, 128], YuvMatrix::Bt709, YuvRange::Limited, Rgba([255, 255, 255, 255])),
+            (RED_601, YuvMatrix::Bt601, YuvRange::Limited, Rgba([255, 0, 0, 255])),
+            (BLUE_601, YuvMatrix::Bt601, YuvRange::Limited, Rgba([0, 0, 255, 255])),
+            ([63, 102, 240], YuvMatrix::Bt709, YuvRange::Limited, Rgba([255, 0, 0, 255])),
+            ([76, 85, 255], YuvMatrix::Bt601, YuvRange::Full, Rgba([255, 0, 0, 255])),
+            ([128, 128, 128], YuvMatrix::Bt709, YuvRange::Full, Rgba([128, 128, 128, 255])),
+        ];
+        for ([y, u, v], matrix, range, expected) in cases {
+            let found = yuv_to_rgb(y, u, v, matrix, range);
+            assert!(is_close(expected, found), "Logic Error: {:?} {:?} {:?} gave {:?}", [y, u, v], matrix, range, found);
+        }
+        // Out of range values clip instead of wrapping
+        let found = yuv_to_rgb(255, 255, 255, YuvMatrix::Bt601, YuvRange::Limited);
+        assert_eq!(255, found[0], "Logic Error: clipping");
+    }
+
+    #[test]
+    fn test_yuv_frame() {
+        // 3x3 frame, the chroma planes round up to 2x2
+        let luma = [16, 235, 16, 235, 16, 235, 16, 235, 16];
+        let (u, v) = ([90, 128, 128, 240], [240, 128, 128, 110]);
+        let i420: Vec<u8> = luma.iter().chain(&u).chain(&v).copied().collect();
+        let nv12: Vec<u8> = luma.iter().copied()
+            .chain(u.iter().zip(&v).flat_map(|(&u, &v)| [u, v]))
+            .collect();
+        let frame = YuvFrame::new(&i420, 3, 3, YuvFormat::I420, YuvMatrix::Bt601, YuvRange::Limited).unwrap();
+        let expected: Vec<Rgba<u8>> = frame.pixels().collect();
+        let frame = YuvFrame::new(&nv12, 3, 3, YuvFormat::Nv12, YuvMatrix::Bt601, YuvRange::Limited).unwrap();
+        let found: Vec<Rgba<u8>> = frame.pixels().collect();
+        assert_eq!(expected, found, "Logic Error: I420 and NV12 differ");
+        assert_eq!(9, found.len(), "Logic Error: pixel count");
+        // Bottom right pixel takes the last chroma sample
+        assert_eq!(yuv_to_rgb(16, 240, 110, YuvMatrix::Bt601, YuvRange::Limited), found[8], "Logic Error: chroma index");
+
+        let found = YuvFrame::new(&i420[..16], 3, 3, YuvFormat::I420, YuvMatrix::Bt601, YuvRange::Limited);
+        assert!(matches!(found, Err(Error::InvalidBuffer(_))), "Logic Error: short buffer");
+    }
+
+    #[test]
+    fn test_calc_palette() {
+        // Three quarters red on top of one quarter blue
+        let (width, height) = (8, 8);
+        let mut data = solid_frame(width, height, RED_601);
+        data[6 * width..8 * width].fill(BLUE_601[0]);
+        let chroma_size = 16;
+        data[64 + 12..64 + 16].fill(BLUE_601[1]);
+        data[64 + chroma_size + 12..64 + 2 * chroma_size].fill(BLUE_601[2]);
+        let frame = YuvFrame::new(&data, width, height, YuvFormat::I420, YuvMatrix::Bt601, YuvRange::Limited).unwrap();
+        let options = QuantizeOptions { color_count: 2, ..Default::default() };
+        let found = calc_palette(&frame, 1, &options).unwrap();
+        assert_eq!(2, found.swatches.len(), "Logic Error: swatch count");
+        assert_eq!(48, found.swatches[0].population, "Logic Error: red population");
+        assert!(found.swatches[0].color[0] > 240, "Logic Error: red first, found {:?}", found.swatches[0].color);
+        assert!(found.swatches[1].color[2] > 240, "Logic Error: blue second, found {:?}", found.swatches[1].color);
+    }
+
+    #[test]
+    fn test_y4m_reader() {
+        let mut stream = b"YUV4MPEG2 W4 H2 F25:1 Ip A1:1 C420jpeg XYSCSS=420JPEG\n".to_vec();
+        for sample in [RED_601, BLUE_601] {
+            stream.extend(b"FRAME\n");
+            stream.extend(solid_frame(4, 2, sample));
+        }
+        let reader = Y4mReader::new(stream.as_slice()).unwrap();
+        assert_eq!((4, 2), reader.dimensions(), "Logic Error: dimensions");
+        assert_eq!(YuvRange::Limited, reader.range(), "Logic Error: range");
+        let options = QuantizeOptions { color_count: 2, ..Default::default() };
+        let found: Vec<ColorPalette> = reader.palettes(YuvMatrix::Bt601, 1, &options)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(2, found.len(), "Logic Error: frame count");
+        assert!(found[0].swatches[0].color[0] > 240, "Logic Error: red frame");
+        assert!(found[1].swatches[0].color[2] > 240, "Logic Error: blue frame");
+
+        // A cut off frame is an error and ends the iteration
+        let mut reader = Y4mReader::new(&stream[..stream.len() - 1]).unwrap();
+        assert!(matches!(reader.next(), Some(Ok(_))), "Logic Error: first frame");
+        assert!(matches!(reader.next(), Some(Err(Error::InvalidStream(_)))), "Logic Error: truncated frame");
+        assert!(reader.next().is_none(), "Logic Error: iteration after error");
+
+        let cases: [&[u8]; 4] = [
+            b"",
+            b"YUV4MPEG W4 H2\n",
+            b"YUV4MPEG2 W4\n",
+            b"YUV4MPEG2 W4 H2 C420p10\n",
+        ];
+        for header in cases {
+            let found = Y4mReader::new(header);
+            assert!(matches!(found, Err(Error::InvalidStream(_))), "Logic Error: {:?}", String::from_utf8_lossy(header));
+        }
+        let header = b"YUV4MPEG2 W4 H2 XCOLORRANGE=FULL\n".as_slice();
+        assert_eq!(YuvRange::Full, Y4mReader::new(header).unwrap().range(), "Logic Error: full range");
+        let limits = DecodeLimits { max_width: Some(2), ..Default::default() };
+        let found = Y4mReader::with_limits(header, &limits);
+        assert!(matches!(found, Err(Error::DimensionsTooLarge { width: 4, height: 2 })), "Logic Error: limits");
+    }
+}
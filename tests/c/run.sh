@@ -0,0 +1,12 @@
+#!/bin/sh
+# Builds the cdylib, then compiles and runs the C API test program against
+# it. Run from anywhere, extra arguments go to the compiler.
+set -eu
+
+root=$(cd "$(dirname "$0")/../.." && pwd)
+cd "$root"
+cargo build --lib
+out="$root/target/debug"
+${CC:-cc} -std=c99 -Wall -Wextra -Werror "$@" -I include tests/c/test_capi.c \
+    -L "$out" -lcolor_thief_rs -Wl,-rpath,"$out" -o "$out/test_capi"
+"$out/test_capi"
@@ -0,0 +1,145 @@
+/* Exercises the C API against the cdylib, run with tests/c/run.sh */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "color_thief.h"
+
+static int failures = 0;
+
+#define CHECK(cond, name) do { \
+    if (!(cond)) { \
+        fprintf(stderr, "FAIL %s:%d %s\n", __FILE__, __LINE__, name); \
+        failures++; \
+    } \
+} while (0)
+
+static unsigned char *read_file(const char *path, size_t *len) {
+    FILE *file = fopen(path, "rb");
+    if (file == NULL) {
+        return NULL;
+    }
+    fseek(file, 0, SEEK_END);
+    *len = (size_t)ftell(file);
+    fseek(file, 0, SEEK_SET);
+    unsigned char *data = malloc(*len);
+    if (data != NULL && fread(data, 1, *len, file) != *len) {
+        free(data);
+        data = NULL;
+    }
+    fclose(file);
+    return data;
+}
+
+static uint64_t total_population(const CtPalette *palette) {
+    uint64_t total = 0;
+    for (size_t i = 0; i < palette->swatch_count; i++) {
+        total += palette->swatches[i].population;
+    }
+    return total;
+}
+
+static void test_abi_version(void) {
+    CHECK(ct_abi_version() == CT_ABI_VERSION, "abi version");
+}
+
+static void test_palette_from_bytes(void) {
+    size_t len = 0;
+    unsigned char *data = read_file("tests/data/12colors.png", &len);
+    CHECK(data != NULL, "read fixture");
+    if (data == NULL) {
+        return;
+    }
+
+    CtOptions options = ct_options_default();
+    options.color_count = 4;
+    options.quality = 1;
+    CtPalette *palette = NULL;
+    CtError *error = NULL;
+    CtStatus status = ct_palette_from_bytes(data, len, &options, &palette, &error);
+    CHECK(status == CT_STATUS_OK, "bytes status");
+    CHECK(error == NULL, "bytes error untouched");
+    if (palette != NULL) {
+        CHECK(palette->swatch_count >= 1 && palette->swatch_count <= 4, "bytes swatch count");
+        CHECK(total_population(palette) == palette->pixel_count, "bytes pixel count");
+        for (size_t i = 0; i < palette->swatch_count; i++) {
+            const CtSwatch *swatch = &palette->swatches[i];
+            printf("#%02x%02x%02x %u\n", swatch->r, swatch->g, swatch->b, swatch->population);
+        }
+    }
+    ct_palette_free(palette);
+    free(data);
+}
+
+static void test_palette_from_rgba(void) {
+    /* 4x2 image, left half red, right half blue, rows padded to 20 bytes */
+    unsigned char data[2 * 20];
+    memset(data, 0, sizeof(data));
+    for (int y = 0; y < 2; y++) {
+        for (int x = 0; x < 4; x++) {
+            unsigned char *pixel = &data[y * 20 + x * 4];
+            pixel[0] = x < 2 ? 255 : 0;
+            pixel[2] = x < 2 ? 0 : 255;
+            pixel[3] = 255;
+        }
+    }
+    CtOptions options = ct_options_default();
+    options.color_count = 2;
+    options.quality = 1;
+    CtPalette *palette = NULL;
+    CtStatus status = ct_palette_from_rgba(data, sizeof(data), 4, 2, 20, &options, &palette, NULL);
+    CHECK(status == CT_STATUS_OK, "rgba status");
+    if (palette != NULL) {
+        CHECK(palette->pixel_count == 8, "rgba pixel count");
+        CHECK(palette->swatch_count == 2, "rgba swatch count");
+        CHECK(total_population(palette) == 8, "rgba populations");
+    }
+    ct_palette_free(palette);
+
+    /* The stride leaves the buffer too short */
+    CtError *error = NULL;
+    palette = NULL;
+    status = ct_palette_from_rgba(data, sizeof(data), 4, 2, 28, &options, &palette, &error);
+    CHECK(status == CT_STATUS_INVALID_BUFFER, "rgba short buffer");
+    CHECK(palette == NULL, "rgba no palette");
+    CHECK(error != NULL && error->status == status, "rgba error status");
+    ct_error_free(error);
+}
+
+static void test_errors(void) {
+    const unsigned char garbage[] = "not an image";
+    CtPalette *palette = NULL;
+    CtError *error = NULL;
+    CtStatus status = ct_palette_from_bytes(garbage, sizeof(garbage), NULL, &palette, &error);
+    CHECK(status == CT_STATUS_DECODE, "decode status");
+    CHECK(error != NULL && strlen(error->message) > 0, "decode message");
+    if (error != NULL) {
+        printf("expected error: %s\n", error->message);
+    }
+    ct_error_free(error);
+
+    CtOptions options = ct_options_default();
+    options.color_count = 1;
+    error = NULL;
+    status = ct_palette_from_bytes(garbage, sizeof(garbage), &options, &palette, &error);
+    CHECK(status == CT_STATUS_INVALID_ARGUMENT, "color count");
+    ct_error_free(error);
+
+    status = ct_palette_from_bytes(NULL, 1, NULL, &palette, NULL);
+    CHECK(status == CT_STATUS_INVALID_ARGUMENT, "null data");
+    ct_palette_free(NULL);
+    ct_error_free(NULL);
+}
+
+int main(void) {
+    test_abi_version();
+    test_palette_from_bytes();
+    test_palette_from_rgba();
+    test_errors();
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("all checks passed\n");
+    return 0;
+}
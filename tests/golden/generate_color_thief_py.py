@@ -0,0 +1,51 @@
+"""Writes color_thief_py.json, the palettes color-thief-py finds for every
+case in cases.json.
+
+Run from anywhere: `python3 tests/golden/generate_color_thief_py.py`. The
+installed colorthief package is required and its version is recorded in
+the file.
+"""
+import json
+import pathlib
+import sys
+
+HERE = pathlib.Path(__file__).resolve().parent
+
+try:
+    from importlib.metadata import version
+
+    from colorthief import MMCQ
+except ImportError:
+    sys.exit("colorthief is not installed: pip install colorthief")
+
+GENERATOR = "colorthief " + version("colorthief")
+
+
+def main():
+    golden = json.loads((HERE / "cases.json").read_text())
+    palettes = {}
+    for name, case in golden["cases"].items():
+        pixels = [tuple(pixel) for pixel in case["pixels"]]
+        palettes[name] = {
+            str(color_count): [list(color) for color in MMCQ.quantize(pixels, color_count).palette]
+            for color_count in golden["color_counts"]
+        }
+    write(HERE / "color_thief_py.json", palettes)
+
+
+def write(path, palettes):
+    lines = []
+    for name, by_count in palettes.items():
+        counts = ", ".join(
+            "{}: {}".format(json.dumps(count), json.dumps(colors, separators=(",", ":")))
+            for count, colors in by_count.items()
+        )
+        lines.append("    {}: {{{}}}".format(json.dumps(name), counts))
+    text = '{{\n  "generator": {},\n  "palettes": {{\n{}\n  }}\n}}\n'.format(
+        json.dumps(GENERATOR), ",\n".join(lines)
+    )
+    path.write_text(text)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,33 @@
+/*
+ * Writes quantize_js.json, the palettes lokesh/quantize finds for every case
+ * in cases.json.
+ *
+ * Run from anywhere: `node tests/golden/generate_quantize_js.mjs`. The
+ * installed package is required and its version is recorded in the file.
+ */
+import { readFileSync, writeFileSync } from "node:fs";
+import { createRequire } from "node:module";
+import { dirname, join } from "node:path";
+import { fileURLToPath } from "node:url";
+
+const here = dirname(fileURLToPath(import.meta.url));
+
+let quantize;
+try {
+    quantize = (await import("@lokesh.dhakar/quantize")).default;
+} catch {
+    console.error("@lokesh.dhakar/quantize is not installed: npm install @lokesh.dhakar/quantize");
+    process.exit(1);
+}
+const version = createRequire(import.meta.url)("@lokesh.dhakar/quantize/package.json").version;
+const generator = `@lokesh.dhakar/quantize ${version}`;
+
+const golden = JSON.parse(readFileSync(join(here, "cases.json"), "utf8"));
+const lines = Object.entries(golden.cases).map(([name, { pixels }]) => {
+    const counts = golden.color_counts
+        .map((count) => `"${count}": ${JSON.stringify(quantize(pixels, count).palette())}`)
+        .join(", ");
+    return `    ${JSON.stringify(name)}: {${counts}}`;
+});
+const text = `{\n  "generator": ${JSON.stringify(generator)},\n  "palettes": {\n${lines.join(",\n")}\n  }\n}\n`;
+writeFileSync(join(here, "quantize_js.json"), text);